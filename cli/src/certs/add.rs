@@ -0,0 +1,58 @@
+use crate::cli::Cli;
+use crate::config::ApiClient;
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+
+pub fn command(
+  cert: String,
+  key: String,
+  domain: Option<String>,
+  cluster_name: Option<String>,
+) -> anyhow::Result<()> {
+  let certificate =
+    fs::read_to_string(&cert).context(format!("Failed to read certificate at {}", cert))?;
+  let private_key =
+    fs::read_to_string(&key).context(format!("Failed to read private key at {}", key))?;
+
+  let cluster = Cli::get_default_cluster_or_ask(cluster_name)?;
+  let base_url = if cluster.0 == "localhost" {
+    format!("http://{}", cluster.0)
+  } else {
+    format!("https://{}", cluster.0)
+  };
+  let response = ApiClient::default()?
+    .post(format!("{}/certificate", base_url))
+    .json(&json!({
+      "domain_name": domain,
+      "certificate": certificate,
+      "private_key": private_key,
+    }))
+    .bearer_auth(ApiClient::bearer_ssh_token(
+      cluster.1.ssh_key.clone().map(PathBuf::from),
+    )?)
+    .send()?;
+
+  match response.status().as_u16() {
+    201 => {
+      let certificate = response.json::<Value>()?;
+      println!(
+        "🔒 Certificate added for {}, expires at {}",
+        certificate["domain_name"].as_str().unwrap_or_default(),
+        certificate["expires_at"].as_str().unwrap_or_default()
+      );
+      Ok(())
+    }
+    400 => Err(anyhow!(
+      "The certificate was rejected, check that it is not expired, covers the domain and matches the key"
+    )),
+    403 => Err(anyhow!(
+      "The domain is not routed to any of your services"
+    )),
+    _ => {
+      response.error_for_status()?;
+      Ok(())
+    }
+  }
+}
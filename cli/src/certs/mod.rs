@@ -0,0 +1,22 @@
+pub(crate) mod add;
+
+use clap::Subcommand;
+
+#[derive(Subcommand)]
+pub enum Commands {
+  /// Upload a certificate issued by your own CA
+  Add {
+    /// Path to the PEM certificate chain, leaf certificate first
+    #[arg(long = "cert")]
+    cert: String,
+    /// Path to the PEM private key
+    #[arg(long = "key")]
+    key: String,
+    /// Domain to serve the certificate for, defaults to its first DNS name
+    #[arg(long = "domain")]
+    domain: Option<String>,
+    /// Cluster name
+    #[arg(long = "cluster")]
+    cluster_name: Option<String>,
+  },
+}
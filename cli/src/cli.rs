@@ -1,6 +1,6 @@
-use crate::cluster;
 use crate::config::{ClusterConfig, Config};
 use crate::init::InitTemplate;
+use crate::{certs, cluster};
 use anyhow::anyhow;
 use clap::error::ErrorKind;
 use clap::{CommandFactory, Parser, Subcommand};
@@ -49,6 +49,11 @@ pub enum Commands {
     #[clap(subcommand)]
     command: cluster::command::Commands,
   },
+  /// Certificate commands
+  Certs {
+    #[clap(subcommand)]
+    command: certs::Commands,
+  },
   // /// Environment variables commands
   // Env {
   //   #[clap(subcommand)]
@@ -76,6 +76,7 @@ pub fn command(allow_invalid_domain: bool) -> anyhow::Result<()> {
     name: cluster.name.clone(),
     dosei_public_key: SSH::generate_ed25519_key()?,
     accounts: cluster.accounts,
+    wildcard_domain: cluster.wildcard_domain,
   });
 
   cluster_init.create_lock(&sess)?;
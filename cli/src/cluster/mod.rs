@@ -19,6 +19,8 @@ pub struct Cluster {
   pub servers: Option<Vec<String>>,
   pub identity: Option<String>,
   pub accounts: Option<Vec<ClusterAccount>>,
+  #[serde(default)]
+  pub wildcard_domain: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
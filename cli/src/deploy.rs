@@ -61,6 +61,9 @@ pub fn command(cluster_name: Option<String>, allow_dirty: bool) -> anyhow::Resul
     println!("{}", response_text);
     return Ok(());
   }
+  if status_code == reqwest::StatusCode::CONFLICT {
+    return Err(anyhow::Error::msg(response.text()?));
+  }
   response.error_for_status()?;
   Ok(())
 }
@@ -1,3 +1,4 @@
+mod certs;
 mod cli;
 mod cluster;
 mod config;
@@ -48,6 +49,14 @@ fn main() -> anyhow::Result<()> {
       }
       cluster::command::Commands::Default => cluster::command::default::command()?,
     },
+    Commands::Certs { command } => match command {
+      certs::Commands::Add {
+        cert,
+        key,
+        domain,
+        cluster_name,
+      } => certs::add::command(cert, key, domain, cluster_name)?,
+    },
     // Commands::Env { command } => match command {
     //   env::Commands::Set { name, value } => env::set::command(name, value, config)?,
     //   env::Commands::Unset { name } => env::unset::command(name, config)?,
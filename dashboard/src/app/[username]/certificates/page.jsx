@@ -67,10 +67,13 @@ export const CertificateRow = ({ certificate }) => {
         <div className="flex items-center px-4 py-4 sm:px-6">
           <div className="flex min-w-0 flex-1 items-center">
             <div className="min-w-0 flex-1 grid-col-2 md:grid md:grid-cols-3 md:gap-4">
-              <div className="hidden md:flex items-center">
+              <div className="hidden md:flex flex-col justify-center min-w-0">
                 <p className="text-sm font-medium text-gray-600 dark:text-white">
                   {certificate.domain_name}
                 </p>
+                <p className="text-xs font-mono text-gray-500 dark:text-white/50 truncate" title={`SHA-256 ${certificate.fingerprint}`}>
+                  {certificate.fingerprint}
+                </p>
               </div>
               <div className="hidden md:flex items-center">
                 <p className="text-sm font-medium text-gray-600 dark:text-white">
@@ -0,0 +1,2 @@
+ALTER TABLE ingress ADD forwarded_headers BOOLEAN DEFAULT TRUE NOT NULL;
+ALTER TABLE ingress ADD trust_forwarded_headers BOOLEAN DEFAULT FALSE NOT NULL;
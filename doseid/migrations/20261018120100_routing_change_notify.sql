@@ -0,0 +1,14 @@
+CREATE OR REPLACE FUNCTION notify_routing_change() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('routing_change', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER ingress_routing_change
+    AFTER INSERT OR UPDATE OR DELETE ON ingress
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_routing_change();
+
+CREATE TRIGGER deployment_routing_change
+    AFTER INSERT OR DELETE OR UPDATE OF service_id, host_port, container_port ON deployment
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_routing_change();
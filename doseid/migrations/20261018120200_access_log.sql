@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS access_log (
+    id UUID NOT NULL,
+    service_id UUID,
+    deployment_id UUID,
+    host TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    protocol TEXT DEFAULT 'HTTP/1.1' NOT NULL,
+    status SMALLINT NOT NULL,
+    bytes BIGINT NOT NULL,
+    upstream_latency_ms INTEGER,
+    client_ip TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (service_id) REFERENCES service(id) ON DELETE CASCADE,
+    FOREIGN KEY (deployment_id) REFERENCES deployment(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS access_log_service_id_created_at_idx ON access_log (service_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS access_log_created_at_idx ON access_log (created_at);
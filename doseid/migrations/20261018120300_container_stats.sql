@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS container_stats (
+    id UUID NOT NULL,
+    deployment_id UUID NOT NULL,
+    cpu_percent DOUBLE PRECISION NOT NULL,
+    memory_usage_bytes BIGINT NOT NULL,
+    memory_limit_bytes BIGINT NOT NULL,
+    network_rx_bytes BIGINT NOT NULL,
+    network_tx_bytes BIGINT NOT NULL,
+    block_read_bytes BIGINT NOT NULL,
+    block_write_bytes BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (deployment_id) REFERENCES deployment(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS container_stats_deployment_id_created_at_idx ON container_stats (deployment_id, created_at);
+CREATE INDEX IF NOT EXISTS container_stats_created_at_idx ON container_stats (created_at);
@@ -0,0 +1,14 @@
+ALTER TABLE deployment ADD COLUMN IF NOT EXISTS status TEXT DEFAULT 'pending' NOT NULL;
+
+CREATE TABLE IF NOT EXISTS container_event (
+    id UUID NOT NULL,
+    deployment_id UUID NOT NULL,
+    action TEXT NOT NULL,
+    exit_code INTEGER,
+    detail TEXT,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (deployment_id) REFERENCES deployment(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS container_event_deployment_id_created_at_idx ON container_event (deployment_id, created_at DESC);
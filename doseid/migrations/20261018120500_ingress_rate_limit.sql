@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS ingress_rate_limit (
+    id UUID NOT NULL,
+    ingress_id UUID NOT NULL,
+    path TEXT,
+    requests_per_second DOUBLE PRECISION NOT NULL,
+    burst INTEGER NOT NULL,
+    key_header TEXT,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (ingress_id) REFERENCES ingress(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS ingress_rate_limit_ingress_id_idx ON ingress_rate_limit (ingress_id);
+
+CREATE TRIGGER ingress_rate_limit_routing_change
+    AFTER INSERT OR UPDATE OR DELETE ON ingress_rate_limit
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_routing_change();
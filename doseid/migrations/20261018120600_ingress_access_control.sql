@@ -0,0 +1,3 @@
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS basic_auth TEXT[] DEFAULT '{}' NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS allow_cidrs TEXT[] DEFAULT '{}' NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS deny_cidrs TEXT[] DEFAULT '{}' NOT NULL;
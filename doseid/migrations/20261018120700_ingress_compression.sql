@@ -0,0 +1,3 @@
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS compression BOOLEAN DEFAULT TRUE NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS compression_min_size INTEGER DEFAULT 1024 NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS compression_content_types TEXT[] DEFAULT '{}' NOT NULL;
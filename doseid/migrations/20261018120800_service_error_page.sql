@@ -0,0 +1,19 @@
+ALTER TABLE service ADD COLUMN IF NOT EXISTS maintenance BOOLEAN DEFAULT FALSE NOT NULL;
+
+CREATE TABLE IF NOT EXISTS service_error_page (
+    service_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    html TEXT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (service_id, name),
+    FOREIGN KEY (service_id) REFERENCES service(id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER service_routing_change
+    AFTER UPDATE OF maintenance ON service
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_routing_change();
+
+CREATE TRIGGER service_error_page_routing_change
+    AFTER INSERT OR UPDATE OR DELETE ON service_error_page
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_routing_change();
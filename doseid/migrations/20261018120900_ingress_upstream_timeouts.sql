@@ -0,0 +1,3 @@
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS connect_timeout_ms INTEGER DEFAULT 5000 NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS read_timeout_ms INTEGER DEFAULT 60000 NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS retries INTEGER DEFAULT 2 NOT NULL;
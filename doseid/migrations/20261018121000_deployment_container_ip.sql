@@ -0,0 +1,6 @@
+ALTER TABLE deployment ADD COLUMN IF NOT EXISTS container_ip TEXT;
+
+DROP TRIGGER IF EXISTS deployment_routing_change ON deployment;
+CREATE TRIGGER deployment_routing_change
+    AFTER INSERT OR DELETE OR UPDATE OF service_id, host_port, container_port, container_ip ON deployment
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_routing_change();
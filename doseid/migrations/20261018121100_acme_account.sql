@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS acme_account (
+    id UUID NOT NULL,
+    directory_url TEXT NOT NULL UNIQUE,
+    contact TEXT,
+    credentials TEXT NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS certificate_renewal (
+    id UUID NOT NULL,
+    certificate_id UUID NOT NULL,
+    previous_certificate TEXT NOT NULL,
+    previous_expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (certificate_id) REFERENCES certificate(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS certificate_renewal_certificate_id_created_at_idx ON certificate_renewal (certificate_id, created_at DESC);
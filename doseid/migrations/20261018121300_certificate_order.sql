@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS certificate_order (
+    id UUID NOT NULL,
+    domain_name TEXT NOT NULL,
+    owner_id UUID NOT NULL,
+    order_url TEXT NOT NULL,
+    token TEXT NOT NULL,
+    key_authorization TEXT NOT NULL,
+    private_key TEXT,
+    status TEXT DEFAULT 'pending' NOT NULL,
+    attempts INTEGER DEFAULT 0 NOT NULL,
+    last_error TEXT,
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id),
+    FOREIGN KEY (owner_id) REFERENCES account(id) ON DELETE CASCADE,
+    UNIQUE (domain_name)
+);
+
+CREATE INDEX IF NOT EXISTS certificate_order_token_idx ON certificate_order (token);
+CREATE INDEX IF NOT EXISTS certificate_order_status_idx ON certificate_order (status);
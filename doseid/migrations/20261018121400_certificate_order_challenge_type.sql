@@ -0,0 +1 @@
+ALTER TABLE certificate_order ADD COLUMN IF NOT EXISTS challenge_type TEXT DEFAULT 'http-01' NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE certificate ADD COLUMN IF NOT EXISTS source TEXT DEFAULT 'acme' NOT NULL;
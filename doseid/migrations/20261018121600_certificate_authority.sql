@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS certificate_authority (
+    id UUID NOT NULL,
+    certificate TEXT NOT NULL,
+    private_key TEXT NOT NULL,
+    permitted_name TEXT,
+    expires_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (id)
+);
@@ -0,0 +1 @@
+ALTER TABLE certificate ADD COLUMN IF NOT EXISTS fingerprint TEXT DEFAULT '' NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE certificate ADD COLUMN IF NOT EXISTS key_type TEXT DEFAULT 'ecdsa-p256' NOT NULL;
+ALTER TABLE certificate_order ADD COLUMN IF NOT EXISTS key_type TEXT DEFAULT 'ecdsa-p256' NOT NULL;
+ALTER TABLE ingress ADD COLUMN IF NOT EXISTS certificate_key_type TEXT;
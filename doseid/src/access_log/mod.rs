@@ -0,0 +1,351 @@
+use crate::config::{AccessLogFormat, Config};
+use crate::metrics::METRICS;
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, Request};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use http_body::{Frame, SizeHint};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+use tracing::{debug, error, info};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub mod route;
+
+const FLUSH_SPAN: u64 = 5; // 5 seconds
+const RETENTION_CHECK_SPAN: u64 = 3600; // 1 hour
+
+/// Access logs waiting to be written to Postgres.
+static PENDING_ACCESS_LOGS: Lazy<Mutex<Vec<AccessLog>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct AccessLog {
+  pub id: Uuid,
+  pub service_id: Option<Uuid>,
+  pub deployment_id: Option<Uuid>,
+  pub host: String,
+  pub method: String,
+  pub path: String,
+  /// HTTP version of the client request, e.g. `HTTP/1.1`.
+  pub protocol: String,
+  pub status: i16,
+  /// Response body size sent to the client.
+  pub bytes: i64,
+  /// Time until the upstream response headers were received, unset when the request never
+  /// reached a deployment.
+  pub upstream_latency_ms: Option<i32>,
+  pub client_ip: String,
+  pub created_at: DateTime<Utc>,
+}
+
+/// Inserted into the proxy response extensions to attribute the request to a service.
+#[derive(Clone, Copy)]
+pub struct AccessLogTarget {
+  pub service_id: Uuid,
+  /// Unset when the request never reached a deployment, e.g. in maintenance mode.
+  pub deployment_id: Option<Uuid>,
+  /// Time spent in the upstream request alone, excluding proxy work like auth and compression.
+  pub upstream_latency: Option<Duration>,
+  /// Client IP as resolved through the trusted proxies, the socket peer is only the last hop.
+  pub client_ip: IpAddr,
+}
+
+impl AccessLog {
+  pub async fn start_server(
+    config: &'static Config,
+    pg_pool: &Arc<Pool<Postgres>>,
+  ) -> anyhow::Result<()> {
+    info!("DoseiD Access Log Server Running");
+    let pool = Arc::clone(pg_pool);
+    tokio::spawn(async move {
+      let mut interval = interval(Duration::from_secs(FLUSH_SPAN));
+      loop {
+        interval.tick().await;
+        if let Err(e) = Self::flush(&pool).await {
+          error!("Failed to write access logs: {}", e);
+        }
+      }
+    });
+
+    let pool = Arc::clone(pg_pool);
+    tokio::spawn(async move {
+      let mut interval = interval(Duration::from_secs(RETENTION_CHECK_SPAN));
+      loop {
+        interval.tick().await;
+        let expired_at = Utc::now() - chrono::Duration::days(config.access_log_retention_days);
+        match sqlx::query!("DELETE FROM access_log WHERE created_at < $1", expired_at)
+          .execute(&*pool)
+          .await
+        {
+          Ok(rows) => debug!("Removed {} expired access logs", rows.rows_affected()),
+          Err(e) => error!("Failed to remove expired access logs: {}", e),
+        }
+      }
+    });
+    Ok(())
+  }
+
+  async fn flush(pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let access_logs = std::mem::take(&mut *PENDING_ACCESS_LOGS.lock().unwrap());
+    if access_logs.is_empty() {
+      return Ok(());
+    }
+    let mut ids = Vec::with_capacity(access_logs.len());
+    let mut service_ids = Vec::with_capacity(access_logs.len());
+    let mut deployment_ids = Vec::with_capacity(access_logs.len());
+    let mut hosts = Vec::with_capacity(access_logs.len());
+    let mut methods = Vec::with_capacity(access_logs.len());
+    let mut paths = Vec::with_capacity(access_logs.len());
+    let mut protocols = Vec::with_capacity(access_logs.len());
+    let mut statuses = Vec::with_capacity(access_logs.len());
+    let mut bytes = Vec::with_capacity(access_logs.len());
+    let mut upstream_latencies = Vec::with_capacity(access_logs.len());
+    let mut client_ips = Vec::with_capacity(access_logs.len());
+    let mut created_ats = Vec::with_capacity(access_logs.len());
+    for access_log in access_logs {
+      ids.push(access_log.id);
+      service_ids.push(access_log.service_id);
+      deployment_ids.push(access_log.deployment_id);
+      hosts.push(access_log.host);
+      methods.push(access_log.method);
+      paths.push(access_log.path);
+      protocols.push(access_log.protocol);
+      statuses.push(access_log.status);
+      bytes.push(access_log.bytes);
+      upstream_latencies.push(access_log.upstream_latency_ms);
+      client_ips.push(access_log.client_ip);
+      created_ats.push(access_log.created_at);
+    }
+    sqlx::query!(
+      "
+      INSERT INTO access_log (
+        id, service_id, deployment_id, host, method, path, protocol, status, bytes, upstream_latency_ms,
+        client_ip, created_at
+      )
+      SELECT * FROM UNNEST(
+        $1::uuid[], $2::uuid[], $3::uuid[], $4::text[], $5::text[], $6::text[], $7::text[],
+        $8::smallint[], $9::bigint[], $10::integer[], $11::text[], $12::timestamptz[]
+      )
+      ",
+      &ids,
+      &service_ids as &[Option<Uuid>],
+      &deployment_ids as &[Option<Uuid>],
+      &hosts,
+      &methods,
+      &paths,
+      &protocols,
+      &statuses,
+      &bytes,
+      &upstream_latencies as &[Option<i32>],
+      &client_ips,
+      &created_ats,
+    )
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn get_by_service_id(
+    service_id: Uuid,
+    limit: i64,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT * FROM access_log WHERE service_id = $1 ORDER BY created_at DESC LIMIT $2",
+        service_id,
+        limit
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+
+  /// Logs every proxied request once the response body has been sent, and records its metrics.
+  pub async fn middleware(
+    Extension(config): Extension<&'static Config>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+  ) -> Response {
+    let started_at = Instant::now();
+    let created_at = Utc::now();
+    let host = request
+      .headers()
+      .get(header::HOST)
+      .and_then(|value| value.to_str().ok())
+      .unwrap_or_default()
+      .to_string();
+    let method = request.method().to_string();
+    let path = request
+      .uri()
+      .path_and_query()
+      .map(|value| value.to_string())
+      .unwrap_or_else(|| request.uri().path().to_string());
+    let protocol = format!("{:?}", request.version());
+
+    let response = next.run(request).await;
+
+    let latency = started_at.elapsed();
+    let target = response.extensions().get::<AccessLogTarget>().copied();
+    METRICS.observe_proxy_request(
+      target.map(|target| target.service_id),
+      response.status().as_u16(),
+      latency,
+    );
+    let access_log = AccessLog {
+      id: Uuid::new_v4(),
+      service_id: target.map(|target| target.service_id),
+      deployment_id: target.and_then(|target| target.deployment_id),
+      host,
+      method,
+      path,
+      protocol,
+      status: response.status().as_u16() as i16,
+      bytes: 0,
+      upstream_latency_ms: target
+        .and_then(|target| target.upstream_latency)
+        .map(|upstream_latency| upstream_latency.as_millis() as i32),
+      client_ip: client_ip(target, client_addr).to_string(),
+      created_at,
+    };
+    let (parts, body) = response.into_parts();
+    let body = Body::new(AccessLogBody {
+      inner: body,
+      bytes: 0,
+      access_log: Some(access_log),
+      format: config.access_log_format,
+    });
+    Response::from_parts(parts, body)
+  }
+
+  fn record(self, format: AccessLogFormat) {
+    match format {
+      AccessLogFormat::Json => match utoipa::gen::serde_json::to_string(&self) {
+        Ok(line) => info!(target: "access_log", "{}", line),
+        Err(e) => error!("Failed to serialize access log: {}", e),
+      },
+      AccessLogFormat::Common => info!(target: "access_log", "{}", self.common_log_format()),
+      AccessLogFormat::Off => {}
+    }
+    if self.service_id.is_some() {
+      PENDING_ACCESS_LOGS.lock().unwrap().push(self);
+    }
+  }
+
+  /// `host ident authuser [date] "request line" status bytes`
+  pub fn common_log_format(&self) -> String {
+    format!(
+      "{} - - [{}] \"{} {} {}\" {} {}",
+      self.client_ip,
+      self.created_at.format("%d/%b/%Y:%H:%M:%S %z"),
+      self.method,
+      self.path,
+      self.protocol,
+      self.status,
+      self.bytes
+    )
+  }
+}
+
+/// Prefers the IP the proxy resolved, requests that never reached a route only have the peer.
+fn client_ip(target: Option<AccessLogTarget>, client_addr: SocketAddr) -> IpAddr {
+  target.map_or(client_addr.ip(), |target| target.client_ip)
+}
+
+/// Counts the response bytes and records the access log once the body is done.
+struct AccessLogBody {
+  inner: Body,
+  bytes: u64,
+  access_log: Option<AccessLog>,
+  format: AccessLogFormat,
+}
+
+impl http_body::Body for AccessLogBody {
+  type Data = Bytes;
+  type Error = axum::Error;
+
+  fn poll_frame(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+    let poll = Pin::new(&mut self.inner).poll_frame(cx);
+    if let Poll::Ready(Some(Ok(frame))) = &poll {
+      if let Some(data) = frame.data_ref() {
+        self.bytes += data.len() as u64;
+      }
+    }
+    poll
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    self.inner.size_hint()
+  }
+}
+
+impl Drop for AccessLogBody {
+  fn drop(&mut self) {
+    if let Some(mut access_log) = self.access_log.take() {
+      access_log.bytes = self.bytes as i64;
+      access_log.record(self.format);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn logs_the_resolved_client_ip_over_the_socket_peer() {
+    let proxy: SocketAddr = "10.0.0.2:41000".parse().unwrap();
+    let target = AccessLogTarget {
+      service_id: Uuid::new_v4(),
+      deployment_id: None,
+      upstream_latency: None,
+      client_ip: "203.0.113.7".parse().unwrap(),
+    };
+    assert_eq!(client_ip(Some(target), proxy), target.client_ip);
+    assert_eq!(client_ip(None, proxy), proxy.ip());
+  }
+
+  #[test]
+  fn common_log_format_starts_with_the_client_ip() {
+    let access_log = AccessLog {
+      id: Uuid::new_v4(),
+      service_id: None,
+      deployment_id: None,
+      host: "example.com".to_string(),
+      method: "GET".to_string(),
+      path: "/index.html?lang=en".to_string(),
+      protocol: "HTTP/1.1".to_string(),
+      status: 200,
+      bytes: 512,
+      upstream_latency_ms: None,
+      client_ip: "203.0.113.7".to_string(),
+      created_at: DateTime::parse_from_rfc3339("2026-10-18T09:30:00Z")
+        .unwrap()
+        .with_timezone(&Utc),
+    };
+    assert_eq!(
+      access_log.common_log_format(),
+      "203.0.113.7 - - [18/Oct/2026:09:30:00 +0000] \"GET /index.html?lang=en HTTP/1.1\" 200 512"
+    );
+  }
+}
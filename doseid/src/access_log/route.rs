@@ -0,0 +1,56 @@
+use crate::access_log::AccessLog;
+use crate::service::Service;
+use crate::session::AuthSession;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+const TAG: &str = "access-log";
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize, IntoParams)]
+pub struct AccessLogQuery {
+  /// Number of most recent entries to return, defaults to 100 and caps at 1000.
+  limit: Option<i64>,
+}
+
+#[utoipa::path(
+  get,
+  path = "/service/{service_id}/access-log",
+  params(
+    ("service_id" = String, Path, description = "Service ID"),
+    AccessLogQuery,
+  ),
+  responses(
+        (status = StatusCode::OK, body = Vec<AccessLog>),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_list_service_access_logs(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+  Path(service_id): Path<Uuid>,
+  Query(query): Query<AccessLogQuery>,
+) -> Result<(StatusCode, Json<Vec<AccessLog>>), StatusCode> {
+  let service = Service::get_by_id(service_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  if service.owner_id != session.account_id {
+    return Err(StatusCode::NOT_FOUND);
+  }
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+  let access_logs = AccessLog::get_by_service_id(service_id, limit, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::OK, Json(access_logs)))
+}
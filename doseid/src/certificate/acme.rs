@@ -0,0 +1,531 @@
+use crate::certificate::encryption::ClusterKey;
+use crate::config::Config;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use chrono::Utc;
+use dosei_schema::app::CertificateKeyType;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{header, Request};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use instant_acme::{Account, AccountCredentials, HttpClient, NewAccount, Order};
+use once_cell::sync::OnceCell;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use rustls::pki_types::CertificateDer;
+use rustls::{ClientConfig, RootCertStore};
+use sqlx::{Pool, Postgres};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use utoipa::gen::serde_json;
+use uuid::Uuid;
+
+/// How many times a finalized order is polled for its certificate before giving up.
+const CERTIFICATE_MAX_POLLS: u32 = 30;
+
+static ACME: OnceCell<Acme> = OnceCell::new();
+
+type HttpsClient =
+  hyper_util::client::legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// The ACME directory certificates are ordered from, see `ACME_DIRECTORY` and `ACME_CA_BUNDLE`.
+pub struct Acme {
+  pub directory_url: String,
+  ca_bundle: Option<PathBuf>,
+  /// `mailto:` contact of the cluster account.
+  contact: Option<String>,
+  /// Key algorithm of certificates of domains that don't choose one.
+  pub key_type: CertificateKeyType,
+  /// The cluster account, loaded from the database on first use.
+  account: Mutex<Option<Account>>,
+}
+
+impl Acme {
+  pub fn new(
+    directory_url: &str,
+    ca_bundle: Option<&Path>,
+    email: Option<&str>,
+    key_type: CertificateKeyType,
+  ) -> Self {
+    Self {
+      directory_url: directory_url.to_string(),
+      ca_bundle: ca_bundle.map(Path::to_path_buf),
+      contact: email.map(|email| format!("mailto:{}", email)),
+      key_type,
+      account: Mutex::new(None),
+    }
+  }
+
+  pub fn init(config: &Config) {
+    let acme = Self::new(
+      config.acme_directory.url(),
+      config.acme_ca_bundle.as_deref(),
+      config.acme_email.as_deref(),
+      config.certificate_key_type,
+    );
+    info!("Using ACME directory: {}", acme.directory_url);
+    if ACME.set(acme).is_err() {
+      warn!("ACME directory already initialized");
+    }
+  }
+
+  pub fn get() -> anyhow::Result<&'static Self> {
+    ACME
+      .get()
+      .ok_or_else(|| anyhow::Error::msg("ACME directory not initialized"))
+  }
+
+  fn http_client(&self) -> anyhow::Result<Box<dyn HttpClient>> {
+    Ok(Box::new(self.https_client()?))
+  }
+
+  /// HTTPS client trusting the system roots, plus the CA bundle when configured.
+  fn https_client(&self) -> anyhow::Result<HttpsClient> {
+    let mut roots = RootCertStore::empty();
+    let native_certs = rustls_native_certs::load_native_certs();
+    for error in native_certs.errors {
+      warn!("Failed to load a system root certificate: {}", error);
+    }
+    roots.add_parsable_certificates(native_certs.certs);
+    if let Some(ca_bundle) = &self.ca_bundle {
+      let pem = std::fs::read(ca_bundle)?;
+      let certs: Vec<CertificateDer<'static>> =
+        rustls_pemfile::certs(&mut BufReader::new(pem.as_slice())).collect::<Result<_, _>>()?;
+      if certs.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+          "No certificates found in ACME CA bundle {:?}",
+          ca_bundle
+        )));
+      }
+      roots.add_parsable_certificates(certs);
+    }
+    let tls_config = ClientConfig::builder()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+      .with_tls_config(tls_config)
+      .https_only()
+      .enable_http1()
+      .enable_http2()
+      .build();
+    Ok(hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(connector))
+  }
+
+  /// The cluster account for this directory, registered once and reused for every order.
+  /// The contact of the stored account is updated when the configured one changes.
+  pub async fn account(&self, pg_pool: &Pool<Postgres>) -> anyhow::Result<Account> {
+    let mut account = self.account.lock().await;
+    if let Some(account) = account.as_ref() {
+      return Ok(account.clone());
+    }
+    let stored = AcmeAccount::get_by_directory_url(&self.directory_url, pg_pool).await?;
+    let restored = match stored {
+      Some(stored) => {
+        let credentials =
+          ClusterKey::get()?.decrypt(&stored.credentials, &credentials_aad(&self.directory_url))?;
+        if stored.contact != self.contact {
+          self.update_contact(&credentials).await?;
+          AcmeAccount::save(
+            &self.directory_url,
+            self.contact.as_deref(),
+            &credentials,
+            pg_pool,
+          )
+          .await?;
+          info!("Updated ACME account contact");
+        }
+        let credentials: AccountCredentials = serde_json::from_str(&credentials)?;
+        Account::from_credentials_and_http(credentials, self.http_client()?).await?
+      }
+      None => {
+        let contact: Vec<&str> = self.contact.iter().map(String::as_str).collect();
+        let (created, credentials) = self.create_account(&contact).await?;
+        AcmeAccount::save(
+          &self.directory_url,
+          self.contact.as_deref(),
+          &serde_json::to_string(&credentials)?,
+          pg_pool,
+        )
+        .await?;
+        info!("Registered ACME account: {}", created.id());
+        created
+      }
+    };
+    *account = Some(restored.clone());
+    Ok(restored)
+  }
+
+  /// Replaces the contact of an existing account, RFC 8555 section 7.3.2. instant-acme has no
+  /// call for it, so the request is signed here with the account key from the credentials.
+  async fn update_contact(&self, credentials: &str) -> anyhow::Result<()> {
+    let credentials: serde_json::Value = serde_json::from_str(credentials)?;
+    let (Some(account_url), Some(key_pkcs8)) = (
+      credentials["id"].as_str(),
+      credentials["key_pkcs8"].as_str(),
+    ) else {
+      return Err(anyhow::Error::msg(
+        "ACME account credentials are missing the id or key",
+      ));
+    };
+    let key_pkcs8 = BASE64_URL_SAFE_NO_PAD.decode(key_pkcs8)?;
+    let client = self.https_client()?;
+
+    let directory = client
+      .request(Request::get(&self.directory_url).body(Full::default())?)
+      .await?;
+    let directory: serde_json::Value =
+      serde_json::from_slice(&directory.into_body().collect().await?.to_bytes())?;
+    let new_nonce = directory["newNonce"]
+      .as_str()
+      .ok_or_else(|| anyhow::Error::msg("ACME directory has no newNonce"))?;
+    let nonce = client
+      .request(Request::head(new_nonce).body(Full::default())?)
+      .await?;
+    let nonce = nonce
+      .headers()
+      .get("replay-nonce")
+      .and_then(|nonce| nonce.to_str().ok())
+      .ok_or_else(|| anyhow::Error::msg("ACME server returned no nonce"))?;
+
+    let contact: Vec<&str> = self.contact.iter().map(String::as_str).collect();
+    let body = signed_request(
+      &key_pkcs8,
+      account_url,
+      nonce,
+      &serde_json::json!({ "contact": contact }),
+    )?;
+    let response = client
+      .request(
+        Request::post(account_url)
+          .header(header::CONTENT_TYPE, "application/jose+json")
+          .body(Full::new(Bytes::from(body.to_string())))?,
+      )
+      .await?;
+    if !response.status().is_success() {
+      let status = response.status();
+      let problem = response.into_body().collect().await?.to_bytes();
+      return Err(anyhow::Error::msg(format!(
+        "Failed to update ACME account contact: {} {}",
+        status,
+        String::from_utf8_lossy(&problem)
+      )));
+    }
+    Ok(())
+  }
+
+  pub async fn create_account(
+    &self,
+    contact: &[&str],
+  ) -> anyhow::Result<(Account, AccountCredentials)> {
+    let new_account = NewAccount {
+      contact,
+      terms_of_service_agreed: true,
+      only_return_existing: false,
+    };
+    Ok(
+      Account::create_with_http(&new_account, &self.directory_url, None, self.http_client()?)
+        .await?,
+    )
+  }
+}
+
+/// Stored credentials of the cluster ACME account, one per directory.
+pub struct AcmeAccount {
+  pub contact: Option<String>,
+  /// Serialized [`AccountCredentials`], including the account key, encrypted with the cluster key.
+  pub credentials: String,
+}
+
+impl AcmeAccount {
+  pub async fn get_by_directory_url(
+    directory_url: &str,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Option<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT contact, credentials FROM acme_account WHERE directory_url = $1",
+        directory_url
+      )
+      .fetch_optional(pg_pool)
+      .await?,
+    )
+  }
+
+  /// Stores the plaintext credentials encrypted with the cluster key.
+  pub async fn save(
+    directory_url: &str,
+    contact: Option<&str>,
+    credentials: &str,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    let credentials = ClusterKey::get()?.encrypt(credentials, &credentials_aad(directory_url))?;
+    sqlx::query!(
+      "
+      INSERT INTO acme_account (id, directory_url, contact, credentials, updated_at, created_at)
+      VALUES ($1, $2, $3, $4, $5, $6)
+      ON CONFLICT (directory_url) DO UPDATE
+      SET contact = EXCLUDED.contact, credentials = EXCLUDED.credentials, updated_at = EXCLUDED.updated_at
+      ",
+      Uuid::new_v4(),
+      directory_url,
+      contact,
+      credentials,
+      Utc::now(),
+      Utc::now(),
+    )
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+  }
+}
+
+/// A flattened JWS posted to `url` as the account `kid`, signed with the ES256 key instant-acme
+/// generates for accounts.
+fn signed_request(
+  key_pkcs8: &[u8],
+  kid: &str,
+  nonce: &str,
+  payload: &serde_json::Value,
+) -> anyhow::Result<serde_json::Value> {
+  let protected = serde_json::json!({ "alg": "ES256", "kid": kid, "nonce": nonce, "url": kid });
+  let protected = BASE64_URL_SAFE_NO_PAD.encode(protected.to_string());
+  let payload = BASE64_URL_SAFE_NO_PAD.encode(payload.to_string());
+
+  let key = PKey::private_key_from_pkcs8(key_pkcs8)?;
+  let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+  signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+  // JWS wants the raw `r || s` instead of openssl's DER encoding.
+  let signature = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+  let mut raw = signature.r().to_vec_padded(32)?;
+  raw.extend(signature.s().to_vec_padded(32)?);
+
+  Ok(serde_json::json!({
+    "protected": protected,
+    "payload": payload,
+    "signature": BASE64_URL_SAFE_NO_PAD.encode(raw),
+  }))
+}
+
+/// Binds encrypted credentials to the account row of the directory.
+pub fn credentials_aad(directory_url: &str) -> String {
+  format!("acme_account:{}", directory_url)
+}
+
+/// Generates a private key of the type, rcgen can't generate RSA keys so openssl does it.
+pub fn generate_key_pair(key_type: CertificateKeyType) -> anyhow::Result<KeyPair> {
+  let (private_key, algorithm) = match key_type {
+    CertificateKeyType::EcdsaP256 => (
+      ec_private_key(Nid::X9_62_PRIME256V1)?,
+      &rcgen::PKCS_ECDSA_P256_SHA256,
+    ),
+    CertificateKeyType::EcdsaP384 => (
+      ec_private_key(Nid::SECP384R1)?,
+      &rcgen::PKCS_ECDSA_P384_SHA384,
+    ),
+    CertificateKeyType::Rsa2048 => (
+      PKey::from_rsa(Rsa::generate(2048)?)?,
+      &rcgen::PKCS_RSA_SHA256,
+    ),
+    CertificateKeyType::Rsa4096 => (
+      PKey::from_rsa(Rsa::generate(4096)?)?,
+      &rcgen::PKCS_RSA_SHA256,
+    ),
+  };
+  let pem = String::from_utf8(private_key.private_key_to_pem_pkcs8()?)?;
+  Ok(KeyPair::from_pem_and_sign_algo(&pem, algorithm)?)
+}
+
+fn ec_private_key(curve: Nid) -> anyhow::Result<PKey<Private>> {
+  let group = EcGroup::from_curve_name(curve)?;
+  Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+/// A fresh key of the given type and a CSR for the domain, as `(csr_der, private_key_pem)`.
+pub fn certificate_request(
+  domain_name: &str,
+  key_type: CertificateKeyType,
+) -> anyhow::Result<(Vec<u8>, String)> {
+  let key_pair = generate_key_pair(key_type)?;
+  let mut params = CertificateParams::new(vec![domain_name.to_owned()]);
+  params.distinguished_name = DistinguishedName::new();
+  params.alg = key_pair.algorithm();
+  params.key_pair = Some(key_pair);
+  let certificate = rcgen::Certificate::from_params(params)?;
+  Ok((
+    certificate.serialize_request_der()?,
+    certificate.serialize_private_key_pem(),
+  ))
+}
+
+/// Polls a finalized order until the CA issued it, returning the certificate chain PEM.
+pub async fn download_certificate(order: &mut Order, domain_name: &str) -> anyhow::Result<String> {
+  let mut polls = 0;
+  loop {
+    match order.certificate().await? {
+      Some(cert_chain_pem) => return Ok(cert_chain_pem),
+      None if polls < CERTIFICATE_MAX_POLLS => {
+        polls += 1;
+        sleep(Duration::from_secs(1)).await
+      }
+      None => {
+        return Err(anyhow::Error::msg(format!(
+          "Certificate for {} not issued after {} polls",
+          domain_name, CERTIFICATE_MAX_POLLS
+        )))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use instant_acme::{ChallengeType, Identifier, NewOrder, OrderStatus};
+
+  #[test]
+  fn contact_updates_are_signed_with_the_account_key() {
+    let key = ec_private_key(Nid::X9_62_PRIME256V1).unwrap();
+    let kid = "https://acme.example.com/acme/acct/1";
+    let request = signed_request(
+      &key.private_key_to_pkcs8().unwrap(),
+      kid,
+      "nonce",
+      &serde_json::json!({ "contact": ["mailto:ops@example.com"] }),
+    )
+    .unwrap();
+
+    let decode = |field: &str| BASE64_URL_SAFE_NO_PAD.decode(request[field].as_str().unwrap());
+    let protected: serde_json::Value =
+      serde_json::from_slice(&decode("protected").unwrap()).unwrap();
+    assert_eq!(protected["kid"], kid);
+    assert_eq!(protected["url"], kid);
+    let payload: serde_json::Value = serde_json::from_slice(&decode("payload").unwrap()).unwrap();
+    assert_eq!(payload["contact"][0], "mailto:ops@example.com");
+
+    let raw = decode("signature").unwrap();
+    assert_eq!(raw.len(), 64);
+    let signature = EcdsaSig::from_private_components(
+      openssl::bn::BigNum::from_slice(&raw[..32]).unwrap(),
+      openssl::bn::BigNum::from_slice(&raw[32..]).unwrap(),
+    )
+    .unwrap();
+    let signing_input = format!(
+      "{}.{}",
+      request["protected"].as_str().unwrap(),
+      request["payload"].as_str().unwrap()
+    );
+    let digest = openssl::sha::sha256(signing_input.as_bytes());
+    assert!(signature.verify(&digest, &key.ec_key().unwrap()).unwrap());
+  }
+
+  #[test]
+  fn generate_key_pair_of_each_type() {
+    for (key_type, algorithm) in [
+      (
+        CertificateKeyType::EcdsaP256,
+        &rcgen::PKCS_ECDSA_P256_SHA256,
+      ),
+      (
+        CertificateKeyType::EcdsaP384,
+        &rcgen::PKCS_ECDSA_P384_SHA384,
+      ),
+      (CertificateKeyType::Rsa2048, &rcgen::PKCS_RSA_SHA256),
+      (CertificateKeyType::Rsa4096, &rcgen::PKCS_RSA_SHA256),
+    ] {
+      let key_pair = generate_key_pair(key_type).unwrap();
+      assert_eq!(key_pair.algorithm(), algorithm);
+      let mut params = CertificateParams::new(vec!["example.com".to_string()]);
+      params.alg = key_pair.algorithm();
+      params.key_pair = Some(key_pair);
+      let certificate = rcgen::Certificate::from_params(params).unwrap();
+      assert!(certificate.serialize_request_der().is_ok());
+    }
+  }
+
+  /// Runs against a local Pebble started with `PEBBLE_VA_ALWAYS_VALID=1`, see `make test.acme`.
+  #[tokio::test]
+  #[ignore = "requires a Pebble ACME server"]
+  async fn pebble_issues_certificate() {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let directory_url =
+      std::env::var("PEBBLE_DIRECTORY").unwrap_or("https://localhost:14000/dir".to_string());
+    let ca_bundle = std::env::var("PEBBLE_CA_BUNDLE").expect("PEBBLE_CA_BUNDLE is required");
+    let acme = Acme::new(
+      &directory_url,
+      Some(Path::new(&ca_bundle)),
+      None,
+      CertificateKeyType::EcdsaP384,
+    );
+
+    let (account, _) = acme.create_account(&[]).await.unwrap();
+    let domain_name = "doseid.test";
+    let mut order = account
+      .new_order(&NewOrder {
+        identifiers: &[Identifier::Dns(domain_name.to_string())],
+      })
+      .await
+      .unwrap();
+    let authorizations = order.authorizations().await.unwrap();
+    let challenge = authorizations[0]
+      .challenges
+      .iter()
+      .find(|challenge| challenge.r#type == ChallengeType::Http01)
+      .unwrap();
+    order.set_challenge_ready(&challenge.url).await.unwrap();
+
+    let mut status = order.refresh().await.unwrap().status;
+    for _ in 0..10 {
+      if status == OrderStatus::Ready {
+        break;
+      }
+      sleep(Duration::from_millis(500)).await;
+      status = order.refresh().await.unwrap().status;
+    }
+    assert_eq!(status, OrderStatus::Ready);
+
+    let (csr, private_key_pem) = certificate_request(domain_name, acme.key_type).unwrap();
+    order.finalize(&csr).await.unwrap();
+    let cert_chain_pem = download_certificate(&mut order, domain_name).await.unwrap();
+    let leaf = openssl::x509::X509::from_pem(cert_chain_pem.as_bytes()).unwrap();
+    let names: Vec<String> = leaf
+      .subject_alt_names()
+      .unwrap()
+      .iter()
+      .filter_map(|name| name.dnsname().map(str::to_string))
+      .collect();
+    assert_eq!(names, vec![domain_name.to_string()]);
+    let private_key =
+      openssl::pkey::PKey::private_key_from_pem(private_key_pem.as_bytes()).unwrap();
+    assert!(leaf.public_key().unwrap().public_eq(&private_key));
+  }
+
+  #[tokio::test]
+  #[ignore = "requires a Pebble ACME server"]
+  async fn pebble_updates_account_contact() {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let directory_url =
+      std::env::var("PEBBLE_DIRECTORY").unwrap_or("https://localhost:14000/dir".to_string());
+    let ca_bundle = std::env::var("PEBBLE_CA_BUNDLE").expect("PEBBLE_CA_BUNDLE is required");
+    let acme = Acme::new(
+      &directory_url,
+      Some(Path::new(&ca_bundle)),
+      Some("ops@doseid.test"),
+      CertificateKeyType::EcdsaP256,
+    );
+
+    let (_, credentials) = acme.create_account(&[]).await.unwrap();
+    let credentials = serde_json::to_string(&credentials).unwrap();
+    acme.update_contact(&credentials).await.unwrap();
+  }
+}
@@ -0,0 +1,349 @@
+use crate::certificate::encryption::ClusterKey;
+use crate::certificate::{not_after, Certificate, SOURCE_INTERNAL};
+use chrono::{Datelike, Duration, Utc};
+use dosei_schema::app::CertificateKeyType;
+use dosei_schema::cluster::ClusterInit;
+use ipnet::IpNet;
+use once_cell::sync::OnceCell;
+use openssl::x509::X509;
+use rcgen::{
+  date_time_ymd, BasicConstraints, CertificateParams, CidrSubnet, DistinguishedName, DnType,
+  ExtendedKeyUsagePurpose, GeneralSubtree, IsCa, KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use sqlx::{Pool, Postgres};
+use std::net::IpAddr;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const AUTHORITY_COMMON_NAME: &str = "Doseid Internal CA";
+const AUTHORITY_VALIDITY_DAYS: i64 = 3650; // 10 years
+/// Kept under the 825 days browsers accept from user trusted roots.
+const LEAF_VALIDITY_DAYS: i64 = 365; // 1 year
+
+/// Loopback and private ranges, the only addresses the root is allowed to sign for.
+const INTERNAL_IP_RANGES: [&str; 6] = [
+  "127.0.0.0/8",
+  "10.0.0.0/8",
+  "172.16.0.0/12",
+  "192.168.0.0/16",
+  "::1/128",
+  "fc00::/7",
+];
+
+static CERTIFICATE_AUTHORITY: OnceCell<CertificateAuthority> = OnceCell::new();
+
+/// Names a public CA won't issue for, served with certificates of the internal CA instead.
+pub fn is_internal_name(domain_name: &str) -> bool {
+  let cluster_name = CERTIFICATE_AUTHORITY
+    .get()
+    .map(|authority| authority.cluster_name.as_str());
+  is_permitted(domain_name, cluster_name)
+}
+
+/// Whether the name is within the name constraints of the root. The cluster name only counts
+/// when a public CA can't validate it, e.g. `dosei`.
+fn is_permitted(domain_name: &str, cluster_name: Option<&str>) -> bool {
+  match domain_name.parse::<IpAddr>() {
+    Ok(ip) => internal_ip_ranges().any(|range| range.contains(&ip)),
+    Err(_) => {
+      domain_name == "localhost"
+        || domain_name.ends_with(".localhost")
+        || (Some(domain_name) == cluster_name && !ClusterInit::validate_domain(domain_name))
+    }
+  }
+}
+
+/// Binds the encrypted root key to its row.
+pub fn private_key_aad(id: Uuid) -> String {
+  format!("certificate_authority:{}", id)
+}
+
+/// The cluster name when the root has to permit it, public names are left to ACME CAs.
+fn permitted_name(cluster_name: &str) -> Option<&str> {
+  (cluster_name != "localhost"
+    && cluster_name.parse::<IpAddr>().is_err()
+    && !ClusterInit::validate_domain(cluster_name))
+  .then_some(cluster_name)
+}
+
+fn internal_ip_ranges() -> impl Iterator<Item = IpNet> {
+  INTERNAL_IP_RANGES
+    .iter()
+    .map(|range| range.parse().expect("Invalid internal IP range"))
+}
+
+/// The cluster root CA, created on first start and trusted once by developers.
+pub struct CertificateAuthority {
+  /// PEM root certificate exported through the API.
+  pub certificate: String,
+  /// Signer rebuilt from the stored key, issued leaves chain up to the stored root.
+  signer: rcgen::Certificate,
+  /// Permitted by the name constraints of the root next to `localhost` and private IPs.
+  cluster_name: String,
+}
+
+impl CertificateAuthority {
+  /// Loads the stored root, or creates one when there is none or it was constrained to another
+  /// cluster name. Leaves of a replaced root are dropped and issued again on their next
+  /// handshake.
+  pub async fn init(cluster_name: &str, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let stored = sqlx::query!(
+      "
+      SELECT id, certificate, private_key, permitted_name FROM certificate_authority
+      ORDER BY created_at DESC LIMIT 1
+      "
+    )
+    .fetch_optional(pg_pool)
+    .await?;
+    let permitted_name = permitted_name(cluster_name);
+    let authority = match stored {
+      Some(stored) if stored.permitted_name.as_deref() == permitted_name => Self::from_pem(
+        stored.certificate,
+        &ClusterKey::get()?.decrypt(&stored.private_key, &private_key_aad(stored.id))?,
+        cluster_name,
+      )?,
+      stored => {
+        if stored.is_some() {
+          sqlx::query!("DELETE FROM certificate WHERE source = $1", SOURCE_INTERNAL)
+            .execute(pg_pool)
+            .await?;
+          warn!(
+            "Cluster name changed, replacing the internal CA, its new root must be trusted again"
+          );
+        }
+        let key_pair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        let private_key = key_pair.serialize_pem();
+        let signer = rcgen::Certificate::from_params(Self::params(key_pair, cluster_name)?)?;
+        let certificate = signer.serialize_pem()?;
+        let id = Uuid::new_v4();
+        sqlx::query!(
+          "
+          INSERT INTO certificate_authority (id, certificate, private_key, permitted_name, expires_at, created_at)
+          VALUES ($1, $2, $3, $4, $5, $6)
+          ",
+          id,
+          certificate,
+          ClusterKey::get()?.encrypt(&private_key, &private_key_aad(id))?,
+          permitted_name,
+          Utc::now() + Duration::days(AUTHORITY_VALIDITY_DAYS),
+          Utc::now(),
+        )
+        .execute(pg_pool)
+        .await?;
+        info!("Created internal certificate authority");
+        Self {
+          certificate,
+          signer,
+          cluster_name: cluster_name.to_string(),
+        }
+      }
+    };
+    let _ = CERTIFICATE_AUTHORITY.set(authority);
+    Ok(())
+  }
+
+  pub fn get() -> anyhow::Result<&'static Self> {
+    CERTIFICATE_AUTHORITY
+      .get()
+      .ok_or_else(|| anyhow::Error::msg("Internal certificate authority not initialized"))
+  }
+
+  fn from_pem(certificate: String, private_key: &str, cluster_name: &str) -> anyhow::Result<Self> {
+    let params = Self::params(KeyPair::from_pem(private_key)?, cluster_name)?;
+    Ok(Self {
+      certificate,
+      signer: rcgen::Certificate::from_params(params)?,
+      cluster_name: cluster_name.to_string(),
+    })
+  }
+
+  /// Root parameters, the issuer name and key must match the stored root for leaves to verify.
+  /// Name constraints keep a trusted root from vouching for public names if its key leaks.
+  fn params(key_pair: KeyPair, cluster_name: &str) -> anyhow::Result<CertificateParams> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+      .distinguished_name
+      .push(DnType::CommonName, AUTHORITY_COMMON_NAME);
+    params
+      .distinguished_name
+      .push(DnType::OrganizationName, "Dosei");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let mut permitted_subtrees = vec![
+      GeneralSubtree::DnsName("localhost".to_string()),
+      GeneralSubtree::DnsName(".localhost".to_string()),
+    ];
+    if let Some(permitted_name) = permitted_name(cluster_name) {
+      permitted_subtrees.push(GeneralSubtree::DnsName(permitted_name.to_string()));
+    }
+    for range in internal_ip_ranges() {
+      permitted_subtrees.push(GeneralSubtree::IpAddress(CidrSubnet::from_addr_prefix(
+        range.addr(),
+        range.prefix_len(),
+      )));
+    }
+    params.name_constraints = Some(NameConstraints {
+      permitted_subtrees,
+      excluded_subtrees: Vec::new(),
+    });
+    set_validity(&mut params, AUTHORITY_VALIDITY_DAYS);
+    params.key_pair = Some(key_pair);
+    Ok(params)
+  }
+
+  /// Signs a leaf certificate for a DNS name or IP address, returned unsaved.
+  pub fn issue(&self, domain_name: &str, owner_id: Uuid) -> anyhow::Result<Certificate> {
+    let mut params = CertificateParams::new(vec![domain_name.to_string()]);
+    params.distinguished_name = DistinguishedName::new();
+    // Verifiers check a common name without DNS names against the DNS constraints of the root,
+    // which an IP address never matches.
+    if domain_name.parse::<IpAddr>().is_err() {
+      params
+        .distinguished_name
+        .push(DnType::CommonName, domain_name);
+    }
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    set_validity(&mut params, LEAF_VALIDITY_DAYS);
+    let leaf = rcgen::Certificate::from_params(params)?;
+    let certificate = leaf.serialize_pem_with_signer(&self.signer)?;
+    let x509 = X509::from_pem(certificate.as_bytes())?;
+    let expires_at = not_after(&x509)?;
+
+    Ok(Certificate {
+      id: Uuid::new_v4(),
+      domain_name: domain_name.to_string(),
+      expires_at,
+      certificate,
+      private_key: leaf.serialize_private_key_pem(),
+      fingerprint: String::new(),
+      key_type: CertificateKeyType::EcdsaP256.as_str().to_string(),
+      owner_id,
+      source: SOURCE_INTERNAL.to_string(),
+      updated_at: Utc::now(),
+      created_at: Utc::now(),
+    })
+  }
+}
+
+/// Valid from yesterday so clocks slightly behind still accept it, rcgen takes whole days.
+fn set_validity(params: &mut CertificateParams, days: i64) {
+  let not_before = Utc::now() - Duration::days(1);
+  let not_after = not_before + Duration::days(days);
+  params.not_before = date_time_ymd(
+    not_before.year(),
+    not_before.month() as u8,
+    not_before.day() as u8,
+  );
+  params.not_after = date_time_ymd(
+    not_after.year(),
+    not_after.month() as u8,
+    not_after.day() as u8,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::stack::Stack;
+  use openssl::x509::store::X509StoreBuilder;
+  use openssl::x509::{X509StoreContext, X509VerifyResult};
+
+  #[test]
+  fn internal_names() {
+    assert!(is_permitted("localhost", None));
+    assert!(is_permitted("app.localhost", None));
+    assert!(is_permitted("192.168.1.10", None));
+    assert!(is_permitted("::1", None));
+    assert!(is_permitted("dosei", Some("dosei")));
+    assert!(!is_permitted("other", Some("dosei")));
+    assert!(!is_permitted("203.0.113.7", None));
+    assert!(!is_permitted("api.example.com", Some("api.example.com")));
+  }
+
+  #[test]
+  fn issued_leaf_chains_to_restored_root() {
+    let key_pair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let private_key = key_pair.serialize_pem();
+    let root =
+      rcgen::Certificate::from_params(CertificateAuthority::params(key_pair, "dosei").unwrap())
+        .unwrap()
+        .serialize_pem()
+        .unwrap();
+    let authority = CertificateAuthority::from_pem(root.clone(), &private_key, "dosei").unwrap();
+
+    let owner_id = Uuid::new_v4();
+    for domain_name in ["app.localhost", "127.0.0.1", "dosei"] {
+      let certificate = authority.issue(domain_name, owner_id).unwrap();
+      assert_eq!(certificate.source, SOURCE_INTERNAL);
+      assert!(certificate.expires_at > Utc::now() + Duration::days(300));
+      assert!(crate::certificate::parse_certified_key(
+        &certificate.certificate,
+        &certificate.private_key
+      )
+      .is_ok());
+
+      let root = X509::from_pem(root.as_bytes()).unwrap();
+      let leaf = X509::from_pem(certificate.certificate.as_bytes()).unwrap();
+      assert_eq!(root.issued(&leaf), X509VerifyResult::OK);
+      assert!(leaf.verify(&root.public_key().unwrap()).unwrap());
+    }
+  }
+
+  #[test]
+  fn name_constraints_reject_public_names() {
+    let key_pair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let private_key = key_pair.serialize_pem();
+    let root =
+      rcgen::Certificate::from_params(CertificateAuthority::params(key_pair, "dosei").unwrap())
+        .unwrap()
+        .serialize_pem()
+        .unwrap();
+    let authority = CertificateAuthority::from_pem(root.clone(), &private_key, "dosei").unwrap();
+    let mut store = X509StoreBuilder::new().unwrap();
+    store
+      .add_cert(X509::from_pem(root.as_bytes()).unwrap())
+      .unwrap();
+    let store = store.build();
+
+    let owner_id = Uuid::new_v4();
+    for (domain_name, permitted) in [
+      ("app.localhost", true),
+      ("10.1.2.3", true),
+      ("dosei", true),
+      ("api.example.com", false),
+      ("203.0.113.7", false),
+    ] {
+      let certificate = authority.issue(domain_name, owner_id).unwrap();
+      let leaf = X509::from_pem(certificate.certificate.as_bytes()).unwrap();
+      let mut context = X509StoreContext::new().unwrap();
+      let verified = context
+        .init(&store, &leaf, &Stack::new().unwrap(), |context| {
+          context.verify_cert()
+        })
+        .unwrap();
+      assert_eq!(verified, permitted, "{}", domain_name);
+    }
+  }
+
+  #[test]
+  fn public_cluster_names_stay_outside_the_root() {
+    assert_eq!(permitted_name("dosei"), Some("dosei"));
+    assert_eq!(permitted_name("example.com"), None);
+    assert_eq!(permitted_name("localhost"), None);
+    assert_eq!(permitted_name("10.0.0.1"), None);
+
+    let key_pair = KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let params = CertificateAuthority::params(key_pair, "example.com").unwrap();
+    let permitted_subtrees = params
+      .name_constraints
+      .as_ref()
+      .unwrap()
+      .permitted_subtrees
+      .clone();
+    assert!(!permitted_subtrees.iter().any(
+      |subtree| matches!(subtree, GeneralSubtree::DnsName(name) if name.contains("example.com"))
+    ));
+  }
+}
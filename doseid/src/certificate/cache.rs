@@ -0,0 +1,176 @@
+use crate::certificate::Certificate;
+use once_cell::sync::Lazy;
+use rustls::sign::CertifiedKey;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+use tracing::{debug, error, info};
+
+/// How long a domain without a certificate is remembered before the database is asked again.
+const NEGATIVE_LIFESPAN: Duration = Duration::from_secs(30);
+/// How often expired negative entries are dropped, they're keyed by arbitrary client SNI.
+const SWEEP_SPAN: u64 = 60;
+
+enum Entry {
+  Found(Arc<CertifiedKey>),
+  NotFound(Instant),
+}
+
+/// The wildcard certificate name covering a domain, wildcards only match a single label.
+pub fn wildcard_name(domain_name: &str) -> Option<String> {
+  let (_, parent) = domain_name.split_once('.')?;
+  parent.contains('.').then(|| format!("*.{}", parent))
+}
+
+pub enum Lookup {
+  Found(Arc<CertifiedKey>),
+  NotFound,
+  /// Not cached, the caller should load it from the database.
+  Unknown,
+}
+
+/// Parsed TLS certificates keyed by SNI name, so handshakes don't hit Postgres.
+///
+/// Uses a std lock because rustls resolves certificates from a sync context.
+pub struct CertificateCache {
+  entries: RwLock<HashMap<String, Entry>>,
+}
+
+pub static CERTIFICATE_CACHE: Lazy<CertificateCache> = Lazy::new(|| CertificateCache {
+  entries: RwLock::new(HashMap::new()),
+});
+
+impl CertificateCache {
+  pub fn get(&self, domain_name: &str) -> Lookup {
+    let entries = self.entries.read().unwrap();
+    match entries.get(domain_name) {
+      Some(Entry::Found(certified_key)) => Lookup::Found(Arc::clone(certified_key)),
+      Some(Entry::NotFound(cached_at)) if cached_at.elapsed() < NEGATIVE_LIFESPAN => {
+        Lookup::NotFound
+      }
+      _ => Lookup::Unknown,
+    }
+  }
+
+  pub fn insert(&self, domain_name: &str, certified_key: Option<Arc<CertifiedKey>>) {
+    let entry = match certified_key {
+      Some(certified_key) => Entry::Found(certified_key),
+      None => Entry::NotFound(Instant::now()),
+    };
+    let mut entries = self.entries.write().unwrap();
+    entries.insert(domain_name.to_string(), entry);
+  }
+
+  /// Drops negative entries that have outlived [`NEGATIVE_LIFESPAN`].
+  pub fn sweep(&self) -> usize {
+    let mut entries = self.entries.write().unwrap();
+    let before = entries.len();
+    entries.retain(|_, entry| match entry {
+      Entry::Found(_) => true,
+      Entry::NotFound(cached_at) => cached_at.elapsed() < NEGATIVE_LIFESPAN,
+    });
+    before - entries.len()
+  }
+
+  pub fn start_sweeper(&'static self) {
+    tokio::spawn(async move {
+      let mut interval = interval(Duration::from_secs(SWEEP_SPAN));
+      loop {
+        interval.tick().await;
+        let swept = self.sweep();
+        if swept > 0 {
+          debug!("Swept {} expired certificate cache entries", swept);
+        }
+      }
+    });
+  }
+
+  /// Loads every stored certificate, called once when the proxy starts.
+  pub async fn warm(&self, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let certificates = Certificate::get_all(pg_pool).await?;
+    let mut loaded = 0;
+    for certificate in certificates {
+      match certificate.certified_key() {
+        Ok(certified_key) => {
+          self.insert(&certificate.domain_name, Some(Arc::new(certified_key)));
+          loaded += 1;
+        }
+        Err(e) => error!(
+          "Failed to load certificate for {}: {}",
+          certificate.domain_name, e
+        ),
+      }
+    }
+    info!("Loaded {} certificates into cache", loaded);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::certificate::parse_certified_key;
+
+  #[test]
+  fn wildcard_name_covers_one_label() {
+    assert_eq!(
+      wildcard_name("api.apps.example.com").as_deref(),
+      Some("*.apps.example.com")
+    );
+    assert_eq!(wildcard_name("example.com"), None);
+    assert_eq!(wildcard_name("localhost"), None);
+  }
+
+  #[test]
+  fn sweep_drops_expired_negative_entries() {
+    let cache = CertificateCache {
+      entries: RwLock::new(HashMap::new()),
+    };
+    cache.insert("fresh.example.com", None);
+    cache.entries.write().unwrap().insert(
+      "stale.example.com".to_string(),
+      Entry::NotFound(Instant::now() - NEGATIVE_LIFESPAN),
+    );
+    assert_eq!(cache.sweep(), 1);
+    assert!(matches!(cache.get("fresh.example.com"), Lookup::NotFound));
+    assert!(!cache
+      .entries
+      .read()
+      .unwrap()
+      .contains_key("stale.example.com"));
+  }
+
+  #[test]
+  fn renewed_certificates_are_swapped_in_place() {
+    let cache = CertificateCache {
+      entries: RwLock::new(HashMap::new()),
+    };
+    let certified_key = |names: &[&str]| {
+      let names = names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+      let certificate = rcgen::generate_simple_self_signed(names).unwrap();
+      Arc::new(
+        parse_certified_key(
+          &certificate.serialize_pem().unwrap(),
+          &certificate.serialize_private_key_pem(),
+        )
+        .unwrap(),
+      )
+    };
+    let previous = certified_key(&["example.com"]);
+    cache.insert("example.com", Some(Arc::clone(&previous)));
+    let Lookup::Found(handshake) = cache.get("example.com") else {
+      panic!("certificate not cached");
+    };
+
+    let renewed = certified_key(&["example.com"]);
+    cache.insert("example.com", Some(Arc::clone(&renewed)));
+    assert!(matches!(cache.get("example.com"), Lookup::Found(key) if Arc::ptr_eq(&key, &renewed)));
+    // Handshakes already resolved keep the replaced certificate.
+    assert!(Arc::ptr_eq(&handshake, &previous));
+  }
+}
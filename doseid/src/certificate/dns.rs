@@ -0,0 +1,260 @@
+use crate::config::{Config, DnsProviderConfig};
+use base64::Engine;
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
+use tracing::info;
+use trust_dns_proto::op::{update_message, Message, ResponseCode};
+use trust_dns_proto::rr::dnssec::rdata::tsig::TsigAlgorithm;
+use trust_dns_proto::rr::dnssec::tsig::TSigner;
+use trust_dns_proto::rr::rdata::TXT;
+use trust_dns_proto::rr::{Name, RData, RecordSet, RecordType};
+use trust_dns_proto::serialize::binary::BinEncodable;
+
+const CHALLENGE_RECORD_TTL: u32 = 60; // 1 minute
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Allowed clock skew between doseid and the nameserver for TSIG.
+const TSIG_FUDGE: u16 = 300; // 5 minutes
+
+static DNS_PROVIDER: OnceCell<Box<dyn DnsProvider>> = OnceCell::new();
+
+/// Publishes the TXT records of DNS-01 challenges.
+#[async_trait::async_trait]
+pub trait DnsProvider: Send + Sync {
+  /// Whether the provider manages the zone of the name.
+  fn handles(&self, domain_name: &str) -> bool;
+
+  async fn present(&self, record_name: &str, value: &str) -> anyhow::Result<()>;
+
+  async fn cleanup(&self, record_name: &str, value: &str) -> anyhow::Result<()>;
+}
+
+pub fn init(config: &Config) -> anyhow::Result<()> {
+  let Some(provider_config) = &config.dns_provider else {
+    return Ok(());
+  };
+  let provider: Box<dyn DnsProvider> = match provider_config {
+    DnsProviderConfig::Rfc2136 {
+      nameserver,
+      zone,
+      tsig_key,
+      tsig_secret,
+      tsig_algorithm,
+    } => Box::new(Rfc2136Provider::new(
+      nameserver,
+      zone,
+      tsig_key.as_deref().zip(tsig_secret.as_deref()),
+      tsig_algorithm,
+    )?),
+  };
+  info!("DNS-01 challenges enabled");
+  let _ = DNS_PROVIDER.set(provider);
+  Ok(())
+}
+
+/// The configured provider for the domain, if any.
+pub fn provider_for(domain_name: &str) -> Option<&'static dyn DnsProvider> {
+  DNS_PROVIDER
+    .get()
+    .map(|provider| provider.as_ref())
+    .filter(|provider| provider.handles(domain_name))
+}
+
+/// The TXT record name a challenge is answered on, wildcards share their base name's record.
+pub fn challenge_record_name(domain_name: &str) -> String {
+  let base = domain_name.strip_prefix("*.").unwrap_or(domain_name);
+  format!("_acme-challenge.{}", base)
+}
+
+/// Dynamic updates (RFC 2136) sent straight to the primary nameserver, e.g. BIND or Knot.
+pub struct Rfc2136Provider {
+  nameserver: String,
+  zone: Name,
+  signer: Option<TSigner>,
+}
+
+impl Rfc2136Provider {
+  pub fn new(
+    nameserver: &str,
+    zone: &str,
+    tsig: Option<(&str, &str)>,
+    tsig_algorithm: &str,
+  ) -> anyhow::Result<Self> {
+    let signer = match tsig {
+      Some((key_name, secret)) => {
+        let secret = base64::engine::general_purpose::STANDARD.decode(secret.trim())?;
+        let algorithm = TsigAlgorithm::from_name(Name::from_ascii(tsig_algorithm)?);
+        Some(TSigner::new(
+          secret,
+          algorithm,
+          Name::from_ascii(key_name)?,
+          TSIG_FUDGE,
+        )?)
+      }
+      None => None,
+    };
+    Ok(Self {
+      nameserver: nameserver.to_string(),
+      zone: fqdn(zone)?,
+      signer,
+    })
+  }
+
+  fn record_set(&self, record_name: &str, value: &str) -> anyhow::Result<RecordSet> {
+    let name = fqdn(record_name)?;
+    if !self.zone.zone_of(&name) {
+      return Err(anyhow::Error::msg(format!(
+        "{} is not in zone {}",
+        record_name, self.zone
+      )));
+    }
+    let mut record_set = RecordSet::with_ttl(name, RecordType::TXT, CHALLENGE_RECORD_TTL);
+    record_set.add_rdata(RData::TXT(TXT::new(vec![value.to_string()])));
+    Ok(record_set)
+  }
+
+  async fn send(&self, mut message: Message) -> anyhow::Result<()> {
+    if let Some(signer) = &self.signer {
+      message.finalize(signer, Utc::now().timestamp() as u32)?;
+    }
+    let nameserver: SocketAddr = lookup_host(&self.nameserver)
+      .await?
+      .next()
+      .ok_or_else(|| anyhow::Error::msg(format!("Failed to resolve {}", self.nameserver)))?;
+    let bind_address = if nameserver.is_ipv4() {
+      "0.0.0.0:0"
+    } else {
+      "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(nameserver).await?;
+    socket.send(&message.to_bytes()?).await?;
+
+    let mut buffer = [0u8; 4096];
+    let length = timeout(UPDATE_TIMEOUT, socket.recv(&mut buffer)).await??;
+    let response = Message::from_vec(&buffer[..length])?;
+    if response.id() != message.id() {
+      return Err(anyhow::Error::msg("DNS update response id mismatch"));
+    }
+    if response.response_code() != ResponseCode::NoError {
+      return Err(anyhow::Error::msg(format!(
+        "DNS update rejected by {}: {}",
+        self.nameserver,
+        response.response_code()
+      )));
+    }
+    Ok(())
+  }
+}
+
+#[async_trait::async_trait]
+impl DnsProvider for Rfc2136Provider {
+  fn handles(&self, domain_name: &str) -> bool {
+    fqdn(&challenge_record_name(domain_name)).is_ok_and(|name| self.zone.zone_of(&name))
+  }
+
+  async fn present(&self, record_name: &str, value: &str) -> anyhow::Result<()> {
+    let record_set = self.record_set(record_name, value)?;
+    self
+      .send(update_message::append(
+        record_set,
+        self.zone.clone(),
+        false,
+        false,
+      ))
+      .await
+  }
+
+  async fn cleanup(&self, record_name: &str, value: &str) -> anyhow::Result<()> {
+    let record_set = self.record_set(record_name, value)?;
+    self
+      .send(update_message::delete_by_rdata(
+        record_set,
+        self.zone.clone(),
+        false,
+      ))
+      .await
+  }
+}
+
+fn fqdn(name: &str) -> anyhow::Result<Name> {
+  let mut name = Name::from_str(name)?;
+  name.set_fqdn(true);
+  Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rfc2136_handles_names_in_its_zone() {
+    let provider = Rfc2136Provider::new(
+      "127.0.0.1:53",
+      "example.com",
+      Some(("doseid", "c2VjcmV0")),
+      "hmac-sha256",
+    )
+    .unwrap();
+    assert!(provider.handles("*.apps.example.com"));
+    assert!(provider.handles("example.com"));
+    assert!(!provider.handles("example.org"));
+    assert_eq!(
+      challenge_record_name("*.apps.example.com"),
+      "_acme-challenge.apps.example.com"
+    );
+    assert!(provider
+      .record_set("_acme-challenge.example.org", "value")
+      .is_err());
+  }
+
+  /// Runs against a nameserver accepting updates for `RFC2136_ZONE`, e.g. BIND with
+  /// `allow-update`, reachable on `RFC2136_NAMESERVER` as `ip:port`.
+  #[tokio::test]
+  #[ignore = "requires an RFC 2136 nameserver"]
+  async fn rfc2136_publishes_and_removes_txt_record() {
+    use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+    use trust_dns_resolver::TokioAsyncResolver;
+
+    let nameserver = std::env::var("RFC2136_NAMESERVER").expect("RFC2136_NAMESERVER is required");
+    let zone = std::env::var("RFC2136_ZONE").expect("RFC2136_ZONE is required");
+    let tsig_key = std::env::var("RFC2136_TSIG_KEY").ok();
+    let tsig_secret = std::env::var("RFC2136_TSIG_SECRET").ok();
+    let provider = Rfc2136Provider::new(
+      &nameserver,
+      &zone,
+      tsig_key.as_deref().zip(tsig_secret.as_deref()),
+      "hmac-sha256",
+    )
+    .unwrap();
+
+    let address: SocketAddr = nameserver.parse().unwrap();
+    let mut opts = ResolverOpts::default();
+    opts.cache_size = 0;
+    let resolver = TokioAsyncResolver::tokio(
+      ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(&[address.ip()], address.port(), true),
+      ),
+      opts,
+    );
+    let record_name = challenge_record_name(&format!("*.doseid.{}", zone));
+    let value = "doseid-challenge";
+    let published = |resolver: TokioAsyncResolver, record_name: String| async move {
+      resolver
+        .txt_lookup(record_name.as_str())
+        .await
+        .is_ok_and(|lookup| lookup.iter().any(|txt| txt.to_string() == value))
+    };
+
+    provider.present(&record_name, value).await.unwrap();
+    assert!(published(resolver.clone(), record_name.clone()).await);
+    provider.cleanup(&record_name, value).await.unwrap();
+    assert!(!published(resolver, record_name).await);
+  }
+}
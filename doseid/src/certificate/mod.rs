@@ -1,16 +1,30 @@
-use cached::{Cached, TimedCache};
+use crate::certificate::acme::{certificate_request, download_certificate, Acme};
+use crate::certificate::authority::CertificateAuthority;
+use crate::certificate::cache::CERTIFICATE_CACHE;
+use crate::certificate::encryption::ClusterKey;
+use crate::ingress::Ingress;
 use chrono::{DateTime, Utc};
+use dosei_schema::app::CertificateKeyType;
+use futures_util::future::join_all;
 use instant_acme::{
-  Account as AcmeAccount, ChallengeType, Identifier, LetsEncrypt, NewAccount, NewOrder, Order,
-  OrderStatus,
+  Authorization, AuthorizationStatus, ChallengeType, Identifier, NewOrder, Order, OrderStatus,
 };
-use once_cell::sync::Lazy;
-use rcgen::{CertificateParams, DistinguishedName};
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::x509::{X509Ref, X509};
+use order::{
+  CertificateOrder, OrderChallenge, CHALLENGE_DNS01, CHALLENGE_HTTP01, ORDER_FAILED, ORDER_ISSUED,
+  ORDER_PENDING, ORDER_VALIDATING,
+};
+use rustls::pki_types::CertificateDer;
+use rustls::sign::CertifiedKey;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
+use std::io::BufReader;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
 use tokio::time::{interval, sleep};
 use tracing::{error, info, warn};
 use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
@@ -18,6 +32,13 @@ use trust_dns_resolver::TokioAsyncResolver;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
+pub mod acme;
+pub mod authority;
+pub mod cache;
+pub mod dns;
+pub mod encryption;
+pub mod order;
+pub mod renewal;
 pub mod route;
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
@@ -25,42 +46,46 @@ pub struct Certificate {
   pub id: Uuid,
   pub domain_name: String,
   pub certificate: String,
+  /// Encrypted with the cluster key once stored, never returned by the API.
+  #[serde(skip_serializing)]
   pub private_key: String,
+  /// SHA-256 of the leaf certificate.
+  pub fingerprint: String,
+  /// Key algorithm, e.g. `ecdsa-p256` or `rsa-2048`.
+  pub key_type: String,
   pub expires_at: DateTime<Utc>,
   pub owner_id: Uuid,
+  /// Either [`SOURCE_ACME`] or [`SOURCE_UPLOADED`].
+  pub source: String,
   pub updated_at: DateTime<Utc>,
   pub created_at: DateTime<Utc>,
 }
 
-#[derive(Clone)]
-pub(crate) struct PendingCertificate {
-  pub domain_name: String,
-  pub owner_id: Uuid,
-  pub token: String,
-  pub token_value: String,
-  pub order: Arc<Mutex<Order>>,
-}
+pub const SOURCE_ACME: &str = "acme";
+/// Brought by the account from its own CA, never renewed by doseid.
+pub const SOURCE_UPLOADED: &str = "uploaded";
+/// Signed by the cluster CA for names a public CA won't issue for.
+pub const SOURCE_INTERNAL: &str = "internal";
 
-const CACHE_LIFESPAN: u64 = 600;
 const INTERNAL_CHECK_SPAN: u64 = 5; // 5 seconds
+const INTERNAL_CHECK_TIMEOUT: u64 = 10; // 10 seconds
+/// Self-checks of the challenge before giving up, about 10 minutes.
+const INTERNAL_MAX_CHECKS: i32 = 120;
 const RENEWAL_CHECK_SPAN: u64 = 86400; // 24 hours
-const EXTERNAL_MAX_CHECKS: u64 = 10;
+const EXTERNAL_MAX_CHECKS: i32 = 10;
+const EXTERNAL_MAX_BACKOFF: Duration = Duration::from_secs(300);
 
-pub struct CertificateManager {
-  pub internal_pending_certificate_cache: Arc<Mutex<TimedCache<String, PendingCertificate>>>,
-  pub http1_challenge_token_cache: Arc<Mutex<TimedCache<String, String>>>,
-}
-
-pub static CERTIFICATE_MANAGER: Lazy<CertificateManager> = Lazy::new(|| CertificateManager {
-  internal_pending_certificate_cache: Arc::new(Mutex::new(TimedCache::with_lifespan(
-    CACHE_LIFESPAN,
-  ))),
-  http1_challenge_token_cache: Arc::new(Mutex::new(TimedCache::with_lifespan(CACHE_LIFESPAN))),
-});
+pub struct CertificateManager;
 
 pub async fn start_certificate_server(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::Result<()> {
   info!("Doseid Certificate Server Running");
 
+  // Orders handed to the CA before a restart pick up where they left off.
+  for order in CertificateOrder::get_by_status(ORDER_VALIDATING, pg_pool).await? {
+    info!("Resuming certificate order for {}", order.domain_name);
+    CertificateManager::external_check(order, Arc::clone(pg_pool));
+  }
+
   let pool_clone = Arc::clone(pg_pool);
   // Start Internal Check Loop
   tokio::spawn(async move {
@@ -69,106 +94,19 @@ pub async fn start_certificate_server(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::
     loop {
       interval.tick().await;
 
-      let mut certificates_to_check = Vec::new();
-      {
-        let cache_lock = CERTIFICATE_MANAGER
-          .internal_pending_certificate_cache
-          .lock()
-          .await;
-        for (_, (_, cert)) in cache_lock.get_store().iter() {
-          certificates_to_check.push(cert.clone());
+      let orders = match CertificateOrder::get_by_status(ORDER_PENDING, &pool_clone).await {
+        Ok(orders) => orders,
+        Err(e) => {
+          error!("Failed to load pending certificate orders: {}", e);
+          continue;
         }
-      }
-      if !certificates_to_check.is_empty() {
-        info!("Active pending certificates in cache:");
-      }
-      for cert in certificates_to_check {
-        info!("{}", cert.domain_name);
-        let cert_pool = Arc::clone(&pool_clone);
-        tokio::spawn(async move {
-          let mut opts = ResolverOpts::default();
-          opts.cache_size = 0;
-          opts.negative_max_ttl = Some(Duration::from_secs(0));
-          opts.positive_max_ttl = Some(Duration::from_secs(0));
-
-          let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), opts);
-          let lookup_result = resolver.ipv4_lookup(&cert.domain_name).await;
-
-          if let Err(e) = &lookup_result {
-            error!("DNS lookup failed for {}: {:?}", cert.domain_name, e);
-            return;
-          }
-
-          // Get the first IP address
-          let response = lookup_result.unwrap();
-          let address = match response.iter().next() {
-            Some(addr) => addr,
-            None => {
-              error!("No IP address found for domain: {}", cert.domain_name);
-              return;
-            }
-          };
-
-          // Build the HTTP client
-          let client_result = reqwest::Client::builder().no_proxy().build();
-
-          if let Err(e) = &client_result {
-            error!("Failed to build HTTP client: {:?}", e);
-            return;
-          }
-
-          let client = client_result.unwrap();
-
-          // Create URL for verification
-          let url = format!(
-            "http://{}/.well-known/acme-challenge/{}",
-            address, cert.token
-          );
-
-          // Send HTTP request
-          let response_result = client.get(&url).send().await;
-
-          if let Err(e) = &response_result {
-            error!("Request failed for {}: {:?}", cert.domain_name, e);
-            return;
-          }
-
-          let response = response_result.unwrap();
-
-          // Get the response text
-          let text_result = response.text().await;
-
-          if let Err(e) = &text_result {
-            error!(
-              "Failed to get response text for {}: {:?}",
-              cert.domain_name, e
-            );
-            return;
-          }
-
-          let response_text = text_result.unwrap();
-
-          // Verify the token
-          if response_text == cert.token_value {
-            info!(
-              "Certificate verification successful for {}: All good go for external check!",
-              cert.domain_name
-            );
-            CertificateManager::external_check(cert.clone(), cert_pool);
-            let internal_pending_certificate_cache =
-              Arc::clone(&CERTIFICATE_MANAGER.internal_pending_certificate_cache);
-            {
-              let mut cache = internal_pending_certificate_cache.lock().await;
-              cache.cache_remove(&cert.domain_name);
-            }
-          } else {
-            warn!(
-              "Certificate verification failed for {}: Token mismatch",
-              cert.domain_name
-            );
-          }
-        });
-      }
+      };
+      join_all(
+        orders
+          .into_iter()
+          .map(|order| CertificateManager::internal_check(order, Arc::clone(&pool_clone))),
+      )
+      .await;
     }
   });
 
@@ -190,86 +128,215 @@ pub async fn start_certificate_server(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::
 }
 
 impl CertificateManager {
-  pub fn external_check(pending_certificate: PendingCertificate, pg_pool: Arc<Pool<Postgres>>) {
-    let order = Arc::clone(&pending_certificate.order);
+  /// Checks the challenge is reachable through our proxy before asking the CA to validate it,
+  /// so a misconfigured DNS record doesn't fail the order at the CA.
+  async fn internal_check(mut order: CertificateOrder, pg_pool: Arc<Pool<Postgres>>) {
+    match Self::serves_challenge(&order).await {
+      Ok(()) => {
+        info!(
+          "Certificate verification successful for {}: All good go for external check!",
+          order.domain_name
+        );
+        if let Err(e) = order.update_status(ORDER_VALIDATING, &pg_pool).await {
+          error!("Failed to update certificate order: {}", e);
+          return;
+        }
+        Self::external_check(order, pg_pool);
+      }
+      Err(e) => {
+        warn!(
+          "Certificate verification failed for {}: {}",
+          order.domain_name, e
+        );
+        if let Err(e) = order
+          .record_failure(&e.to_string(), INTERNAL_MAX_CHECKS, &pg_pool)
+          .await
+        {
+          error!("Failed to update certificate order: {}", e);
+        }
+        if order.status == ORDER_FAILED {
+          Self::cleanup(&order).await;
+        }
+      }
+    }
+  }
 
-    let mut attempts = 1;
-    let mut backoff_duration = Duration::from_millis(250);
+  /// Removes the TXT record of a finished dns-01 order.
+  async fn cleanup(order: &CertificateOrder) {
+    if order.challenge_type != CHALLENGE_DNS01 {
+      return;
+    }
+    let Some(provider) = dns::provider_for(&order.domain_name) else {
+      return;
+    };
+    let record_name = dns::challenge_record_name(&order.domain_name);
+    if let Err(e) = provider
+      .cleanup(&record_name, &order.key_authorization)
+      .await
+    {
+      warn!("Failed to remove {} TXT record: {}", record_name, e);
+    }
+  }
+
+  async fn serves_challenge(order: &CertificateOrder) -> anyhow::Result<()> {
+    let mut opts = ResolverOpts::default();
+    opts.cache_size = 0;
+    opts.negative_max_ttl = Some(Duration::from_secs(0));
+    opts.positive_max_ttl = Some(Duration::from_secs(0));
+
+    let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), opts);
+    if order.challenge_type == CHALLENGE_DNS01 {
+      // Waits for the TXT record to propagate, the CA gives a single shot per order.
+      let record_name = dns::challenge_record_name(&order.domain_name);
+      let published = resolver
+        .txt_lookup(record_name.as_str())
+        .await?
+        .iter()
+        .any(|txt| txt.to_string() == order.key_authorization);
+      if !published {
+        return Err(anyhow::Error::msg(format!(
+          "{} TXT record not published yet",
+          record_name
+        )));
+      }
+      return Ok(());
+    }
+    let response = resolver.ipv4_lookup(&order.domain_name).await?;
+    let address = response
+      .iter()
+      .next()
+      .ok_or_else(|| anyhow::Error::msg("No IP address found for domain"))?;
+
+    let client = reqwest::Client::builder()
+      .no_proxy()
+      .timeout(Duration::from_secs(INTERNAL_CHECK_TIMEOUT))
+      .build()?;
+    let url = format!(
+      "http://{}/.well-known/acme-challenge/{}",
+      address, order.token
+    );
+    let response_text = client.get(&url).send().await?.text().await?;
+    if response_text != order.key_authorization {
+      return Err(anyhow::Error::msg("Token mismatch"));
+    }
+    Ok(())
+  }
+
+  pub fn external_check(mut order: CertificateOrder, pg_pool: Arc<Pool<Postgres>>) {
     tokio::spawn(async move {
-      loop {
-        sleep(backoff_duration).await;
-        let mut order_guard = order.lock().await;
-
-        let authorizations = order_guard.authorizations().await.unwrap();
-        let authorization = &authorizations
-          .first()
-          .ok_or_else(|| anyhow::Error::msg("authorization not found"))
-          .unwrap();
-        let challenge = authorization
-          .challenges
-          .iter()
-          .find(|ch| ch.r#type == ChallengeType::Http01)
-          .ok_or_else(|| anyhow::Error::msg("http-01 challenge not found"))
-          .unwrap();
-        order_guard
-          .set_challenge_ready(&challenge.url)
-          .await
-          .unwrap();
-
-        let order_state = order_guard.refresh().await.unwrap();
-        match order_state.status {
-          OrderStatus::Ready => {
-            drop(order_guard);
-            match Certificate::new(pending_certificate, Arc::clone(&pg_pool)).await {
-              Ok(_) => {}
-              Err(_) => error!("Something went wrong when generating CERT"),
-            };
-            break;
-          }
-          order_status => {
-            error!("Order Status: {:?}", order_status);
-            error!("Full Order State: {:?}", order_state);
-            backoff_duration *= 4;
-            attempts += 1;
-
-            if EXTERNAL_MAX_CHECKS <= attempts {
-              error!("Order is not yet ready after {EXTERNAL_MAX_CHECKS} attempts, Giving up.");
-              break;
-            }
-            info!("Order is not ready, waiting {backoff_duration:?}");
-          }
+      if let Err(e) = Self::validate(&mut order, &pg_pool).await {
+        error!("Certificate order for {} failed: {}", order.domain_name, e);
+        if let Err(e) = order.fail(&e.to_string(), &pg_pool).await {
+          error!("Failed to update certificate order: {}", e);
         }
       }
+      Self::cleanup(&order).await;
     });
   }
 
-  pub async fn get_http01_challenge_token_value(token: String) -> Option<String> {
-    let http1_challenge_token_cache = Arc::clone(&CERTIFICATE_MANAGER.http1_challenge_token_cache);
-    {
-      let mut cache = http1_challenge_token_cache.lock().await;
-      if let Some(value) = cache.cache_get(&token) {
-        let token_value = value.clone();
-        return cache.cache_set(token, token_value);
+  /// Asks the CA to validate the challenge and polls the order until it can be finalized.
+  async fn validate(order: &mut CertificateOrder, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let account = Acme::get()?.account(pg_pool).await?;
+    let mut acme_order = account.order(order.order_url.clone()).await?;
+
+    let mut backoff_duration = Duration::from_millis(250);
+    let mut challenge_ready = false;
+    loop {
+      sleep(backoff_duration).await;
+
+      let authorizations = acme_order.authorizations().await?;
+      let authorization = authorizations
+        .first()
+        .ok_or_else(|| anyhow::Error::msg("authorization not found"))?;
+      let challenge = authorization
+        .challenges
+        .iter()
+        .find(|ch| ch.r#type == order.acme_challenge_type())
+        .ok_or_else(|| {
+          anyhow::Error::msg(format!("{} challenge not found", order.challenge_type))
+        })?;
+      // A resumed order may have been handed to the CA already, which refuses it twice.
+      if !challenge_ready && awaits_challenge(authorization) {
+        acme_order.set_challenge_ready(&challenge.url).await?;
+      }
+      challenge_ready = true;
+
+      let order_state = acme_order.refresh().await?;
+      match order_state.status {
+        OrderStatus::Ready => {
+          Certificate::finalize(order, &mut acme_order, pg_pool).await?;
+          order.update_status(ORDER_ISSUED, pg_pool).await?;
+          return Ok(());
+        }
+        // Finalized before a restart, the certificate is downloaded with the stored CSR key.
+        OrderStatus::Valid => {
+          let private_key = order.private_key.as_deref().ok_or_else(|| {
+            anyhow::Error::msg("Order was finalized without storing its private key")
+          })?;
+          let private_key = ClusterKey::get()?.decrypt(private_key, &order.private_key_aad())?;
+          Certificate::download(order, &mut acme_order, private_key, pg_pool).await?;
+          order.update_status(ORDER_ISSUED, pg_pool).await?;
+          return Ok(());
+        }
+        OrderStatus::Invalid => {
+          let detail = authorization
+            .challenges
+            .iter()
+            .find_map(|challenge| challenge.error.as_ref())
+            .and_then(|error| error.detail.clone())
+            .unwrap_or("The CA rejected the order".to_string());
+          return Err(anyhow::Error::msg(detail));
+        }
+        order_status => {
+          order
+            .record_failure(
+              &format!("Order is not ready yet: {:?}", order_status),
+              EXTERNAL_MAX_CHECKS,
+              pg_pool,
+            )
+            .await?;
+          if order.status == ORDER_FAILED {
+            error!("Order is not yet ready after {EXTERNAL_MAX_CHECKS} attempts, Giving up.");
+            return Ok(());
+          }
+          backoff_duration = (backoff_duration * 4).min(EXTERNAL_MAX_BACKOFF);
+          info!("Order is not ready, waiting {backoff_duration:?}");
+        }
       }
     }
-    None
   }
 }
 
 impl Certificate {
-  pub async fn request(owner_id: Uuid, domain_name: &str) -> anyhow::Result<()> {
-    let server_url = LetsEncrypt::Production.url().to_string();
-
-    let new_account_info = NewAccount {
-      contact: &[],
-      terms_of_service_agreed: true,
-      only_return_existing: false,
+  /// Orders a certificate, with the cluster key type unless the domain chose one.
+  pub async fn request(
+    owner_id: Uuid,
+    domain_name: &str,
+    key_type: Option<CertificateKeyType>,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    if authority::is_internal_name(domain_name) {
+      return Err(anyhow::Error::msg(format!(
+        "{} can't be validated by an ACME CA, it is served by the internal CA",
+        domain_name
+      )));
+    }
+    let dns_provider = dns::provider_for(domain_name);
+    let challenge_type = match (dns_provider, domain_name.starts_with("*.")) {
+      (Some(_), _) => ChallengeType::Dns01,
+      (None, true) => {
+        return Err(anyhow::Error::msg(format!(
+          "Wildcard certificate for {} needs a DNS provider, set DNS_PROVIDER",
+          domain_name
+        )))
+      }
+      (None, false) => ChallengeType::Http01,
     };
+    let acme = Acme::get()?;
+    let key_type = key_type.unwrap_or(acme.key_type);
+    let account = acme.account(pg_pool).await?;
 
-    let (_account, credentials) = AcmeAccount::create(&new_account_info, &server_url, None).await?;
-
-    let mut order = AcmeAccount::from_credentials(credentials)
-      .await?
+    let mut order = account
       .new_order(&NewOrder {
         identifiers: &[Identifier::Dns(domain_name.to_string())],
       })
@@ -281,56 +348,78 @@ impl Certificate {
     let challenge = authorization
       .challenges
       .iter()
-      .find(|ch| ch.r#type == ChallengeType::Http01)
-      .ok_or_else(|| anyhow::Error::msg("http-01 challenge not found"))?;
+      .find(|ch| ch.r#type == challenge_type)
+      .ok_or_else(|| anyhow::Error::msg(format!("{:?} challenge not found", challenge_type)))?;
+
+    let key_authorization = order.key_authorization(challenge);
+    let (challenge_type, key_authorization) = match dns_provider {
+      Some(provider) => {
+        let value = key_authorization.dns_value();
+        provider
+          .present(&dns::challenge_record_name(domain_name), &value)
+          .await?;
+        (CHALLENGE_DNS01, value)
+      }
+      None => (CHALLENGE_HTTP01, key_authorization.as_str().to_string()),
+    };
+    CertificateOrder::new(
+      domain_name,
+      owner_id,
+      order.url(),
+      OrderChallenge {
+        challenge_type,
+        token: &challenge.token,
+        key_authorization: &key_authorization,
+      },
+      key_type,
+      pg_pool,
+    )
+    .await?;
+    info!("Certificate requested for: {}", domain_name);
+    Ok(())
+  }
 
-    let http1_challenge_token_cache = Arc::clone(&CERTIFICATE_MANAGER.http1_challenge_token_cache);
-    {
-      let mut cache = http1_challenge_token_cache.lock().await;
-      cache.cache_set(
-        challenge.token.clone(),
-        order.key_authorization(challenge).as_str().to_string(),
-      );
-    }
+  /// Issues a certificate of the internal CA, for names no public CA will validate.
+  pub async fn issue_internal(
+    domain_name: &str,
+    owner_id: Uuid,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Certificate> {
+    let certificate = CertificateAuthority::get()?.issue(domain_name, owner_id)?;
+    certificate.store(pg_pool).await
+  }
 
-    let internal_pending_certificate_cache =
-      Arc::clone(&CERTIFICATE_MANAGER.internal_pending_certificate_cache);
-    {
-      let mut cache = internal_pending_certificate_cache.lock().await;
-      cache.cache_set(
-        domain_name.to_string(),
-        PendingCertificate {
-          domain_name: domain_name.to_string(),
-          owner_id,
-          token: challenge.token.clone(),
-          token_value: order.key_authorization(challenge).as_str().to_string(),
-          order: Arc::new(Mutex::new(order)),
-        },
-      );
-    }
-    Ok(())
+  /// Finalizes a validated order and stores the issued certificate. The CSR key is stored on
+  /// the order first, so a restart before the download doesn't lose it.
+  async fn finalize(
+    order: &mut CertificateOrder,
+    acme_order: &mut Order,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Certificate> {
+    // RSA 4096 keys take long enough to generate to stall the runtime.
+    let domain_name = order.domain_name.clone();
+    let key_type = order.certificate_key_type();
+    let (csr, private_key) =
+      tokio::task::spawn_blocking(move || certificate_request(&domain_name, key_type)).await??;
+    order
+      .set_private_key(
+        ClusterKey::get()?.encrypt(&private_key, &order.private_key_aad())?,
+        pg_pool,
+      )
+      .await?;
+    acme_order.finalize(&csr).await?;
+    Self::download(order, acme_order, private_key, pg_pool).await
   }
 
-  async fn new(
-    pending_certificate: PendingCertificate,
-    pg_pool: Arc<Pool<Postgres>>,
+  /// Downloads the certificate of a finalized order and stores it with the plaintext CSR key.
+  async fn download(
+    order: &CertificateOrder,
+    acme_order: &mut Order,
+    private_key: String,
+    pg_pool: &Pool<Postgres>,
   ) -> anyhow::Result<Certificate> {
-    let order = Arc::clone(&pending_certificate.order);
-    let certificate = {
-      let mut params = CertificateParams::new(vec![pending_certificate.domain_name.to_owned()]);
-      params.distinguished_name = DistinguishedName::new();
-      rcgen::Certificate::from_params(params)?
-    };
-    let signing_request = certificate.serialize_request_der()?;
-    let mut order = order.lock().await;
-    order.finalize(&signing_request).await?;
-
-    let cert_chain_pem = loop {
-      match order.certificate().await? {
-        Some(cert_chain_pem) => break cert_chain_pem,
-        None => sleep(Duration::from_secs(1)).await,
-      }
-    };
+    let key_type = order.certificate_key_type();
+    let cert_chain_pem = download_certificate(acme_order, &order.domain_name).await?;
 
     let mut certificates: Vec<String> = cert_chain_pem
       .split("-----END CERTIFICATE-----")
@@ -339,41 +428,187 @@ impl Certificate {
     certificates.pop();
 
     let mut expires_at = Utc::now();
-    if let Ok(cert) = openssl::x509::X509::from_pem(certificates[0].as_bytes()) {
-      let not_after_str = cert.not_after().to_string().replace("GMT", "+0000");
-      if let Ok(not_after) = DateTime::parse_from_str(&not_after_str, "%b %d %H:%M:%S %Y %z") {
-        expires_at = not_after.with_timezone(&Utc);
+    if let Ok(cert) = X509::from_pem(certificates[0].as_bytes()) {
+      if let Ok(not_after) = not_after(&cert) {
+        expires_at = not_after;
       }
     }
 
     let certificate = Certificate {
       id: Uuid::new_v4(),
-      domain_name: pending_certificate.domain_name.to_string(),
+      domain_name: order.domain_name.to_string(),
       certificate: certificates[0].to_string(),
-      private_key: certificate.serialize_private_key_pem(),
+      private_key,
+      fingerprint: String::new(),
+      key_type: key_type.as_str().to_string(),
       expires_at,
-      owner_id: pending_certificate.owner_id,
+      owner_id: order.owner_id,
+      source: SOURCE_ACME.to_string(),
       updated_at: Utc::now(),
       created_at: Utc::now(),
     };
+    certificate.store(pg_pool).await
+  }
+
+  /// Validates an uploaded certificate chain and private key without storing them.
+  ///
+  /// The domain defaults to the first DNS name of the leaf certificate, the key is stored
+  /// as PKCS#8 whatever format it was uploaded in.
+  pub fn from_pem(
+    owner_id: Uuid,
+    domain_name: Option<&str>,
+    cert_chain_pem: &str,
+    private_key_pem: &str,
+  ) -> anyhow::Result<Certificate> {
+    let cert_chain = X509::stack_from_pem(cert_chain_pem.as_bytes())?;
+    let leaf = cert_chain
+      .first()
+      .ok_or_else(|| anyhow::Error::msg("No certificates found in PEM"))?;
+    let private_key = PKey::private_key_from_pem(private_key_pem.as_bytes())?;
+    if !leaf.public_key()?.public_eq(&private_key) {
+      return Err(anyhow::Error::msg(
+        "Private key does not match the certificate",
+      ));
+    }
+
+    let names: Vec<String> = leaf
+      .subject_alt_names()
+      .map(|names| {
+        names
+          .iter()
+          .filter_map(|name| name.dnsname().map(str::to_lowercase))
+          .collect()
+      })
+      .unwrap_or_default();
+    let domain_name = match domain_name {
+      Some(domain_name) => domain_name.to_lowercase(),
+      None => names
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow::Error::msg("Certificate has no DNS names"))?,
+    };
+    let wildcard = cache::wildcard_name(&domain_name);
+    if !names
+      .iter()
+      .any(|name| *name == domain_name || Some(name) == wildcard.as_ref())
+    {
+      return Err(anyhow::Error::msg(format!(
+        "Certificate does not cover {}",
+        domain_name
+      )));
+    }
+
+    let expires_at = not_after(leaf)?;
+    if expires_at <= Utc::now() {
+      return Err(anyhow::Error::msg(format!(
+        "Certificate expired at {}",
+        expires_at
+      )));
+    }
+
+    let mut certificate = String::new();
+    for cert in &cert_chain {
+      certificate.push_str(std::str::from_utf8(&cert.to_pem()?)?);
+    }
+    let certificate = Certificate {
+      id: Uuid::new_v4(),
+      domain_name,
+      certificate,
+      private_key: String::from_utf8(private_key.private_key_to_pem_pkcs8()?)?,
+      fingerprint: String::new(),
+      key_type: describe_key_type(&private_key),
+      expires_at,
+      owner_id,
+      source: SOURCE_UPLOADED.to_string(),
+      updated_at: Utc::now(),
+      created_at: Utc::now(),
+    };
+    parse_certified_key(&certificate.certificate, &certificate.private_key)?;
+    Ok(certificate)
+  }
+
+  /// Stores a certificate with a plaintext private key, replacing the active one of the
+  /// domain on renewal. The key is encrypted with the cluster key before it reaches Postgres.
+  ///
+  /// The replaced certificate keeps serving until the new one is committed, then the cache
+  /// entry is swapped in place so handshakes never miss a certificate.
+  pub async fn store(self, pg_pool: &Pool<Postgres>) -> anyhow::Result<Certificate> {
+    let mut certificate = self;
+    // Refuse to replace a working certificate with one rustls can't load.
+    let certified_key = Arc::new(parse_certified_key(
+      &certificate.certificate,
+      &certificate.private_key,
+    )?);
+    certificate.fingerprint = fingerprint(&certificate.certificate)?;
+    certificate.private_key = ClusterKey::get()?.encrypt(
+      &certificate.private_key,
+      &private_key_aad(&certificate.domain_name),
+    )?;
+
+    let mut transaction = pg_pool.begin().await?;
+    let previous = sqlx::query_as!(
+      Certificate,
+      "SELECT * FROM certificate WHERE domain_name = $1 FOR UPDATE",
+      certificate.domain_name
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if let Some(previous) = &previous {
+      if !replaces(&previous.source, &certificate.source) {
+        return Err(anyhow::Error::msg(format!(
+          "{} has an uploaded certificate, not replacing it with an {} one",
+          certificate.domain_name, certificate.source
+        )));
+      }
+    }
     let certificate = sqlx::query_as!(
-        Certificate,
-        "INSERT INTO certificate (id, domain_name, certificate, private_key, expires_at, owner_id, updated_at, created_at)
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-         RETURNING *",
+      Certificate,
+      "
+      INSERT INTO certificate (id, domain_name, certificate, private_key, fingerprint, key_type, expires_at, owner_id, source, updated_at, created_at)
+      VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+      ON CONFLICT (domain_name) DO UPDATE
+      SET certificate = EXCLUDED.certificate, private_key = EXCLUDED.private_key, fingerprint = EXCLUDED.fingerprint,
+          key_type = EXCLUDED.key_type, expires_at = EXCLUDED.expires_at, source = EXCLUDED.source, updated_at = EXCLUDED.updated_at
+      RETURNING *
+      ",
+      certificate.id,
+      certificate.domain_name,
+      certificate.certificate,
+      certificate.private_key,
+      certificate.fingerprint,
+      certificate.key_type,
+      certificate.expires_at,
+      certificate.owner_id,
+      certificate.source,
+      certificate.updated_at,
+      certificate.created_at,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if let Some(previous) = &previous {
+      sqlx::query!(
+        "
+        INSERT INTO certificate_renewal (id, certificate_id, previous_certificate, previous_expires_at, expires_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ",
+        Uuid::new_v4(),
         certificate.id,
-        certificate.domain_name,
-        certificate.certificate,
-        certificate.private_key,
+        previous.certificate,
+        previous.expires_at,
         certificate.expires_at,
-        certificate.owner_id,
-        certificate.updated_at,
-        certificate.created_at,
-    )
-      .fetch_one(&*pg_pool)
+        Utc::now(),
+      )
+      .execute(&mut *transaction)
       .await?;
+    }
+    transaction.commit().await?;
+
+    CERTIFICATE_CACHE.insert(&certificate.domain_name, Some(certified_key));
     // TODO: Send email and notify.
-    info!("Created certificate: {:?}", certificate.domain_name);
+    match previous {
+      Some(_) => info!("Renewed certificate: {:?}", certificate.domain_name),
+      None => info!("Created certificate: {:?}", certificate.domain_name),
+    }
     Ok(certificate)
   }
 
@@ -383,8 +618,25 @@ impl Certificate {
       .await?
       .ok_or_else(|| anyhow::Error::msg("Certificate not found for renewal"))?;
 
-    // Request a new certificate using the same process as for new certificates
-    Self::request(existing_cert.owner_id, domain_name).await?;
+    if existing_cert.source == SOURCE_INTERNAL {
+      Self::issue_internal(domain_name, existing_cert.owner_id, pg_pool).await?;
+      return Ok(());
+    }
+
+    if CertificateOrder::is_in_progress(domain_name, pg_pool).await? {
+      info!(
+        "Certificate renewal already in progress for: {}",
+        domain_name
+      );
+      return Ok(());
+    }
+
+    // The key type the domain chose on its ingress, else the current cluster default.
+    let key_type = Ingress::get_by_host(domain_name, pg_pool)
+      .await?
+      .and_then(|ingress| ingress.certificate_key_type)
+      .and_then(|key_type| key_type.parse().ok());
+    Self::request(existing_cert.owner_id, domain_name, key_type, pg_pool).await?;
 
     info!("Certificate renewal requested for: {}", domain_name);
     Ok(())
@@ -395,11 +647,12 @@ impl Certificate {
     // Define the renewal threshold (e.g., 30 days before expiration)
     let renewal_threshold = Utc::now() + chrono::Duration::days(30);
 
-    // Find certificates nearing expiration
+    // Find certificates nearing expiration, uploaded ones are renewed by their owner
     let certificates = sqlx::query_as!(
       Certificate,
-      "SELECT * FROM certificate WHERE expires_at < $1",
-      renewal_threshold
+      "SELECT * FROM certificate WHERE expires_at < $1 AND source <> $2",
+      renewal_threshold,
+      SOURCE_UPLOADED
     )
     .fetch_all(pg_pool)
     .await?;
@@ -421,6 +674,14 @@ impl Certificate {
     Ok(())
   }
 
+  pub async fn get_by_id(id: Uuid, pg_pool: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+    Ok(
+      sqlx::query_as!(Certificate, "SELECT * FROM certificate WHERE id = $1", id)
+        .fetch_optional(pg_pool)
+        .await?,
+    )
+  }
+
   pub async fn get_by_domain_name(
     domain_name: String,
     pg_pool: &Pool<Postgres>,
@@ -435,6 +696,14 @@ impl Certificate {
     Ok(certificate)
   }
 
+  pub async fn get_all(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Certificate>> {
+    Ok(
+      sqlx::query_as!(Certificate, "SELECT * FROM certificate")
+        .fetch_all(pg_pool)
+        .await?,
+    )
+  }
+
   pub async fn get_by_owner_id(
     owner_id: Uuid,
     pg_pool: &Pool<Postgres>,
@@ -449,4 +718,247 @@ impl Certificate {
       .await?,
     )
   }
+
+  /// Decrypts the stored private key and parses it with the certificate chain for rustls.
+  pub fn certified_key(&self) -> anyhow::Result<CertifiedKey> {
+    let private_key =
+      ClusterKey::get()?.decrypt(&self.private_key, &private_key_aad(&self.domain_name))?;
+    parse_certified_key(&self.certificate, &private_key)
+  }
+
+  /// Encrypts private keys and ACME account credentials stored in plaintext before the cluster
+  /// key existed.
+  pub async fn encrypt_stored_keys(pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let cluster_key = ClusterKey::get()?;
+    let mut encrypted = 0;
+    for certificate in Self::get_all(pg_pool).await? {
+      if ClusterKey::is_encrypted(&certificate.private_key) {
+        continue;
+      }
+      sqlx::query!(
+        "UPDATE certificate SET private_key = $1, fingerprint = $2 WHERE id = $3",
+        cluster_key.encrypt(
+          &certificate.private_key,
+          &private_key_aad(&certificate.domain_name)
+        )?,
+        fingerprint(&certificate.certificate)?,
+        certificate.id
+      )
+      .execute(pg_pool)
+      .await?;
+      encrypted += 1;
+    }
+    let authorities = sqlx::query!("SELECT id, private_key FROM certificate_authority")
+      .fetch_all(pg_pool)
+      .await?;
+    for authority in authorities {
+      if ClusterKey::is_encrypted(&authority.private_key) {
+        continue;
+      }
+      sqlx::query!(
+        "UPDATE certificate_authority SET private_key = $1 WHERE id = $2",
+        cluster_key.encrypt(
+          &authority.private_key,
+          &authority::private_key_aad(authority.id)
+        )?,
+        authority.id
+      )
+      .execute(pg_pool)
+      .await?;
+      encrypted += 1;
+    }
+    let accounts = sqlx::query!("SELECT id, directory_url, credentials FROM acme_account")
+      .fetch_all(pg_pool)
+      .await?;
+    for account in accounts {
+      if ClusterKey::is_encrypted(&account.credentials) {
+        continue;
+      }
+      sqlx::query!(
+        "UPDATE acme_account SET credentials = $1 WHERE id = $2",
+        cluster_key.encrypt(
+          &account.credentials,
+          &acme::credentials_aad(&account.directory_url)
+        )?,
+        account.id
+      )
+      .execute(pg_pool)
+      .await?;
+      encrypted += 1;
+    }
+    if encrypted > 0 {
+      info!("Encrypted {} stored private keys", encrypted);
+    }
+    Ok(())
+  }
+}
+
+/// Parses a PEM certificate chain and plaintext PKCS#8, SEC1 or PKCS#1 private key for rustls.
+fn parse_certified_key(certificate: &str, private_key: &str) -> anyhow::Result<CertifiedKey> {
+  let mut cert_reader = BufReader::new(certificate.as_bytes());
+  let cert_chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut cert_reader)
+    .filter_map(|result| result.ok())
+    .map(CertificateDer::into_owned)
+    .collect();
+  if cert_chain.is_empty() {
+    return Err(anyhow::Error::msg("No certificates found in PEM"));
+  }
+
+  let key_data = rustls_pemfile::private_key(&mut BufReader::new(private_key.as_bytes()))?
+    .ok_or_else(|| anyhow::Error::msg("No private key found"))?;
+  let signing_key = rustls::crypto::ring::sign::any_supported_type(&key_data)
+    .map_err(|_| anyhow::Error::msg("Unsupported key type"))?;
+  Ok(CertifiedKey::new(cert_chain, signing_key))
+}
+
+/// Names uploaded keys like [`CertificateKeyType`], e.g. `rsa-3072` for sizes doseid doesn't issue.
+fn describe_key_type(private_key: &PKey<Private>) -> String {
+  match (private_key.rsa(), private_key.ec_key()) {
+    (Ok(rsa), _) => format!("rsa-{}", rsa.size() * 8),
+    (_, Ok(ec_key)) => match ec_key.group().curve_name() {
+      Some(Nid::X9_62_PRIME256V1) => CertificateKeyType::EcdsaP256.as_str().to_string(),
+      Some(Nid::SECP384R1) => CertificateKeyType::EcdsaP384.as_str().to_string(),
+      Some(Nid::SECP521R1) => "ecdsa-p521".to_string(),
+      _ => "ecdsa".to_string(),
+    },
+    _ if private_key.id() == Id::ED25519 => "ed25519".to_string(),
+    _ => "unknown".to_string(),
+  }
+}
+
+/// Binds an encrypted certificate key to the row of its domain.
+fn private_key_aad(domain_name: &str) -> String {
+  format!("certificate:{}", domain_name)
+}
+
+/// Uploaded certificates are managed by their owner, only another upload replaces them. An
+/// ACME order still in flight when the upload lands must not overwrite it.
+fn replaces(previous_source: &str, source: &str) -> bool {
+  previous_source != SOURCE_UPLOADED || source == SOURCE_UPLOADED
+}
+
+/// Whether the CA still waits for a challenge of the authorization to be marked ready.
+fn awaits_challenge(authorization: &Authorization) -> bool {
+  authorization.status == AuthorizationStatus::Pending
+}
+
+/// SHA-256 of the leaf certificate, colon separated like `openssl x509 -fingerprint`.
+fn fingerprint(certificate: &str) -> anyhow::Result<String> {
+  let leaf = X509::from_pem(certificate.as_bytes())?;
+  let digest = leaf.digest(MessageDigest::sha256())?;
+  Ok(
+    digest
+      .iter()
+      .map(|byte| format!("{:02X}", byte))
+      .collect::<Vec<_>>()
+      .join(":"),
+  )
+}
+
+pub(crate) fn not_after(cert: &X509Ref) -> anyhow::Result<DateTime<Utc>> {
+  let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+  DateTime::from_timestamp(i64::from(diff.days) * 86400 + i64::from(diff.secs), 0)
+    .ok_or_else(|| anyhow::Error::msg("Certificate expiry out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::ec::{EcGroup, EcKey};
+  use openssl::rsa::Rsa;
+  use utoipa::gen::serde_json;
+
+  fn self_signed(names: &[&str]) -> (String, String) {
+    let names = names
+      .iter()
+      .map(|name| name.to_string())
+      .collect::<Vec<_>>();
+    let certificate = rcgen::generate_simple_self_signed(names).unwrap();
+    (
+      certificate.serialize_pem().unwrap(),
+      certificate.serialize_private_key_pem(),
+    )
+  }
+
+  #[test]
+  fn from_pem_validates_uploaded_certificate() {
+    let owner_id = Uuid::new_v4();
+    let (cert_pem, key_pem) = self_signed(&["*.example.com", "example.com"]);
+
+    let certificate = Certificate::from_pem(owner_id, None, &cert_pem, &key_pem).unwrap();
+    assert_eq!(certificate.domain_name, "*.example.com");
+    assert_eq!(certificate.source, SOURCE_UPLOADED);
+    assert!(certificate.expires_at > Utc::now());
+    assert!(Certificate::from_pem(owner_id, Some("api.example.com"), &cert_pem, &key_pem).is_ok());
+    assert!(Certificate::from_pem(owner_id, Some("example.org"), &cert_pem, &key_pem).is_err());
+
+    let (_, other_key_pem) = self_signed(&["example.com"]);
+    assert!(Certificate::from_pem(owner_id, None, &cert_pem, &other_key_pem).is_err());
+  }
+
+  #[test]
+  fn parse_certified_key_accepts_sec1_and_pkcs1_keys() {
+    let ec_key = EcKey::generate(&EcGroup::from_curve_name(Nid::SECP384R1).unwrap()).unwrap();
+    let rsa = Rsa::generate(2048).unwrap();
+    let keys = [
+      (
+        PKey::from_ec_key(ec_key.clone()).unwrap(),
+        ec_key.private_key_to_pem().unwrap(),
+        &rcgen::PKCS_ECDSA_P384_SHA384,
+        "ecdsa-p384",
+      ),
+      (
+        PKey::from_rsa(rsa.clone()).unwrap(),
+        rsa.private_key_to_pem().unwrap(),
+        &rcgen::PKCS_RSA_SHA256,
+        "rsa-2048",
+      ),
+    ];
+    for (private_key, traditional_pem, algorithm, key_type) in keys {
+      let pkcs8_pem = String::from_utf8(private_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+      let mut params = rcgen::CertificateParams::new(vec!["example.com".to_string()]);
+      params.alg = algorithm;
+      params.key_pair =
+        Some(rcgen::KeyPair::from_pem_and_sign_algo(&pkcs8_pem, algorithm).unwrap());
+      let cert_pem = rcgen::Certificate::from_params(params)
+        .unwrap()
+        .serialize_pem()
+        .unwrap();
+
+      let traditional_pem = String::from_utf8(traditional_pem).unwrap();
+      assert!(!traditional_pem.contains("BEGIN PRIVATE KEY"));
+      assert!(parse_certified_key(&cert_pem, &traditional_pem).is_ok());
+      assert_eq!(describe_key_type(&private_key), key_type);
+    }
+  }
+
+  #[test]
+  fn uploaded_certificates_are_only_replaced_by_uploads() {
+    assert!(!replaces(SOURCE_UPLOADED, SOURCE_ACME));
+    assert!(!replaces(SOURCE_UPLOADED, SOURCE_INTERNAL));
+    assert!(replaces(SOURCE_UPLOADED, SOURCE_UPLOADED));
+    assert!(replaces(SOURCE_ACME, SOURCE_ACME));
+    assert!(replaces(SOURCE_ACME, SOURCE_UPLOADED));
+  }
+
+  #[test]
+  fn only_pending_authorizations_await_the_challenge() {
+    let authorization = |status: &str, challenge_status: &str| -> Authorization {
+      serde_json::from_value(serde_json::json!({
+        "identifier": { "type": "dns", "value": "example.com" },
+        "status": status,
+        "challenges": [{
+          "type": "http-01",
+          "url": "https://acme.example.com/chall/1",
+          "token": "token",
+          "status": challenge_status,
+        }],
+      }))
+      .unwrap()
+    };
+    assert!(awaits_challenge(&authorization("pending", "pending")));
+    // Resumed orders the CA already validated must not be marked ready again.
+    assert!(!awaits_challenge(&authorization("valid", "valid")));
+    assert!(!awaits_challenge(&authorization("invalid", "invalid")));
+  }
 }
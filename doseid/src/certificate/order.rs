@@ -0,0 +1,261 @@
+use chrono::{DateTime, Utc};
+use dosei_schema::app::CertificateKeyType;
+use instant_acme::ChallengeType;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Waiting for the http-01 challenge to be served through the proxy.
+pub const ORDER_PENDING: &str = "pending";
+/// The challenge was handed to the CA, waiting for it to validate the order.
+pub const ORDER_VALIDATING: &str = "validating";
+pub const ORDER_ISSUED: &str = "issued";
+pub const ORDER_FAILED: &str = "failed";
+
+pub const CHALLENGE_HTTP01: &str = "http-01";
+/// Required for wildcard names, needs a DNS provider for the zone.
+pub const CHALLENGE_DNS01: &str = "dns-01";
+
+/// The challenge a new order is validated with.
+pub struct OrderChallenge<'a> {
+  pub challenge_type: &'a str,
+  pub token: &'a str,
+  pub key_authorization: &'a str,
+}
+
+/// The latest ACME order of a domain, persisted so issuance resumes after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct CertificateOrder {
+  pub id: Uuid,
+  pub domain_name: String,
+  pub owner_id: Uuid,
+  #[serde(skip_serializing)]
+  pub order_url: String,
+  #[serde(skip_serializing)]
+  pub token: String,
+  /// The http-01 response body, or the TXT record value for dns-01.
+  #[serde(skip_serializing)]
+  pub key_authorization: String,
+  /// One of `pending`, `validating`, `issued` or `failed`.
+  pub status: String,
+  /// Checks made in the current status.
+  pub attempts: i32,
+  pub last_error: Option<String>,
+  pub updated_at: DateTime<Utc>,
+  pub created_at: DateTime<Utc>,
+  /// `http-01` or `dns-01`.
+  pub challenge_type: String,
+  /// Key algorithm the certificate is finalized with, e.g. `ecdsa-p256` or `rsa-2048`.
+  pub key_type: String,
+  /// Key of the CSR encrypted with the cluster key, kept from finalizing the order until the
+  /// certificate is stored so a restart in between can still download it.
+  #[serde(skip_serializing)]
+  pub private_key: Option<String>,
+}
+
+impl CertificateOrder {
+  /// Starts tracking a new order, replacing the previous order of the domain.
+  pub async fn new(
+    domain_name: &str,
+    owner_id: Uuid,
+    order_url: &str,
+    challenge: OrderChallenge<'_>,
+    key_type: CertificateKeyType,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Self> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "
+        INSERT INTO certificate_order (id, domain_name, owner_id, order_url, challenge_type, token, key_authorization, key_type, status, attempts, last_error, updated_at, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, 0, NULL, $10, $11)
+        ON CONFLICT (domain_name) DO UPDATE
+        SET id = EXCLUDED.id, owner_id = EXCLUDED.owner_id, order_url = EXCLUDED.order_url,
+            challenge_type = EXCLUDED.challenge_type, token = EXCLUDED.token, key_authorization = EXCLUDED.key_authorization,
+            key_type = EXCLUDED.key_type, status = EXCLUDED.status, attempts = 0, last_error = NULL,
+            updated_at = EXCLUDED.updated_at, created_at = EXCLUDED.created_at
+        RETURNING *
+        ",
+        Uuid::new_v4(),
+        domain_name,
+        owner_id,
+        order_url,
+        challenge.challenge_type,
+        challenge.token,
+        challenge.key_authorization,
+        key_type.as_str(),
+        ORDER_PENDING,
+        Utc::now(),
+        Utc::now(),
+      )
+      .fetch_one(pg_pool)
+      .await?,
+    )
+  }
+
+  pub async fn get_by_status(status: &str, pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT * FROM certificate_order WHERE status = $1",
+        status
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+
+  pub async fn get_by_owner_id(
+    owner_id: Uuid,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT * FROM certificate_order WHERE owner_id = $1 ORDER BY updated_at DESC",
+        owner_id
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+
+  /// Whether the domain has an order that is still in progress.
+  pub async fn is_in_progress(domain_name: &str, pg_pool: &Pool<Postgres>) -> anyhow::Result<bool> {
+    let order = sqlx::query!(
+      "SELECT id FROM certificate_order WHERE domain_name = $1 AND status IN ($2, $3)",
+      domain_name,
+      ORDER_PENDING,
+      ORDER_VALIDATING
+    )
+    .fetch_optional(pg_pool)
+    .await?;
+    Ok(order.is_some())
+  }
+
+  /// Fails the orders of a domain that are still in progress, e.g. once a certificate for it
+  /// was uploaded.
+  pub async fn cancel(
+    domain_name: &str,
+    reason: &str,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    sqlx::query!(
+      "
+      UPDATE certificate_order SET status = $1, last_error = $2, private_key = NULL, updated_at = $3
+      WHERE domain_name = $4 AND status IN ($5, $6)
+      ",
+      ORDER_FAILED,
+      reason,
+      Utc::now(),
+      domain_name,
+      ORDER_PENDING,
+      ORDER_VALIDATING
+    )
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+  }
+
+  /// The key authorization the proxy answers the http-01 challenge with.
+  pub async fn get_key_authorization(
+    token: &str,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Option<String>> {
+    let order = sqlx::query!(
+      "
+      SELECT key_authorization FROM certificate_order
+      WHERE token = $1 AND challenge_type = $2 AND status IN ($3, $4)
+      ",
+      token,
+      CHALLENGE_HTTP01,
+      ORDER_PENDING,
+      ORDER_VALIDATING
+    )
+    .fetch_optional(pg_pool)
+    .await?;
+    Ok(order.map(|order| order.key_authorization))
+  }
+
+  pub fn acme_challenge_type(&self) -> ChallengeType {
+    match self.challenge_type.as_str() {
+      CHALLENGE_DNS01 => ChallengeType::Dns01,
+      _ => ChallengeType::Http01,
+    }
+  }
+
+  /// Binds the encrypted CSR key to this order.
+  pub fn private_key_aad(&self) -> String {
+    format!("certificate_order:{}", self.id)
+  }
+
+  pub fn certificate_key_type(&self) -> CertificateKeyType {
+    self.key_type.parse().unwrap_or_default()
+  }
+
+  /// Moves the order to a new status, resetting the attempts.
+  pub async fn update_status(
+    &mut self,
+    status: &str,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    self.status = status.to_string();
+    self.attempts = 0;
+    self.update(pg_pool).await
+  }
+
+  /// Stores the encrypted key of the CSR before the order is finalized.
+  pub async fn set_private_key(
+    &mut self,
+    private_key: String,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    self.private_key = Some(private_key);
+    self.update(pg_pool).await
+  }
+
+  /// Records a failed check, failing the order once `max_attempts` is reached.
+  pub async fn record_failure(
+    &mut self,
+    error: &str,
+    max_attempts: i32,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    self.attempts += 1;
+    self.last_error = Some(error.to_string());
+    if self.attempts >= max_attempts {
+      self.status = ORDER_FAILED.to_string();
+    }
+    self.update(pg_pool).await
+  }
+
+  pub async fn fail(&mut self, error: &str, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    self.status = ORDER_FAILED.to_string();
+    self.last_error = Some(error.to_string());
+    self.update(pg_pool).await
+  }
+
+  async fn update(&mut self, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    self.updated_at = Utc::now();
+    // The CSR key isn't needed once the order is over.
+    if self.status == ORDER_ISSUED || self.status == ORDER_FAILED {
+      self.private_key = None;
+    }
+    sqlx::query!(
+      "
+      UPDATE certificate_order SET status = $1, attempts = $2, last_error = $3, private_key = $4, updated_at = $5
+      WHERE id = $6
+      ",
+      self.status,
+      self.attempts,
+      self.last_error,
+      self.private_key,
+      self.updated_at,
+      self.id
+    )
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+  }
+}
@@ -0,0 +1,38 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// A renewal that replaced the active certificate, the replaced certificate is kept for
+/// auditing without its private key.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct CertificateRenewal {
+  pub id: Uuid,
+  pub certificate_id: Uuid,
+  pub previous_expires_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  pub created_at: DateTime<Utc>,
+}
+
+impl CertificateRenewal {
+  pub async fn get_by_certificate_id(
+    certificate_id: Uuid,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "
+        SELECT id, certificate_id, previous_expires_at, expires_at, created_at
+        FROM certificate_renewal
+        WHERE certificate_id = $1
+        ORDER BY created_at DESC
+        ",
+        certificate_id
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+}
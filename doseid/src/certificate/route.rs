@@ -1,15 +1,33 @@
+use crate::certificate::authority::CertificateAuthority;
+use crate::certificate::cache::wildcard_name;
+use crate::certificate::order::CertificateOrder;
+use crate::certificate::renewal::CertificateRenewal;
 use crate::certificate::Certificate;
-use crate::certificate::CertificateManager;
+use crate::cluster::Cluster;
+use crate::ingress::Ingress;
 use crate::session::AuthSession;
 use axum::extract::Path;
-use axum::http::StatusCode;
+use axum::http::{header, HeaderName, StatusCode};
 use axum::{Extension, Json};
 use log::info;
+use serde::Deserialize;
 use sqlx::{Pool, Postgres};
 use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
 
 const TAG: &str = "certificate";
 
+#[derive(Deserialize, ToSchema)]
+pub struct CertificateUpload {
+  /// Defaults to the first DNS name of the certificate.
+  pub domain_name: Option<String>,
+  /// PEM chain, leaf certificate first.
+  pub certificate: String,
+  /// PEM private key, PKCS#8, PKCS#1 or SEC1.
+  pub private_key: String,
+}
+
 #[utoipa::path(
   get,
   path = "/certificate",
@@ -31,6 +49,170 @@ pub async fn api_list_certificates(
   Ok((StatusCode::OK, Json(certificates)))
 }
 
+#[utoipa::path(
+  post,
+  path = "/certificate",
+  request_body = CertificateUpload,
+  responses(
+        (status = StatusCode::CREATED, body = Certificate),
+        (status = StatusCode::BAD_REQUEST, description = "Invalid certificate, key mismatch or expired"),
+        (status = StatusCode::FORBIDDEN, description = "Domain is not routed to a service of the account"),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_upload_certificate(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+  Json(body): Json<CertificateUpload>,
+) -> Result<(StatusCode, Json<Certificate>), StatusCode> {
+  let certificate = Certificate::from_pem(
+    session.account_id,
+    body.domain_name.as_deref(),
+    &body.certificate,
+    &body.private_key,
+  )
+  .map_err(|e| {
+    info!("Rejected certificate upload: {}", e);
+    StatusCode::BAD_REQUEST
+  })?;
+
+  let ingresses = match certificate.domain_name.strip_prefix("*.") {
+    Some(parent) => Ingress::get_by_parent_domain(parent, &pg_pool).await,
+    None => Ingress::get_by_host(&certificate.domain_name, &pg_pool)
+      .await
+      .map(|ingress| ingress.into_iter().collect()),
+  }
+  .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  let hosts: Vec<(&str, Uuid)> = ingresses
+    .iter()
+    .map(|ingress| (ingress.host.as_str(), ingress.owner_id))
+    .collect();
+  let cluster = Cluster::get().await;
+  let owns_domain = owns_domain(
+    &certificate.domain_name,
+    session.account_id,
+    &hosts,
+    cluster.wildcard_domain.as_deref(),
+  );
+  let existing = Certificate::get_by_domain_name(certificate.domain_name.clone(), &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  if !owns_domain || existing.is_some_and(|existing| existing.owner_id != session.account_id) {
+    return Err(StatusCode::FORBIDDEN);
+  }
+
+  let certificate = certificate
+    .store(&pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  CertificateOrder::cancel(
+    &certificate.domain_name,
+    "Replaced by an uploaded certificate",
+    &pg_pool,
+  )
+  .await
+  .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::CREATED, Json(certificate)))
+}
+
+/// Whether the account may upload a certificate for the domain, given the ingresses it covers as
+/// `(host, owner_id)`. A host must be routed to the account. A wildcard must cover at least one
+/// of its hosts and none of another account, and the cluster wildcard domain is shared by every
+/// account, so nobody can upload `*.<wildcard_domain>`.
+fn owns_domain(
+  domain_name: &str,
+  account_id: Uuid,
+  ingresses: &[(&str, Uuid)],
+  cluster_wildcard_domain: Option<&str>,
+) -> bool {
+  let covered: Vec<Uuid> = ingresses
+    .iter()
+    .filter(|(host, _)| match domain_name.strip_prefix("*.") {
+      Some(parent) => {
+        Some(parent) != cluster_wildcard_domain
+          && wildcard_name(host).as_deref() == Some(domain_name)
+      }
+      None => *host == domain_name,
+    })
+    .map(|(_, owner_id)| *owner_id)
+    .collect();
+  !covered.is_empty() && covered.iter().all(|owner_id| *owner_id == account_id)
+}
+
+#[utoipa::path(
+  get,
+  path = "/certificate/order",
+  responses(
+        (status = StatusCode::OK, body = Vec<CertificateOrder>, description = "Issuance status of the latest order of each domain"),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_list_certificate_orders(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+) -> Result<(StatusCode, Json<Vec<CertificateOrder>>), StatusCode> {
+  let orders = CertificateOrder::get_by_owner_id(session.account_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::OK, Json(orders)))
+}
+
+#[utoipa::path(
+  get,
+  path = "/certificate/{certificate_id}/renewals",
+  params(
+    ("certificate_id" = String, Path, description = "Certificate ID"),
+  ),
+  responses(
+        (status = StatusCode::OK, body = Vec<CertificateRenewal>),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_list_certificate_renewals(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+  Path(certificate_id): Path<Uuid>,
+) -> Result<(StatusCode, Json<Vec<CertificateRenewal>>), StatusCode> {
+  let certificate = Certificate::get_by_id(certificate_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  if certificate.owner_id != session.account_id {
+    return Err(StatusCode::NOT_FOUND);
+  }
+  let renewals = CertificateRenewal::get_by_certificate_id(certificate_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::OK, Json(renewals)))
+}
+
+#[utoipa::path(
+  get,
+  path = "/certificate/authority",
+  responses(
+        (status = StatusCode::OK, body = String, content_type = "application/x-pem-file", description = "Root certificate of the internal CA, trust it to reach local and IP based clusters over HTTPS"),
+  ),
+  tag = TAG
+)]
+pub async fn api_certificate_authority(
+) -> Result<(StatusCode, [(HeaderName, &'static str); 1], String), StatusCode> {
+  let authority = CertificateAuthority::get().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((
+    StatusCode::OK,
+    [(header::CONTENT_TYPE, "application/x-pem-file")],
+    authority.certificate.clone(),
+  ))
+}
+
 #[utoipa::path(
   get,
   path = "/.well-known/acme-challenge/:token",
@@ -41,11 +223,45 @@ pub async fn api_list_certificates(
   tag = TAG
 )]
 pub async fn api_http01_challenge(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
   Path(token): Path<String>,
 ) -> Result<(StatusCode, String), StatusCode> {
   info!("ACME challenge request for token: {}", token);
-  if let Some(token_value) = CertificateManager::get_http01_challenge_token_value(token).await {
-    return Ok((StatusCode::OK, token_value));
+  let key_authorization = CertificateOrder::get_key_authorization(&token, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  Ok((StatusCode::OK, key_authorization))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn wildcard_upload_requires_every_sibling_host() {
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    let own = [("a.example.com", a)];
+    assert!(owns_domain("a.example.com", a, &own, None));
+    assert!(owns_domain("*.example.com", a, &own, None));
+    assert!(!owns_domain("a.example.com", b, &own, None));
+
+    let shared = [("a.example.com", a), ("b.example.com", b)];
+    assert!(!owns_domain("*.example.com", a, &shared, None));
+    assert!(!owns_domain("*.example.com", b, &shared, None));
+
+    let cluster = [("app.apps.example.com", a)];
+    assert!(owns_domain(
+      "app.apps.example.com",
+      a,
+      &cluster,
+      Some("apps.example.com")
+    ));
+    assert!(!owns_domain(
+      "*.apps.example.com",
+      a,
+      &cluster,
+      Some("apps.example.com")
+    ));
   }
-  Err(StatusCode::NOT_FOUND)
 }
@@ -7,6 +7,9 @@ use dosei_schema::cluster::ClusterInit;
 use sqlx::{Pool, Postgres};
 use tracing::error;
 
+/// Port of the dashboard container, also published on 127.0.0.1.
+const DASHBOARD_PORT: i16 = 8844;
+
 pub struct Dashboard {
   // The domain name where the dashboard will be running.
   // dashboard.dosei.cloud
@@ -20,7 +23,7 @@ impl Dashboard {
     // Request a certificate for the domain name.
     if let Ok(result) = Certificate::get_by_domain_name(self.name.clone(), pg_pool).await {
       if result.is_none() && ClusterInit::validate_domain(&self.name) {
-        if let Err(e) = Certificate::request(default_user.id, &self.name).await {
+        if let Err(e) = Certificate::request(default_user.id, &self.name, None, pg_pool).await {
           error!("{}", e);
         }
       }
@@ -29,13 +32,14 @@ impl Dashboard {
     // Create dashboard Service if not present (aka: fresh cluster)
     let service = match Service::new("dashboard", default_user.id, pg_pool).await {
       Ok(service) => service,
-      Err(_) => Service::get_by_name("dashboard".to_string(), pg_pool)
+      Err(_) => Service::get_by_name("dashboard", default_user.id, pg_pool)
         .await?
         .unwrap(),
     };
 
-    // Get or create a deployment for the service.
-    let deployment = match Deployment::get_by_service_id(service.id, pg_pool)
+    // Get or create a deployment for the service, published on loopback for
+    // `dosei cluster dashboard`.
+    let mut deployment = match Deployment::get_by_service_id(service.id, pg_pool)
       .await?
       .into_iter()
       .next()
@@ -45,18 +49,23 @@ impl Dashboard {
         Deployment::new(
           service.id,
           service.owner_id,
-          Some(8844),
-          Some(8844),
+          Some(DASHBOARD_PORT),
+          Some(DASHBOARD_PORT),
           pg_pool,
         )
         .await?
       }
     };
+    if deployment.host_port != Some(DASHBOARD_PORT) {
+      deployment
+        .update_host_port(Some(DASHBOARD_PORT), pg_pool)
+        .await?;
+    }
 
     let image_tag = format!("doseidotio/dashboard:{}", env!("CARGO_PKG_VERSION"));
     deployment.stop().await?;
     deployment.remove().await?;
-    deployment.start(Some(image_tag)).await?;
+    deployment.start(Some(image_tag), pg_pool).await?;
 
     // Ingress insert or Update
     match Ingress::get_by_service_id(service.id, pg_pool)
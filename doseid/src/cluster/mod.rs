@@ -33,18 +33,22 @@ impl Deref for DaemonClusterInit {
 #[derive(Clone)]
 pub struct Cluster {
   pub name: String,
+  /// Services are published as `<service>.<wildcard_domain>` when set.
+  pub wildcard_domain: Option<String>,
 }
 
 pub static CLUSTER: Lazy<Arc<Mutex<Cluster>>> = Lazy::new(|| {
   Arc::new(Mutex::new(Cluster {
     name: "localhost".to_string(),
+    wildcard_domain: None,
   }))
 });
 
 impl Cluster {
-  pub async fn init(name: String) {
+  pub async fn init(name: String, wildcard_domain: Option<String>) {
     let mut cluster = CLUSTER.lock().await;
     cluster.name = name;
+    cluster.wildcard_domain = wildcard_domain;
   }
   pub async fn get() -> Cluster {
     CLUSTER.lock().await.clone()
@@ -58,7 +62,7 @@ impl DaemonClusterInit {
     Ok(serde_json::from_str::<Self>(&cluster_data)?)
   }
   pub async fn init(&self, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
-    Cluster::init(self.name.clone()).await;
+    Cluster::init(self.name.clone(), self.wildcard_domain.clone()).await;
     let _ = Account::new("dosei", None, pg_pool).await;
     let default_user = Account::get_default_user(pg_pool).await?;
     let _ = AccountSSHKey::new(default_user.id, self.dosei_public_key.clone(), pg_pool).await;
@@ -100,14 +104,24 @@ impl DaemonClusterInit {
 
     if let Ok(result) = Certificate::get_by_domain_name(self.name.clone(), pg_pool).await {
       if result.is_none() && ClusterInit::validate_domain(&self.name) {
-        if let Err(e) = Certificate::request(default_user.id, &self.name).await {
+        if let Err(e) = Certificate::request(default_user.id, &self.name, None, pg_pool).await {
+          error!("{}", e);
+        }
+      }
+    }
+    if let Some(wildcard_domain) = &self.wildcard_domain {
+      let domain_name = format!("*.{}", wildcard_domain);
+      if !ClusterInit::validate_domain(wildcard_domain) {
+        error!("Invalid cluster wildcard domain: {}", wildcard_domain);
+      } else if let Ok(None) = Certificate::get_by_domain_name(domain_name.clone(), pg_pool).await {
+        if let Err(e) = Certificate::request(default_user.id, &domain_name, None, pg_pool).await {
           error!("{}", e);
         }
       }
     }
     let service = match Service::new("dosei", default_user.id, pg_pool).await {
       Ok(service) => service,
-      Err(_) => Service::get_by_name("dosei".to_string(), pg_pool)
+      Err(_) => Service::get_by_name("dosei", default_user.id, pg_pool)
         .await?
         .unwrap(),
     };
@@ -1 +1,13 @@
+use crate::config::{AccessLogFormat, AcmeDirectory};
+use dosei_schema::app::CertificateKeyType;
+
 pub(crate) const DATABASE_URL: &str = "postgres://postgres@host/postgres?host=/var/run/postgresql";
+pub(crate) const ACCESS_LOG_FORMAT: AccessLogFormat = AccessLogFormat::Json;
+pub(crate) const ACCESS_LOG_RETENTION_DAYS: i64 = 7;
+pub(crate) const ERROR_PAGES_DIR: &str = "/var/lib/doseid/error-pages";
+pub(crate) const CERTIFICATE_KEY_TYPE: CertificateKeyType = CertificateKeyType::EcdsaP256;
+pub(crate) const CERTIFICATE_KEY_PATH: &str = "/var/lib/doseid/certificate.key";
+pub(crate) const NETWORK_SUBNET_POOL: &str = "10.213.0.0/16";
+pub(crate) const NETWORK_SUBNET_PREFIX: u8 = 24;
+pub(crate) const ACME_DIRECTORY: AcmeDirectory = AcmeDirectory::LetsEncryptProduction;
+pub(crate) const RFC2136_TSIG_ALGORITHM: &str = "hmac-sha256";
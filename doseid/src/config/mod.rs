@@ -1,13 +1,167 @@
 mod default;
 
+use dosei_schema::app::CertificateKeyType;
 use dotenv::dotenv;
+use ipnet::IpNet;
 use serde::Deserialize;
 use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
   pub host: String,
   pub database_url: String,
+  pub access_log_format: AccessLogFormat,
+  pub access_log_retention_days: i64,
+  /// Bearer token required by `/metrics`, metrics aren't served without it.
+  pub metrics_token: Option<String>,
+  /// Cluster wide proxy error pages, named `404.html`, `502.html`, `503.html`, `504.html`
+  /// and `maintenance.html`.
+  pub error_pages_dir: PathBuf,
+  pub acme_directory: AcmeDirectory,
+  /// PEM root certificates trusted on top of the system ones when talking to the ACME
+  /// directory, for internal CAs like step-ca or Pebble.
+  pub acme_ca_bundle: Option<PathBuf>,
+  /// Contact of the cluster ACME account, where the CA sends expiry notices.
+  pub acme_email: Option<String>,
+  /// Set with `DNS_PROVIDER`, enables DNS-01 challenges and wildcard certificates.
+  pub dns_provider: Option<DnsProviderConfig>,
+  /// Key algorithm of ACME certificates, domains can override it in their app ingress.
+  pub certificate_key_type: CertificateKeyType,
+  /// Cluster key encrypting certificate private keys in Postgres, created on first start.
+  /// Keep it out of database backups.
+  pub certificate_key_path: PathBuf,
+  /// Proxies in front of the cluster, `TRUSTED_PROXIES` as comma separated CIDRs. A trusted
+  /// `X-Forwarded-For` is only read on connections from them, and they're skipped in it.
+  pub trusted_proxies: Vec<IpNet>,
+  /// Range the owner networks are carved from, `NETWORK_SUBNET_POOL`. Docker's default pools
+  /// only fit about 30 bridge networks.
+  pub network_subnet_pool: IpNet,
+  /// Prefix length of each owner network, `NETWORK_SUBNET_PREFIX`.
+  pub network_subnet_prefix: u8,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+  Json,
+  /// Common Log Format
+  Common,
+  /// Only store access logs, without printing them.
+  Off,
+}
+
+impl AccessLogFormat {
+  fn from_env(value: &str) -> anyhow::Result<Self> {
+    match value.to_lowercase().as_str() {
+      "json" => Ok(Self::Json),
+      "common" | "clf" => Ok(Self::Common),
+      "off" => Ok(Self::Off),
+      _ => Err(anyhow::Error::msg(format!(
+        "Invalid ACCESS_LOG_FORMAT `{}`, expected json, common or off",
+        value
+      ))),
+    }
+  }
+}
+
+/// Where certificates are ordered from, set with `ACME_DIRECTORY` to `production`, `staging`
+/// or the directory URL of any other ACME CA.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub enum AcmeDirectory {
+  LetsEncryptProduction,
+  /// Untrusted certificates with much higher rate limits, for testing.
+  LetsEncryptStaging,
+  Custom(String),
+}
+
+impl AcmeDirectory {
+  fn from_env(value: &str) -> anyhow::Result<Self> {
+    match value.to_lowercase().as_str() {
+      "production" | "letsencrypt" => Ok(Self::LetsEncryptProduction),
+      "staging" | "letsencrypt-staging" => Ok(Self::LetsEncryptStaging),
+      _ if value.starts_with("https://") => Ok(Self::Custom(value.to_string())),
+      // The ACME client only talks HTTPS, account keys and orders must not travel in clear.
+      _ if value.starts_with("http://") => Err(anyhow::Error::msg(format!(
+        "Invalid ACME_DIRECTORY `{}`, the directory must be served over https://",
+        value
+      ))),
+      _ => Err(anyhow::Error::msg(format!(
+        "Invalid ACME_DIRECTORY `{}`, expected production, staging or a directory URL",
+        value
+      ))),
+    }
+  }
+
+  pub fn url(&self) -> &str {
+    match self {
+      Self::LetsEncryptProduction => instant_acme::LetsEncrypt::Production.url(),
+      Self::LetsEncryptStaging => instant_acme::LetsEncrypt::Staging.url(),
+      Self::Custom(url) => url,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub enum DnsProviderConfig {
+  /// RFC 2136 dynamic updates, signed with TSIG when a key is set.
+  Rfc2136 {
+    /// `host:port` of the primary nameserver, `RFC2136_NAMESERVER`.
+    nameserver: String,
+    /// Zone the challenge records are written to, `RFC2136_ZONE`.
+    zone: String,
+    /// `RFC2136_TSIG_KEY`, `RFC2136_TSIG_SECRET` (base64) and `RFC2136_TSIG_ALGORITHM`.
+    tsig_key: Option<String>,
+    tsig_secret: Option<String>,
+    tsig_algorithm: String,
+  },
+}
+
+impl DnsProviderConfig {
+  fn from_env(value: &str) -> anyhow::Result<Self> {
+    match value.to_lowercase().as_str() {
+      "rfc2136" => {
+        let tsig_key = env::var("RFC2136_TSIG_KEY").ok();
+        let tsig_secret = env::var("RFC2136_TSIG_SECRET").ok();
+        // Half a TSIG config would send unsigned updates.
+        if tsig_key.is_some() != tsig_secret.is_some() {
+          return Err(anyhow::Error::msg(
+            "RFC2136_TSIG_KEY and RFC2136_TSIG_SECRET must be set together",
+          ));
+        }
+        Ok(Self::Rfc2136 {
+          nameserver: env::var("RFC2136_NAMESERVER")
+            .map_err(|_| anyhow::Error::msg("RFC2136_NAMESERVER is required"))?,
+          zone: env::var("RFC2136_ZONE")
+            .map_err(|_| anyhow::Error::msg("RFC2136_ZONE is required"))?,
+          tsig_key,
+          tsig_secret,
+          tsig_algorithm: env::var("RFC2136_TSIG_ALGORITHM")
+            .unwrap_or(default::RFC2136_TSIG_ALGORITHM.to_string()),
+        })
+      }
+      _ => Err(anyhow::Error::msg(format!(
+        "Invalid DNS_PROVIDER `{}`, expected rfc2136",
+        value
+      ))),
+    }
+  }
+}
+
+/// Accepts CIDRs and bare IPs, e.g. `10.0.0.0/8, 203.0.113.7`.
+fn parse_trusted_proxies(value: &str) -> anyhow::Result<Vec<IpNet>> {
+  value
+    .split(',')
+    .map(str::trim)
+    .filter(|entry| !entry.is_empty())
+    .map(|entry| {
+      entry
+        .parse::<IpNet>()
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| anyhow::Error::msg(format!("Invalid TRUSTED_PROXIES entry `{}`", entry)))
+    })
+    .collect()
 }
 
 impl Config {
@@ -22,9 +176,61 @@ impl Config {
       .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
+    let network_subnet_pool: IpNet = env::var("NETWORK_SUBNET_POOL")
+      .unwrap_or(default::NETWORK_SUBNET_POOL.to_string())
+      .parse()
+      .map_err(|_| anyhow::Error::msg("Invalid NETWORK_SUBNET_POOL, expected a CIDR"))?;
+    let network_subnet_prefix = match env::var("NETWORK_SUBNET_PREFIX") {
+      Ok(value) => value.parse()?,
+      Err(_) => default::NETWORK_SUBNET_PREFIX,
+    };
+    if network_subnet_pool.subnets(network_subnet_prefix).is_err() {
+      return Err(anyhow::Error::msg(format!(
+        "NETWORK_SUBNET_PREFIX /{} doesn't fit in NETWORK_SUBNET_POOL {}",
+        network_subnet_prefix, network_subnet_pool
+      )));
+    }
+
     Ok(Config {
       host: "0.0.0.0".to_string(),
       database_url: env::var("DATABASE_URL").unwrap_or(default::DATABASE_URL.to_string()),
+      access_log_format: match env::var("ACCESS_LOG_FORMAT") {
+        Ok(value) => AccessLogFormat::from_env(&value)?,
+        Err(_) => default::ACCESS_LOG_FORMAT,
+      },
+      access_log_retention_days: match env::var("ACCESS_LOG_RETENTION_DAYS") {
+        Ok(value) => value.parse()?,
+        Err(_) => default::ACCESS_LOG_RETENTION_DAYS,
+      },
+      metrics_token: env::var("METRICS_TOKEN").ok(),
+      error_pages_dir: PathBuf::from(
+        env::var("ERROR_PAGES_DIR").unwrap_or(default::ERROR_PAGES_DIR.to_string()),
+      ),
+      acme_directory: match env::var("ACME_DIRECTORY") {
+        Ok(value) => AcmeDirectory::from_env(&value)?,
+        Err(_) => default::ACME_DIRECTORY,
+      },
+      acme_ca_bundle: env::var("ACME_CA_BUNDLE").ok().map(PathBuf::from),
+      acme_email: env::var("ACME_EMAIL")
+        .ok()
+        .filter(|email| !email.is_empty()),
+      dns_provider: match env::var("DNS_PROVIDER") {
+        Ok(value) => Some(DnsProviderConfig::from_env(&value)?),
+        Err(_) => None,
+      },
+      certificate_key_type: match env::var("CERTIFICATE_KEY_TYPE") {
+        Ok(value) => value.parse()?,
+        Err(_) => default::CERTIFICATE_KEY_TYPE,
+      },
+      certificate_key_path: PathBuf::from(
+        env::var("CERTIFICATE_KEY_PATH").unwrap_or(default::CERTIFICATE_KEY_PATH.to_string()),
+      ),
+      trusted_proxies: match env::var("TRUSTED_PROXIES") {
+        Ok(value) => parse_trusted_proxies(&value)?,
+        Err(_) => Vec::new(),
+      },
+      network_subnet_pool,
+      network_subnet_prefix,
     })
   }
 
@@ -0,0 +1,294 @@
+use bollard::models::EventMessage;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Exit codes of a process ended by `SIGKILL` or `SIGTERM`.
+const SIGNAL_EXIT_CODES: [i32; 2] = [137, 143];
+/// How long after a `kill` its `die` is still attributed to it, longer than any stop timeout.
+const KILL_WINDOW: Duration = Duration::from_secs(300);
+
+/// A lifecycle event of a deployment container, as reported by Docker.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ContainerEvent {
+  pub id: Uuid,
+  pub deployment_id: Uuid,
+  /// One of `create`, `start`, `die`, `oom`, `health_status`, `kill` or `stop`.
+  pub action: String,
+  pub exit_code: Option<i32>,
+  /// Health status for `health_status` and the signal for `kill`.
+  pub detail: Option<String>,
+  pub created_at: DateTime<Utc>,
+}
+
+impl ContainerEvent {
+  /// Parses a Docker container event, `None` for untracked actions or non deployment containers.
+  pub fn from_docker(event: &EventMessage) -> Option<Self> {
+    let actor = event.actor.as_ref()?;
+    let attributes = actor.attributes.as_ref()?;
+    let deployment_id = Uuid::parse_str(attributes.get("name")?).ok()?;
+    let action = event.action.as_deref()?;
+    let (action, exit_code, detail) = match action {
+      "create" | "start" | "oom" | "stop" => (action, None, None),
+      "die" => (
+        action,
+        attributes
+          .get("exitCode")
+          .and_then(|code| code.parse().ok()),
+        None,
+      ),
+      "kill" => (action, None, attributes.get("signal").cloned()),
+      _ => {
+        let status = action.strip_prefix("health_status:")?;
+        ("health_status", None, Some(status.trim().to_string()))
+      }
+    };
+    let created_at = event
+      .time_nano
+      .map(DateTime::from_timestamp_nanos)
+      .unwrap_or_else(Utc::now);
+    Some(ContainerEvent {
+      id: Uuid::new_v4(),
+      deployment_id,
+      action: action.to_string(),
+      exit_code,
+      detail,
+      created_at,
+    })
+  }
+
+  /// The deployment status this event moves to, `None` if it doesn't change it.
+  ///
+  /// `killed` is whether a `kill` preceded this event, a container dying from that signal was
+  /// stopped on purpose rather than crashing.
+  pub fn deployment_status(&self, killed: bool) -> Option<&'static str> {
+    match self.action.as_str() {
+      "create" => Some("created"),
+      "start" => Some("running"),
+      "stop" => Some("stopped"),
+      "oom" => Some("failed"),
+      "die" if self.exit_code == Some(0) => Some("exited"),
+      "die"
+        if killed
+          && self
+            .exit_code
+            .is_some_and(|code| SIGNAL_EXIT_CODES.contains(&code)) =>
+      {
+        Some("stopped")
+      }
+      "die" => Some("failed"),
+      "health_status" => match self.detail.as_deref() {
+        Some("healthy") => Some("running"),
+        Some("unhealthy") => Some("unhealthy"),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+
+  /// Whether the container left the network, its IP may then be handed to another container.
+  ///
+  /// Not on `kill`, the process can trap the signal and keep running.
+  pub fn releases_ip(&self) -> bool {
+    matches!(self.action.as_str(), "die" | "stop" | "oom")
+  }
+
+  /// Whether this `kill` sends a signal that ends the process unless trapped.
+  fn is_terminating_kill(&self) -> bool {
+    self.action == "kill"
+      && matches!(
+        self.detail.as_deref(),
+        Some("9" | "15" | "SIGKILL" | "SIGTERM")
+      )
+  }
+
+  /// Stores the event and updates the deployment status, skipped for unknown deployments.
+  pub async fn save(&self, killed: bool, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let mut transaction = pg_pool.begin().await?;
+    sqlx::query!(
+      "
+      INSERT INTO container_event (id, deployment_id, action, exit_code, detail, created_at)
+      SELECT $1, $2, $3, $4, $5, $6
+      WHERE EXISTS (SELECT 1 FROM deployment WHERE id = $2)
+      ",
+      self.id,
+      self.deployment_id,
+      self.action,
+      self.exit_code,
+      self.detail,
+      self.created_at,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if let Some(status) = self.deployment_status(killed) {
+      sqlx::query!(
+        "UPDATE deployment SET status = $1, updated_at = $2 WHERE id = $3",
+        status,
+        Utc::now(),
+        self.deployment_id
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    if self.releases_ip() {
+      // Set again by `Container::sync_deployment_ip` when the container starts.
+      sqlx::query!(
+        "
+        UPDATE deployment SET container_ip = NULL, updated_at = $1
+        WHERE id = $2 AND container_ip IS NOT NULL
+        ",
+        Utc::now(),
+        self.deployment_id
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+  }
+
+  pub async fn get_by_service_id(
+    service_id: Uuid,
+    limit: i64,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "
+        SELECT container_event.* FROM container_event
+        JOIN deployment ON container_event.deployment_id = deployment.id
+        WHERE deployment.service_id = $1
+        ORDER BY container_event.created_at DESC
+        LIMIT $2
+        ",
+        service_id,
+        limit
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+}
+
+/// Deployments sent a terminating signal, so the `die` that follows can be told apart from a
+/// crash. Entries leave on that `die`, on the next start, or once [`KILL_WINDOW`] passed.
+#[derive(Default)]
+pub struct KilledContainers {
+  killed_at: HashMap<Uuid, Instant>,
+}
+
+impl KilledContainers {
+  /// Tracks the event and returns whether a `die` follows a `kill` of the same container.
+  pub fn observe(&mut self, event: &ContainerEvent, now: Instant) -> bool {
+    self
+      .killed_at
+      .retain(|_, killed_at| now.saturating_duration_since(*killed_at) < KILL_WINDOW);
+    match event.action.as_str() {
+      "kill" if event.is_terminating_kill() => {
+        self.killed_at.insert(event.deployment_id, now);
+        false
+      }
+      "die" => self.killed_at.remove(&event.deployment_id).is_some(),
+      "create" | "start" => {
+        self.killed_at.remove(&event.deployment_id);
+        false
+      }
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bollard::models::{EventActor, EventMessageTypeEnum};
+
+  fn docker_event(action: &str, attributes: &[(&str, &str)]) -> EventMessage {
+    let mut attributes: HashMap<String, String> = attributes
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect();
+    attributes.insert(
+      "name".to_string(),
+      "6f1c2a4e-3d5b-4c8a-9e7f-0a1b2c3d4e5f".to_string(),
+    );
+    EventMessage {
+      typ: Some(EventMessageTypeEnum::CONTAINER),
+      action: Some(action.to_string()),
+      actor: Some(EventActor {
+        id: Some("container".to_string()),
+        attributes: Some(attributes),
+      }),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn container_events_update_deployment_status() {
+    let died = ContainerEvent::from_docker(&docker_event("die", &[("exitCode", "137")])).unwrap();
+    assert_eq!(died.exit_code, Some(137));
+    assert_eq!(died.deployment_status(false), Some("failed"));
+    assert_eq!(died.deployment_status(true), Some("stopped"));
+    assert!(died.releases_ip());
+
+    let hangup = ContainerEvent::from_docker(&docker_event("kill", &[("signal", "1")])).unwrap();
+    assert!(!hangup.releases_ip());
+
+    let crashed = ContainerEvent::from_docker(&docker_event("die", &[("exitCode", "1")])).unwrap();
+    assert_eq!(crashed.deployment_status(true), Some("failed"));
+
+    let stopped = ContainerEvent::from_docker(&docker_event("stop", &[])).unwrap();
+    assert_eq!(stopped.deployment_status(false), Some("stopped"));
+    assert!(stopped.releases_ip());
+
+    let unhealthy = ContainerEvent::from_docker(&docker_event("health_status: unhealthy", &[]));
+    let unhealthy = unhealthy.unwrap();
+    assert_eq!(unhealthy.action, "health_status");
+    assert_eq!(unhealthy.deployment_status(false), Some("unhealthy"));
+
+    assert!(ContainerEvent::from_docker(&docker_event("exec_start: sh", &[])).is_none());
+  }
+
+  #[test]
+  fn only_leaving_the_network_releases_the_ip() {
+    let terminated = ContainerEvent::from_docker(&docker_event("kill", &[("signal", "15")]));
+    assert!(!terminated.unwrap().releases_ip());
+    let killed = ContainerEvent::from_docker(&docker_event("kill", &[("signal", "SIGKILL")]));
+    assert!(!killed.unwrap().releases_ip());
+    let oom = ContainerEvent::from_docker(&docker_event("oom", &[])).unwrap();
+    assert!(oom.releases_ip());
+    let started = ContainerEvent::from_docker(&docker_event("start", &[])).unwrap();
+    assert!(!started.releases_ip());
+  }
+
+  #[test]
+  fn killed_containers_are_forgotten_once_they_die() {
+    let now = Instant::now();
+    let mut killed = KilledContainers::default();
+    let event = |action: &str, attributes: &[(&str, &str)]| {
+      ContainerEvent::from_docker(&docker_event(action, attributes)).unwrap()
+    };
+
+    assert!(!killed.observe(&event("kill", &[("signal", "15")]), now));
+    assert!(killed.observe(&event("die", &[("exitCode", "143")]), now));
+    assert!(killed.killed_at.is_empty());
+
+    // A trapped signal leaves the container running, its next crash isn't a stop.
+    assert!(!killed.observe(&event("kill", &[("signal", "1")]), now));
+    assert!(!killed.observe(&event("die", &[("exitCode", "137")]), now));
+
+    assert!(!killed.observe(&event("kill", &[("signal", "9")]), now));
+    assert!(!killed.observe(&event("start", &[]), now));
+    assert!(killed.killed_at.is_empty());
+
+    killed.observe(&event("kill", &[("signal", "SIGKILL")]), now);
+    let later = now + KILL_WINDOW;
+    assert!(!killed.observe(&event("die", &[("exitCode", "137")]), later));
+    assert!(killed.killed_at.is_empty());
+  }
+}
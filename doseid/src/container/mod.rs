@@ -1,12 +1,27 @@
 use bollard::container::ListContainersOptions;
-use bollard::models::{ContainerSummary, EventMessage, EventMessageTypeEnum};
+use bollard::models::ContainerSummary;
 use bollard::system::EventsOptions;
 use bollard::Docker;
+use chrono::Utc;
+use event::{ContainerEvent, KilledContainers};
+use futures_util::future::join_all;
 use futures_util::StreamExt;
+use network::Network;
+use sqlx::{Pool, Postgres};
+use stats::ContainerStats;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::time::interval;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+pub mod event;
+pub mod network;
+pub mod stats;
+
+const STATS_SPAN: u64 = 30; // 30 seconds
+const STATS_RETENTION_HOURS: i64 = 24;
 
 pub struct Container;
 
@@ -26,36 +41,70 @@ impl Container {
     };
   }
 
-  pub async fn start_monitoring_server() -> anyhow::Result<()> {
+  /// Samples resource usage of every running deployment container and keeps a rolling window.
+  pub async fn start_monitoring_server(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::Result<()> {
     info!("DoseiD Container Monitoring Service Running");
+    let pool = Arc::clone(pg_pool);
     tokio::spawn(async move {
-      let mut interval = interval(Duration::from_secs(60));
+      let mut interval = interval(Duration::from_secs(STATS_SPAN));
       loop {
         interval.tick().await;
-        for container in Self::get_running_containers().await.unwrap() {
-          info!(
-            "Container: ID={}, Names={:?}, Image={}, Status={}, State={}, Created={}",
-            container.id.as_deref().unwrap_or("N/A"),
-            container
-              .names
-              .as_ref()
-              .map(|names| names.join(", "))
-              .unwrap_or_else(|| "N/A".to_string()),
-            container.image.as_deref().unwrap_or("N/A"),
-            container.status.as_deref().unwrap_or("N/A"),
-            container.state.as_deref().unwrap_or("N/A"),
-            container.created.unwrap_or(0)
-          );
+        if let Err(e) = Self::sample_stats(&pool).await {
+          error!("Failed to sample container stats: {}", e);
+        }
+        let expired_at = Utc::now() - chrono::Duration::hours(STATS_RETENTION_HOURS);
+        match ContainerStats::delete_older_than(expired_at, &pool).await {
+          Ok(rows) => debug!("Removed {} expired container stats", rows),
+          Err(e) => error!("Failed to remove expired container stats: {}", e),
         }
       }
     });
     Ok(())
   }
 
-  pub async fn start_event_listener() -> anyhow::Result<()> {
+  async fn sample_stats(pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let docker = Docker::connect_with_socket_defaults()?;
+    let deployment_ids: Vec<Uuid> = Self::get_running_containers()
+      .await?
+      .into_iter()
+      .filter_map(|container| {
+        container
+          .names
+          .iter()
+          .flatten()
+          .find_map(|name| Uuid::parse_str(name.trim_start_matches('/')).ok())
+      })
+      .collect();
+    let samples = join_all(
+      deployment_ids
+        .into_iter()
+        .map(|deployment_id| ContainerStats::sample(&docker, deployment_id)),
+    )
+    .await;
+    for sample in samples {
+      match sample {
+        Ok(Some(stats)) => {
+          if let Err(e) = stats.save(pg_pool).await {
+            error!(
+              "Failed to save container stats of deployment {}: {}",
+              stats.deployment_id, e
+            );
+          }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read container stats: {}", e),
+      }
+    }
+    Ok(())
+  }
+
+  /// Persists deployment container lifecycle events and keeps the deployment status in sync.
+  pub async fn start_event_listener(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::Result<()> {
     info!("DoseiD Docker Event Listener Service Running");
+    let pool = Arc::clone(pg_pool);
     tokio::spawn(async move {
       let docker = Docker::connect_with_socket_defaults().unwrap();
+      let mut killed = KilledContainers::default();
       let mut stream = docker.events(Some(EventsOptions {
         filters: HashMap::from([("type", vec!["container"])]),
         ..Default::default()
@@ -63,66 +112,32 @@ impl Container {
       while let Some(event_result) = stream.next().await {
         match event_result {
           Ok(event) => {
-            let event: EventMessage = event;
-            match event.typ {
-              Some(EventMessageTypeEnum::CONTAINER) => match event.action.clone().unwrap().as_str()
-              {
-                "create" => {
-                  let attributes = &event.actor.clone().unwrap().attributes.unwrap();
-                  let name = attributes
-                    .get("name")
-                    .unwrap_or(&"unknown".to_string())
-                    .clone();
-                  info!("Container created: {}", name);
-                }
-                "start" => {
-                  let attributes = &event.actor.clone().unwrap().attributes.unwrap();
-                  let name = attributes
-                    .get("name")
-                    .map(|s| s.as_str())
-                    .unwrap_or("unknown");
-                  let image = attributes
-                    .get("image")
-                    .map(|s| s.as_str())
-                    .unwrap_or("unknown");
-                  // Fix: Store the cloned actor in a binding first
-                  let actor = event.actor.clone().unwrap();
-                  let id = actor.id.as_deref().unwrap_or("unknown");
-                  info!(
-                    "Container started - Name: {}, Image: {}, ID: {}",
-                    name, image, id
-                  );
-                }
-                "die" => {
-                  let attributes = &event.actor.clone().unwrap().attributes.unwrap();
-                  let name = attributes
-                    .get("name")
-                    .map(|s| s.as_str())
-                    .unwrap_or("unknown");
-                  let image = attributes
-                    .get("image")
-                    .map(|s| s.as_str())
-                    .unwrap_or("unknown");
-                  let exit_code = attributes
-                    .get("exitCode")
-                    .map(|s| s.as_str())
-                    .unwrap_or("unknown");
-                  // Fix: Store the cloned actor in a binding first
-                  let actor = event.actor.clone().unwrap();
-                  let id = actor.id.as_deref().unwrap_or("unknown");
-                  error!(
-                    "Container stopped - Name: {}, Image: {}, Exit Code: {}, ID: {}",
-                    name, image, exit_code, id
-                  );
-                }
-                event_action => {
-                  warn!("Unhandled container event action: {}", event_action);
-                }
-              },
-              Some(EventMessageTypeEnum::BUILDER) => {
-                warn!("Unhandled Docker builder events");
+            let Some(container_event) = ContainerEvent::from_docker(&event) else {
+              debug!(
+                "Unhandled container event action: {}",
+                event.action.as_deref().unwrap_or("unknown")
+              );
+              continue;
+            };
+            match container_event.action.as_str() {
+              "die" | "oom" => warn!(
+                "Deployment container {} - Deployment: {}, Exit Code: {:?}",
+                container_event.action, container_event.deployment_id, container_event.exit_code
+              ),
+              action => info!(
+                "Deployment container {} - Deployment: {}",
+                action, container_event.deployment_id
+              ),
+            }
+            let was_killed = killed.observe(&container_event, Instant::now());
+            if let Err(e) = container_event.save(was_killed, &pool).await {
+              error!("Failed to save container event: {}", e);
+            }
+            if container_event.action == "start" {
+              let deployment_id = container_event.deployment_id;
+              if let Err(e) = Self::sync_deployment_ip(&docker, deployment_id, &pool).await {
+                error!("Failed to update deployment {} IP: {}", deployment_id, e);
               }
-              _ => {}
             }
           }
           Err(e) => error!("Docker streaming failed: {:?}", e),
@@ -132,6 +147,37 @@ impl Container {
     Ok(())
   }
 
+  /// Records the IP of a deployment container on its owner network, it may change on restart.
+  pub async fn sync_deployment_ip(
+    docker: &Docker,
+    deployment_id: Uuid,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    let Some(deployment) = sqlx::query!(
+      "SELECT owner_id FROM deployment WHERE id = $1",
+      deployment_id
+    )
+    .fetch_optional(pg_pool)
+    .await?
+    else {
+      return Ok(());
+    };
+    let network = Network::name(deployment.owner_id);
+    let container_ip = Network::container_ip(docker, &deployment_id.to_string(), &network).await?;
+    sqlx::query!(
+      "
+      UPDATE deployment SET container_ip = $1, updated_at = $2
+      WHERE id = $3 AND container_ip IS DISTINCT FROM $1
+      ",
+      container_ip,
+      Utc::now(),
+      deployment_id
+    )
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+  }
+
   async fn get_running_containers() -> anyhow::Result<Vec<ContainerSummary>> {
     let docker = Docker::connect_with_socket_defaults()?;
     let containers = docker
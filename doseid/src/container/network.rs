@@ -0,0 +1,261 @@
+use crate::config::Config;
+use bollard::models::{EndpointSettings, Ipam, IpamConfig};
+use bollard::network::{
+  ConnectNetworkOptions, CreateNetworkOptions, DisconnectNetworkOptions, InspectNetworkOptions,
+  ListNetworksOptions,
+};
+use bollard::Docker;
+use ipnet::IpNet;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Label holding the owner id of a Dosei network.
+const OWNER_LABEL: &str = "io.dosei.owner";
+
+/// `NETWORK_SUBNET_POOL` and `NETWORK_SUBNET_PREFIX`.
+static SUBNET_POOL: OnceCell<(IpNet, u8)> = OnceCell::new();
+/// Serializes network creation so two owners can't be handed the same subnet.
+static CREATE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Each owner gets its own bridge network, services reach their siblings by name on it and
+/// can't reach services of other owners. The proxy reaches them by container IP.
+pub struct Network;
+
+impl Network {
+  pub fn name(owner_id: Uuid) -> String {
+    format!("dosei-{}", owner_id)
+  }
+
+  /// Joins the existing owner networks when doseid runs in a container.
+  pub async fn init(config: &Config) -> anyhow::Result<()> {
+    let _ = SUBNET_POOL.set((config.network_subnet_pool, config.network_subnet_prefix));
+    let docker = Docker::connect_with_socket_defaults()?;
+    let networks = docker
+      .list_networks(Some(ListNetworksOptions {
+        filters: HashMap::from([("label", vec![OWNER_LABEL])]),
+      }))
+      .await?;
+    for name in networks.into_iter().filter_map(|network| network.name) {
+      // The proxy can still reach the other owners, and `ensure` retries on the next deploy.
+      if let Err(e) = Self::join(&docker, &name).await {
+        error!("Failed to join Docker network {}: {}", name, e);
+      }
+    }
+    Ok(())
+  }
+
+  /// Creates the network of an owner if missing and makes sure doseid is on it.
+  pub async fn ensure(docker: &Docker, owner_id: Uuid) -> anyhow::Result<String> {
+    let name = Self::name(owner_id);
+    let _guard = CREATE_LOCK.lock().await;
+    if docker
+      .inspect_network(&name, None::<InspectNetworkOptions<String>>)
+      .await
+      .is_err()
+    {
+      Self::create(docker, &name, owner_id).await?;
+    }
+    Self::join(docker, &name).await?;
+    Ok(name)
+  }
+
+  /// Creates a bridge network on the first free subnet of the pool.
+  async fn create(docker: &Docker, name: &str, owner_id: Uuid) -> anyhow::Result<()> {
+    let (pool, prefix) = *SUBNET_POOL
+      .get()
+      .ok_or_else(|| anyhow::Error::msg("Network subnet pool not initialized"))?;
+    let mut used = Vec::new();
+    for network in docker
+      .list_networks(None::<ListNetworksOptions<String>>)
+      .await?
+    {
+      let configs = network
+        .ipam
+        .and_then(|ipam| ipam.config)
+        .unwrap_or_default();
+      used.extend(
+        configs
+          .into_iter()
+          .filter_map(|config| config.subnet?.parse::<IpNet>().ok()),
+      );
+    }
+    let owner_id = owner_id.to_string();
+    loop {
+      let subnet = free_subnet(pool, prefix, &used).ok_or_else(|| {
+        anyhow::Error::msg(format!(
+          "No free /{} subnet left in the network pool {}",
+          prefix, pool
+        ))
+      })?;
+      let result = docker
+        .create_network(CreateNetworkOptions {
+          name,
+          driver: "bridge",
+          ipam: Ipam {
+            config: Some(vec![IpamConfig {
+              subnet: Some(subnet.to_string()),
+              ..Default::default()
+            }]),
+            ..Default::default()
+          },
+          labels: HashMap::from([(OWNER_LABEL, owner_id.as_str())]),
+          ..Default::default()
+        })
+        .await;
+      match result {
+        Ok(_) => {
+          info!("Created Docker network: {} on {}", name, subnet);
+          return Ok(());
+        }
+        // Routes or networks Docker didn't list, e.g. of another daemon, can still overlap.
+        Err(e) if e.to_string().contains("overlap") => {
+          warn!("Subnet {} is in use, trying the next one: {}", subnet, e);
+          used.push(subnet);
+        }
+        Err(e) => return Err(e.into()),
+      }
+    }
+  }
+
+  /// On the host, bridge networks are reachable without joining them.
+  async fn join(docker: &Docker, name: &str) -> anyhow::Result<()> {
+    let Some(container_id) = Self::own_container_id() else {
+      return Ok(());
+    };
+    let network = docker
+      .inspect_network(name, None::<InspectNetworkOptions<String>>)
+      .await?;
+    let joined = network
+      .containers
+      .iter()
+      .flat_map(|containers| containers.keys())
+      .any(|id| id.starts_with(&container_id));
+    if joined {
+      return Ok(());
+    }
+    docker
+      .connect_network(
+        name,
+        ConnectNetworkOptions {
+          container: container_id.as_str(),
+          endpoint_config: EndpointSettings::default(),
+        },
+      )
+      .await?;
+    info!("Joined Docker network: {}", name);
+    Ok(())
+  }
+
+  /// Endpoint settings for a service container, aliased so siblings resolve `http://<service-name>`.
+  pub fn service_endpoint(service_name: &str) -> EndpointSettings {
+    EndpointSettings {
+      aliases: Some(vec![service_name.to_string()]),
+      ..Default::default()
+    }
+  }
+
+  /// The IP of a container on a network, once it's running.
+  pub async fn container_ip(
+    docker: &Docker,
+    container: &str,
+    network: &str,
+  ) -> anyhow::Result<Option<String>> {
+    let container = docker.inspect_container(container, None).await?;
+    Ok(
+      container
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .and_then(|mut networks| networks.remove(network))
+        .and_then(|endpoint| endpoint.ip_address)
+        .filter(|ip| !ip.is_empty()),
+    )
+  }
+
+  /// Disconnects a container from a network, so its service alias stops resolving.
+  pub async fn disconnect(docker: &Docker, container: &str, network: &str) -> anyhow::Result<()> {
+    docker
+      .disconnect_network(
+        network,
+        DisconnectNetworkOptions {
+          container,
+          force: true,
+        },
+      )
+      .await?;
+    Ok(())
+  }
+
+  /// Read from the mounts rather than the hostname, which `--hostname` can override.
+  fn own_container_id() -> Option<String> {
+    if !Path::new("/.dockerenv").exists() {
+      return None;
+    }
+    let id = std::fs::read_to_string("/proc/self/mountinfo")
+      .ok()
+      .and_then(|mountinfo| container_id_from_mounts(&mountinfo));
+    if id.is_none() {
+      warn!("Couldn't find the doseid container id, owner networks won't be joined");
+    }
+    id
+  }
+}
+
+/// Docker bind mounts `/etc/hostname`, `/etc/hosts` and `/etc/resolv.conf` from
+/// `<data-root>/containers/<id>/`.
+fn container_id_from_mounts(mountinfo: &str) -> Option<String> {
+  mountinfo.lines().find_map(|line| {
+    let root = line.split_whitespace().nth(3)?;
+    let (_, rest) = root.split_once("/containers/")?;
+    let id = rest.split('/').next()?;
+    (id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit())).then(|| id.to_string())
+  })
+}
+
+/// The first subnet of the pool that doesn't overlap any used one.
+fn free_subnet(pool: IpNet, prefix: u8, used: &[IpNet]) -> Option<IpNet> {
+  pool.subnets(prefix).ok()?.find(|subnet| {
+    !used
+      .iter()
+      .any(|used| used.contains(&subnet.network()) || subnet.contains(&used.network()))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn free_subnet_skips_overlapping_networks() {
+    let pool: IpNet = "10.213.0.0/16".parse().unwrap();
+    let used: Vec<IpNet> = vec![
+      "10.213.0.0/24".parse().unwrap(),
+      "10.213.1.128/25".parse().unwrap(),
+      "172.17.0.0/16".parse().unwrap(),
+    ];
+    assert_eq!(
+      free_subnet(pool, 24, &used),
+      Some("10.213.2.0/24".parse().unwrap())
+    );
+    let full: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+    assert_eq!(free_subnet(pool, 24, &full), None);
+  }
+
+  #[test]
+  fn container_id_comes_from_the_hostname_mount() {
+    let id = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    let mountinfo = format!(
+      "1021 1020 0:52 / / rw,relatime master:1 - overlay overlay rw\n\
+       1030 1021 259:1 /var/lib/docker/containers/{id}/resolv.conf /etc/resolv.conf rw - ext4 /dev/nvme0n1p1 rw\n\
+       1031 1021 259:1 /var/lib/docker/containers/{id}/hostname /etc/hostname rw - ext4 /dev/nvme0n1p1 rw\n"
+    );
+    assert_eq!(container_id_from_mounts(&mountinfo), Some(id.to_string()));
+    assert_eq!(
+      container_id_from_mounts("1021 1020 0:52 / / rw,relatime - overlay overlay rw\n"),
+      None
+    );
+  }
+}
@@ -0,0 +1,232 @@
+use bollard::container::{MemoryStatsStats, Stats, StatsOptions};
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// A resource usage sample of a deployment container.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct ContainerStats {
+  pub id: Uuid,
+  pub deployment_id: Uuid,
+  pub cpu_percent: f64,
+  /// Memory usage without the page cache, same as `docker stats`.
+  pub memory_usage_bytes: i64,
+  pub memory_limit_bytes: i64,
+  pub network_rx_bytes: i64,
+  pub network_tx_bytes: i64,
+  pub block_read_bytes: i64,
+  pub block_write_bytes: i64,
+  pub created_at: DateTime<Utc>,
+}
+
+impl ContainerStats {
+  /// Takes a single stats sample, Docker waits for a second cycle so CPU usage can be computed.
+  pub async fn sample(
+    docker: &Docker,
+    deployment_id: Uuid,
+  ) -> anyhow::Result<Option<ContainerStats>> {
+    let mut stream = docker.stats(
+      &deployment_id.to_string(),
+      Some(StatsOptions {
+        stream: false,
+        one_shot: false,
+      }),
+    );
+    match stream.next().await {
+      Some(stats) => Ok(Some(Self::from_docker(deployment_id, &stats?))),
+      None => Ok(None),
+    }
+  }
+
+  fn from_docker(deployment_id: Uuid, stats: &Stats) -> Self {
+    let cpu_delta = stats
+      .cpu_stats
+      .cpu_usage
+      .total_usage
+      .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+    let system_delta = stats
+      .cpu_stats
+      .system_cpu_usage
+      .unwrap_or_default()
+      .saturating_sub(stats.precpu_stats.system_cpu_usage.unwrap_or_default());
+    let online_cpus = stats.cpu_stats.online_cpus.unwrap_or(1);
+    let cpu_percent = if system_delta > 0 {
+      cpu_delta as f64 / system_delta as f64 * online_cpus as f64 * 100.0
+    } else {
+      0.0
+    };
+
+    let memory = &stats.memory_stats;
+    let cache = match memory.stats {
+      Some(MemoryStatsStats::V1(v1)) => v1.total_inactive_file,
+      Some(MemoryStatsStats::V2(v2)) => v2.inactive_file,
+      None => 0,
+    };
+    let memory_usage = memory.usage.unwrap_or_default().saturating_sub(cache);
+
+    let (network_rx, network_tx) = stats
+      .networks
+      .iter()
+      .flat_map(|networks| networks.values())
+      .fold((0, 0), |(rx, tx), network| {
+        (rx + network.rx_bytes, tx + network.tx_bytes)
+      });
+
+    let (block_read, block_write) = stats
+      .blkio_stats
+      .io_service_bytes_recursive
+      .iter()
+      .flatten()
+      .fold((0, 0), |(read, write), entry| {
+        match entry.op.to_lowercase().as_str() {
+          "read" => (read + entry.value, write),
+          "write" => (read, write + entry.value),
+          _ => (read, write),
+        }
+      });
+
+    ContainerStats {
+      id: Uuid::new_v4(),
+      deployment_id,
+      cpu_percent,
+      memory_usage_bytes: memory_usage as i64,
+      memory_limit_bytes: memory.limit.unwrap_or_default() as i64,
+      network_rx_bytes: network_rx as i64,
+      network_tx_bytes: network_tx as i64,
+      block_read_bytes: block_read as i64,
+      block_write_bytes: block_write as i64,
+      created_at: Utc::now(),
+    }
+  }
+
+  /// Stores the sample, skipped for containers which aren't a known deployment.
+  pub async fn save(&self, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    sqlx::query!(
+      "
+      INSERT INTO container_stats (
+        id,
+        deployment_id,
+        cpu_percent,
+        memory_usage_bytes,
+        memory_limit_bytes,
+        network_rx_bytes,
+        network_tx_bytes,
+        block_read_bytes,
+        block_write_bytes,
+        created_at
+      )
+      SELECT $1, $2, $3, $4, $5, $6, $7, $8, $9, $10
+      WHERE EXISTS (SELECT 1 FROM deployment WHERE id = $2)
+      ",
+      self.id,
+      self.deployment_id,
+      self.cpu_percent,
+      self.memory_usage_bytes,
+      self.memory_limit_bytes,
+      self.network_rx_bytes,
+      self.network_tx_bytes,
+      self.block_read_bytes,
+      self.block_write_bytes,
+      self.created_at,
+    )
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+  }
+
+  pub async fn delete_older_than(
+    expired_at: DateTime<Utc>,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<u64> {
+    Ok(
+      sqlx::query!(
+        "DELETE FROM container_stats WHERE created_at < $1",
+        expired_at
+      )
+      .execute(pg_pool)
+      .await?
+      .rows_affected(),
+    )
+  }
+
+  pub async fn get_by_service_id(
+    service_id: Uuid,
+    since: DateTime<Utc>,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "
+        SELECT container_stats.* FROM container_stats
+        JOIN deployment ON container_stats.deployment_id = deployment.id
+        WHERE deployment.service_id = $1 AND container_stats.created_at >= $2
+        ORDER BY container_stats.created_at
+        ",
+        service_id,
+        since
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use utoipa::gen::serde_json;
+
+  #[test]
+  fn from_docker_matches_docker_stats() {
+    let network = |rx_bytes: u64, tx_bytes: u64| {
+      serde_json::json!({
+        "rx_bytes": rx_bytes, "rx_packets": 1, "rx_errors": 0, "rx_dropped": 0,
+        "tx_bytes": tx_bytes, "tx_packets": 1, "tx_errors": 0, "tx_dropped": 0,
+      })
+    };
+    let cpu = |total_usage: u64, system_cpu_usage: u64| {
+      serde_json::json!({
+        "cpu_usage": { "total_usage": total_usage, "usage_in_usermode": 0, "usage_in_kernelmode": 0 },
+        "system_cpu_usage": system_cpu_usage,
+        "online_cpus": 4,
+        "throttling_data": { "periods": 0, "throttled_periods": 0, "throttled_time": 0 },
+      })
+    };
+    let stats: Stats = serde_json::from_value(serde_json::json!({
+      "read": "2026-10-18T12:00:01Z",
+      "preread": "2026-10-18T12:00:00Z",
+      "num_procs": 0,
+      "pids_stats": {},
+      "networks": { "eth0": network(1000, 200), "eth1": network(24, 56) },
+      "memory_stats": { "usage": 64 * 1024 * 1024, "limit": 512 * 1024 * 1024 },
+      "blkio_stats": {
+        "io_service_bytes_recursive": [
+          { "major": 8, "minor": 0, "op": "read", "value": 4096 },
+          { "major": 8, "minor": 0, "op": "write", "value": 8192 },
+          { "major": 8, "minor": 16, "op": "Write", "value": 1024 },
+        ],
+      },
+      "cpu_stats": cpu(300_000_000, 2_000_000_000),
+      "precpu_stats": cpu(100_000_000, 1_000_000_000),
+      "storage_stats": {},
+    }))
+    .unwrap();
+
+    let deployment_id = Uuid::new_v4();
+    let sample = ContainerStats::from_docker(deployment_id, &stats);
+    assert_eq!(sample.deployment_id, deployment_id);
+    // A fifth of the host CPU time on 4 CPUs, shown as 80% like `docker stats` does.
+    assert!((sample.cpu_percent - 80.0).abs() < f64::EPSILON);
+    assert_eq!(sample.memory_usage_bytes, 64 * 1024 * 1024);
+    assert_eq!(sample.memory_limit_bytes, 512 * 1024 * 1024);
+    assert_eq!(sample.network_rx_bytes, 1024);
+    assert_eq!(sample.network_tx_bytes, 256);
+    assert_eq!(sample.block_read_bytes, 4096);
+    assert_eq!(sample.block_write_bytes, 9216);
+  }
+}
@@ -1,29 +1,47 @@
+use crate::container::network::Network;
+use crate::container::Container;
+use crate::metrics::METRICS;
+use crate::service::Service;
 use anyhow::anyhow;
-use bollard::container::{CreateContainerOptions, StartContainerOptions};
+use bollard::container::{CreateContainerOptions, NetworkingConfig, StartContainerOptions};
 use bollard::image::BuildImageOptions;
-use bollard::models::{HostConfig, PortBinding, PortMap};
+use bollard::models::{HostConfig, PortBinding};
 use bollard::Docker;
 use chrono::{DateTime, Utc};
 use futures_util::StreamExt;
-use rand::Rng;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
-use std::collections::HashMap;
-use std::net::TcpListener;
-use tracing::log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
 use tracing::{debug, error, info};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
 pub mod route;
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+const LAST_ACCESSED_FLUSH_SPAN: u64 = 10; // 10 seconds
+
+/// Pending `last_accessed_at` writes keyed by deployment id.
+static LAST_ACCESSED: Lazy<Mutex<HashMap<Uuid, DateTime<Utc>>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct Deployment {
   pub id: Uuid,
   pub service_id: Uuid,
   pub owner_id: Uuid,
+  /// Port of a process on the host, for deployments which don't run in a container. Containers
+  /// with one get their port published on 127.0.0.1, like the dashboard.
   pub host_port: Option<i16>,
   pub container_port: Option<i16>,
+  /// IP on the owner network, set once the container is running.
+  pub container_ip: Option<String>,
+  /// Updated from container events, see [`crate::container::event::ContainerEvent`].
+  pub status: String,
   pub last_accessed_at: Option<DateTime<Utc>>,
   pub updated_at: DateTime<Utc>,
   pub created_at: DateTime<Utc>,
@@ -37,11 +55,6 @@ impl Deployment {
     host_port: Option<i16>,
     pg_pool: &Pool<Postgres>,
   ) -> anyhow::Result<Self> {
-    let host_port = match (container_port, host_port) {
-      (Some(_), Some(port)) => Some(port),
-      (Some(_), None) => Some(Self::find_available_host_port()?),
-      (None, _) => None,
-    };
     let deployment = sqlx::query_as!(
       Deployment,
       "INSERT INTO
@@ -83,6 +96,8 @@ impl Deployment {
       ..Default::default()
     };
 
+    let started_at = Instant::now();
+    let mut succeeded = true;
     let mut stream = docker.build_image(build_image_options, None, Some(tar.to_owned().into()));
     let mut logs = Vec::new(); // Vector to store logs
 
@@ -97,14 +112,22 @@ impl Deployment {
           let error = format!("{:?}", e);
           error!("{}", e);
           logs.push(error);
+          succeeded = false;
           break;
         }
       }
     }
+    METRICS.observe_build(succeeded, started_at.elapsed());
     Ok(logs)
   }
 
-  pub(crate) async fn start(&self, image_tag: Option<String>) -> anyhow::Result<()> {
+  /// Starts the container on its owner network, aliased by service name, and records its IP
+  /// for the proxy.
+  pub(crate) async fn start(
+    &self,
+    image_tag: Option<String>,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
     let docker = Docker::connect_with_socket_defaults()?;
 
     let exposed_port;
@@ -117,23 +140,28 @@ impl Deployment {
       None
     };
 
-    let host_config = if let Some(host_port) = self.host_port {
-      let mut port_map = PortMap::new();
-      // TODO: make this cleaner unwrap, move to exposed port check or something
-      port_map.insert(
-        format!("{}/tcp", &self.container_port.unwrap()),
+    let service = Service::get_by_id(self.service_id, pg_pool)
+      .await?
+      .ok_or_else(|| anyhow!("Service {} not found", self.service_id))?;
+    let network = Network::ensure(&docker, self.owner_id).await?;
+    let port_bindings = match (self.container_port, self.host_port) {
+      (Some(container_port), Some(host_port)) => Some(HashMap::from([(
+        format!("{}/tcp", container_port),
         Some(vec![PortBinding {
           host_ip: Some("127.0.0.1".to_string()),
           host_port: Some(host_port.to_string()),
         }]),
-      );
-      Some(HostConfig {
-        port_bindings: Some(port_map),
-        ..Default::default()
-      })
-    } else {
-      None
+      )])),
+      _ => None,
     };
+    let host_config = Some(HostConfig {
+      network_mode: Some(network.clone()),
+      port_bindings,
+      ..Default::default()
+    });
+    let networking_config = Some(NetworkingConfig {
+      endpoints_config: HashMap::from([(network, Network::service_endpoint(&service.name))]),
+    });
 
     let options = Some(CreateContainerOptions {
       name: self.id,
@@ -151,6 +179,7 @@ impl Deployment {
       image: Some(image_tag),
       exposed_ports,
       host_config,
+      networking_config,
       // env: Some(env_refs),
       tty: Some(true),
       ..Default::default()
@@ -160,6 +189,57 @@ impl Deployment {
     docker
       .start_container(&container.id, None::<StartContainerOptions<String>>)
       .await?;
+    Container::sync_deployment_ip(&docker, self.id, pg_pool).await?;
+    Ok(())
+  }
+
+  /// The `host:port` the proxy forwards to, `None` if the deployment isn't reachable yet.
+  pub fn upstream_authority(&self) -> Option<String> {
+    match (&self.container_ip, self.container_port, self.host_port) {
+      (Some(ip), Some(container_port), _) => match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => Some(format!("[{}]:{}", ip, container_port)),
+        _ => Some(format!("{}:{}", ip, container_port)),
+      },
+      (_, _, Some(host_port)) => Some(format!("127.0.0.1:{}", host_port)),
+      _ => None,
+    }
+  }
+
+  pub async fn update_host_port(
+    &mut self,
+    host_port: Option<i16>,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    sqlx::query!(
+      "UPDATE deployment SET host_port = $1, updated_at = $2 WHERE id = $3",
+      host_port,
+      Utc::now(),
+      self.id
+    )
+    .execute(pg_pool)
+    .await?;
+    self.host_port = host_port;
+    Ok(())
+  }
+
+  /// Takes the older deployments of the service off the owner network and stops them, so the
+  /// service alias only resolves to this one.
+  pub async fn retire_previous(&self, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let docker = Docker::connect_with_socket_defaults()?;
+    let network = Network::name(self.owner_id);
+    let previous = Self::get_by_service_id(self.service_id, pg_pool)
+      .await?
+      .into_iter()
+      .filter(|deployment| deployment.id != self.id && deployment.created_at <= self.created_at);
+    for deployment in previous {
+      let container = deployment.id.to_string();
+      if let Err(e) = Network::disconnect(&docker, &container, &network).await {
+        debug!("Deployment {} wasn't on {}: {}", deployment.id, network, e);
+      }
+      if let Err(e) = deployment.stop().await {
+        debug!("Deployment {} wasn't running: {}", deployment.id, e);
+      }
+    }
     Ok(())
   }
 
@@ -190,52 +270,85 @@ impl Deployment {
     )
   }
 
-  pub async fn find_via_host(host: &str, pg_pool: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+  /// Returns the most recent deployment of every service.
+  pub async fn get_latest_per_service(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
     Ok(
       sqlx::query_as!(
         Self,
         "
-         SELECT deployment.* FROM deployment
-         JOIN ingress ON deployment.service_id = ingress.service_id
-         WHERE ingress.host = $1
-         ORDER BY deployment.created_at DESC
-         LIMIT 1
-        ",
-        host
+         SELECT DISTINCT ON (service_id) * FROM deployment
+         ORDER BY service_id, created_at DESC
+        "
       )
-      .fetch_optional(pg_pool)
+      .fetch_all(pg_pool)
       .await?,
     )
   }
 
-  pub fn update_last_accessed(&self, pg_pool: &Pool<Postgres>) {
-    let deployment_id = self.id;
-    let pool = pg_pool.clone();
-    tokio::spawn(async move {
-      let result = sqlx::query!(
-        "UPDATE deployment SET last_accessed_at = $1 WHERE id = $2",
-        Utc::now(),
-        deployment_id
+  /// Returns every running deployment, newest first.
+  pub async fn get_running(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT * FROM deployment WHERE status = 'running' ORDER BY created_at DESC"
       )
-      .execute(&pool)
-      .await;
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
 
-      match result {
-        Ok(rows) => {
-          if rows.rows_affected() > 0 {
-            debug!("Updated last_accessed_at for deployment: {}", deployment_id);
-          } else {
-            warn!("No deployment found with id: {}", deployment_id);
-          }
+  /// Counts deployments by their status, as tracked from container events.
+  pub async fn count_by_status(pg_pool: &Pool<Postgres>) -> anyhow::Result<BTreeMap<String, i64>> {
+    let rows =
+      sqlx::query!(r#"SELECT status, COUNT(*) AS "count!" FROM deployment GROUP BY status"#)
+        .fetch_all(pg_pool)
+        .await?;
+    Ok(
+      rows
+        .into_iter()
+        .map(|row| (row.status, row.count))
+        .collect(),
+    )
+  }
+
+  /// Records an access, persisted in batches by [`Deployment::start_last_accessed_flusher`].
+  pub fn update_last_accessed(&self) {
+    LAST_ACCESSED.lock().unwrap().insert(self.id, Utc::now());
+  }
+
+  pub async fn start_last_accessed_flusher(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::Result<()> {
+    let pool = Arc::clone(pg_pool);
+    tokio::spawn(async move {
+      let mut interval = interval(Duration::from_secs(LAST_ACCESSED_FLUSH_SPAN));
+      loop {
+        interval.tick().await;
+        let accessed = std::mem::take(&mut *LAST_ACCESSED.lock().unwrap());
+        if accessed.is_empty() {
+          continue;
         }
-        Err(e) => {
-          error!(
-            "Failed to update last_accessed_at for deployment {}: {}",
-            deployment_id, e
-          );
+        let (ids, accessed_at): (Vec<Uuid>, Vec<DateTime<Utc>>) = accessed.into_iter().unzip();
+        let result = sqlx::query!(
+          "
+          UPDATE deployment SET last_accessed_at = accessed.last_accessed_at
+          FROM UNNEST($1::uuid[], $2::timestamptz[]) AS accessed(id, last_accessed_at)
+          WHERE deployment.id = accessed.id
+          ",
+          &ids,
+          &accessed_at
+        )
+        .execute(&*pool)
+        .await;
+
+        match result {
+          Ok(rows) => debug!(
+            "Updated last_accessed_at for {} deployments",
+            rows.rows_affected()
+          ),
+          Err(e) => error!("Failed to update last_accessed_at: {}", e),
         }
       }
     });
+    Ok(())
   }
 }
 
@@ -246,30 +359,4 @@ impl Deployment {
   fn image_tag(&self) -> String {
     format!("{}/{}:{}", self.owner_id, self.service_id, self.id)
   }
-
-  /// Finds an available TCP port on the host in the range 10000-20000
-  ///
-  /// Randomly tries ports in the specified range until finding one that can be bound to.
-  /// Makes up to 1000 attempts to find an available port before giving up.
-  ///
-  /// # Returns
-  /// - `Ok(port)`: The available port number as an i16
-  /// - `Err`: An error if no available port could be found after 1000 attempts
-  ///
-  /// # Example
-  /// ```
-  /// let port = Deployment::find_available_host_port()?;
-  /// println!("Found available port: {}", port);
-  /// ```
-  fn find_available_host_port() -> anyhow::Result<i16> {
-    let mut rng = rand::rng();
-
-    for _ in 0..1000 {
-      let port = rng.random_range(10000..=20000);
-      if TcpListener::bind(format!("0.0.0.0:{}", port)).is_ok() {
-        return Ok(port);
-      }
-    }
-    Err(anyhow!("Failed to find an available port"))
-  }
 }
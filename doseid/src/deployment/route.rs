@@ -1,7 +1,10 @@
 use crate::account::Account;
-use crate::certificate::Certificate;
+use crate::certificate::order::CertificateOrder;
+use crate::certificate::{Certificate, SOURCE_ACME};
+use crate::cluster::Cluster;
 use crate::deployment::Deployment;
 use crate::ingress::Ingress;
+use crate::service::error_page::ServiceErrorPage;
 use crate::service::Service;
 use crate::session::AuthSession;
 use axum::extract::{Multipart, Path};
@@ -10,6 +13,7 @@ use axum::{Extension, Json};
 use dosei_schema::app::App;
 use dosei_schema::cluster::ClusterInit;
 use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::error;
 use utoipa::gen::serde_json::{json, Value};
@@ -54,6 +58,7 @@ pub async fn api_list_service_deployments(
   path = "/deploy",
   responses(
         (status = StatusCode::OK, body = Value),
+        (status = StatusCode::CONFLICT, body = Value, description = "Another account publishes a service of the same name under the cluster wildcard domain"),
   ),
   security(
       ("Authentication" = [])
@@ -109,14 +114,44 @@ pub async fn api_deploy(
 
   let app = App::from_string(&app).map_err(|_| StatusCode::BAD_REQUEST)?;
 
-  let service = match Service::new(&app.name, session.account_id, &pg_pool).await {
-    Ok(service) => service,
-    Err(_) => Service::get_by_name(app.name.clone(), &pg_pool)
+  // Services are published as `<service>.<wildcard_domain>`, names are only unique per account.
+  let wildcard_domain = Cluster::get().await.wildcard_domain;
+  if let Some(wildcard_domain) = &wildcard_domain {
+    let host = format!("{}.{}", app.name, wildcard_domain);
+    if let Some(ingress) = Ingress::get_by_host(&host, &pg_pool)
       .await
       .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-      .unwrap(),
+    {
+      if ingress.owner_id != session.account_id {
+        return Ok((
+          StatusCode::CONFLICT,
+          Json(json!({
+            "error": format!("{} is taken by another account, rename the service", host)
+          })),
+        ));
+      }
+    }
+  }
+
+  let service = match Service::get_by_name(&app.name, session.account_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+  {
+    Some(service) => service,
+    None => Service::new(&app.name, session.account_id, &pg_pool)
+      .await
+      .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
   };
 
+  let error_pages = match &app.error_pages {
+    Some(error_pages) => ServiceErrorPage::read_from_archive(&file_data, error_pages)
+      .map_err(|_| StatusCode::BAD_REQUEST)?,
+    None => HashMap::new(),
+  };
+  ServiceErrorPage::replace(service.id, &error_pages, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
   let deployment = Deployment::new(service.id, service.owner_id, app.port, None, &pg_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -126,10 +161,17 @@ pub async fn api_deploy(
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
   deployment
-    .start(None)
+    .start(None, &pg_pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  if let Err(e) = deployment.retire_previous(&pg_pool).await {
+    error!(
+      "Failed to retire previous deployments of {}: {}",
+      service.name, e
+    );
+  }
 
+  let ingress_settings = app.ingress.unwrap_or_default();
   if let Some(domains) = app.domains {
     if !domains.is_empty() {
       let domain = domains.first().unwrap();
@@ -138,13 +180,70 @@ pub async fn api_deploy(
           let account = Account::get_by_id(service.owner_id, &pg_pool)
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-          if let Err(e) = Certificate::request(account.unwrap().id, domain).await {
+          if let Err(e) = Certificate::request(
+            account.unwrap().id,
+            domain,
+            ingress_settings.certificate_key_type,
+            &pg_pool,
+          )
+          .await
+          {
             error!("{}", e);
           }
           {
             let _ = Ingress::new(domain.clone(), service.id, service.owner_id, &pg_pool).await;
           }
         }
+        // A changed key type is applied right away instead of at the next renewal.
+        if let (Some(certificate), Some(key_type)) = (result, ingress_settings.certificate_key_type)
+        {
+          if certificate.source == SOURCE_ACME
+            && certificate.owner_id == service.owner_id
+            && certificate.key_type != key_type.as_str()
+            && !CertificateOrder::is_in_progress(domain, &pg_pool)
+              .await
+              .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+          {
+            if let Err(e) =
+              Certificate::request(service.owner_id, domain, Some(key_type), &pg_pool).await
+            {
+              error!("{}", e);
+            }
+          }
+        }
+      }
+      if let Some(ingress) = Ingress::get_by_host(domain, &pg_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+      {
+        if ingress.service_id == service.id {
+          ingress
+            .update_settings(&ingress_settings, &pg_pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+      }
+    }
+  }
+
+  // Every service is also reachable under the cluster wildcard domain
+  if let Some(wildcard_domain) = wildcard_domain {
+    let host = format!("{}.{}", service.name, wildcard_domain);
+    if ClusterInit::validate_domain(&host) {
+      let ingress = match Ingress::get_by_host(&host, &pg_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+      {
+        Some(ingress) => ingress,
+        None => Ingress::new(host, service.id, service.owner_id, &pg_pool)
+          .await
+          .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+      };
+      if ingress.service_id == service.id {
+        ingress
+          .update_settings(&ingress_settings, &pg_pool)
+          .await
+          .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
       }
     }
   }
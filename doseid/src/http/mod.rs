@@ -5,7 +5,7 @@ mod proxy;
 use crate::config::Config;
 use crate::http::proxy::Proxy;
 use crate::session::Session;
-use crate::{account, auth, certificate, deployment, ingress, service};
+use crate::{access_log, account, auth, certificate, deployment, ingress, metrics, service};
 use anyhow::{anyhow, Context};
 use axum::{middleware, Extension, Router};
 use sqlx::{Pool, Postgres};
@@ -39,18 +39,29 @@ impl Http {
     let (public_router, public_api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
       .routes(routes!(health::health))
       .routes(routes!(info::info))
+      .routes(routes!(metrics::route::api_metrics))
       .routes(routes!(certificate::route::api_http01_challenge))
+      .routes(routes!(certificate::route::api_certificate_authority))
       .split_for_parts();
     api_doc.merge(public_api);
 
     let (private_router, private_api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
       .routes(routes!(account::route::api_user))
       .routes(routes!(account::route::api_list_user_ssh_key))
-      .routes(routes!(certificate::route::api_list_certificates))
+      .routes(routes!(
+        certificate::route::api_list_certificates,
+        certificate::route::api_upload_certificate
+      ))
+      .routes(routes!(certificate::route::api_list_certificate_renewals))
+      .routes(routes!(certificate::route::api_list_certificate_orders))
       .routes(routes!(service::route::api_list_services))
       .routes(routes!(deployment::route::api_deploy))
       .routes(routes!(deployment::route::api_list_service_deployments))
       .routes(routes!(ingress::route::api_list_service_ingresses))
+      .routes(routes!(access_log::route::api_list_service_access_logs))
+      .routes(routes!(service::route::api_get_service_metrics))
+      .routes(routes!(service::route::api_list_service_events))
+      .routes(routes!(service::route::api_update_service_maintenance))
       .routes(routes!(auth::route::login_ssh))
       .routes(routes!(auth::route::logout))
       .route_layer(middleware::from_fn(Session::middleware))
@@ -0,0 +1,106 @@
+use crate::metrics::METRICS;
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::RustlsAcceptor;
+use futures_util::future::BoxFuture;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Wraps the TLS acceptor to count handshakes and open connections.
+#[derive(Clone)]
+pub struct MetricsAcceptor {
+  inner: RustlsAcceptor,
+}
+
+impl MetricsAcceptor {
+  pub fn new(inner: RustlsAcceptor) -> Self {
+    Self { inner }
+  }
+}
+
+impl<I, S> Accept<I, S> for MetricsAcceptor
+where
+  RustlsAcceptor: Accept<I, S>,
+  <RustlsAcceptor as Accept<I, S>>::Future: Send + 'static,
+  <RustlsAcceptor as Accept<I, S>>::Stream: Send + 'static,
+  <RustlsAcceptor as Accept<I, S>>::Service: Send + 'static,
+{
+  type Stream = TrackedStream<<RustlsAcceptor as Accept<I, S>>::Stream>;
+  type Service = <RustlsAcceptor as Accept<I, S>>::Service;
+  type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+  fn accept(&self, stream: I, service: S) -> Self::Future {
+    let handshake = self.inner.accept(stream, service);
+    Box::pin(async move {
+      match handshake.await {
+        Ok((stream, service)) => {
+          METRICS.tls_handshake(true);
+          Ok((TrackedStream::new(stream), service))
+        }
+        Err(e) => {
+          METRICS.tls_handshake(false);
+          Err(e)
+        }
+      }
+    })
+  }
+}
+
+/// Keeps the active connections gauge up to date for as long as the stream lives.
+pub struct TrackedStream<S> {
+  inner: S,
+}
+
+impl<S> TrackedStream<S> {
+  fn new(inner: S) -> Self {
+    METRICS.connection_opened();
+    Self { inner }
+  }
+}
+
+impl<S> Drop for TrackedStream<S> {
+  fn drop(&mut self) {
+    METRICS.connection_closed();
+  }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TrackedStream<S> {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_read(cx, buf)
+  }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrackedStream<S> {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+
+  fn poll_write_vectored(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    bufs: &[io::IoSlice<'_>],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+  }
+
+  fn is_write_vectored(&self) -> bool {
+    self.inner.is_write_vectored()
+  }
+}
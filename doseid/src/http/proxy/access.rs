@@ -0,0 +1,223 @@
+use crate::ingress::Ingress;
+use axum::http::{header, HeaderMap};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use cached::{Cached, TimedCache};
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tracing::{error, warn};
+
+/// How long a verified `Authorization` header skips bcrypt, which takes tens of milliseconds.
+const VERIFIED_LIFESPAN: u64 = 300; // 5 minutes
+
+/// Keyed by the `Authorization` header and the bcrypt hash it matched, so changed credentials
+/// are never served from the cache.
+static VERIFIED_CREDENTIALS: Lazy<Mutex<TimedCache<(String, String), ()>>> =
+  Lazy::new(|| Mutex::new(TimedCache::with_lifespan(VERIFIED_LIFESPAN)));
+
+pub enum AccessDenied {
+  /// The client IP isn't allowed.
+  Forbidden,
+  /// Basic auth credentials are missing or wrong.
+  Unauthorized,
+}
+
+/// Per-ingress access controls, parsed once when the routing table is loaded.
+#[derive(Debug, Default)]
+pub struct AccessPolicy {
+  allow: Option<Vec<IpNet>>,
+  deny: Vec<IpNet>,
+  /// `(username, bcrypt hash)` pairs.
+  credentials: Vec<(String, String)>,
+}
+
+impl From<&Ingress> for AccessPolicy {
+  fn from(ingress: &Ingress) -> Self {
+    // An allowlist of only invalid entries still denies everyone.
+    let allow = (!ingress.allow_cidrs.is_empty()).then(|| parse_networks(&ingress.allow_cidrs));
+    let credentials = ingress
+      .basic_auth
+      .iter()
+      .filter_map(|entry| match entry.split_once(':') {
+        Some((username, hash)) => Some((username.to_string(), hash.to_string())),
+        None => {
+          warn!("Ignoring malformed basic auth entry for {}", ingress.host);
+          None
+        }
+      })
+      .collect();
+    Self {
+      allow,
+      deny: parse_networks(&ingress.deny_cidrs),
+      credentials,
+    }
+  }
+}
+
+impl AccessPolicy {
+  pub fn requires_auth(&self) -> bool {
+    !self.credentials.is_empty()
+  }
+
+  /// `client_ip` must come from [`super::forwarding::client_ip`], which only reads
+  /// `X-Forwarded-For` on connections from trusted proxies and only the hops they appended.
+  pub fn is_ip_allowed(&self, client_ip: IpAddr) -> bool {
+    if self.deny.iter().any(|network| network.contains(&client_ip)) {
+      return false;
+    }
+    match &self.allow {
+      Some(allow) => allow.iter().any(|network| network.contains(&client_ip)),
+      None => true,
+    }
+  }
+
+  pub async fn check(&self, headers: &HeaderMap, client_ip: IpAddr) -> Result<(), AccessDenied> {
+    if !self.is_ip_allowed(client_ip) {
+      return Err(AccessDenied::Forbidden);
+    }
+    if !self.requires_auth() {
+      return Ok(());
+    }
+    let authorization = headers
+      .get(header::AUTHORIZATION)
+      .and_then(|value| value.to_str().ok())
+      .ok_or(AccessDenied::Unauthorized)?;
+    let (username, password) = parse_basic_auth(authorization).ok_or(AccessDenied::Unauthorized)?;
+    let (_, hash) = self
+      .credentials
+      .iter()
+      .find(|(name, _)| *name == username)
+      .ok_or(AccessDenied::Unauthorized)?;
+
+    let cache_key = (authorization.to_string(), hash.clone());
+    if VERIFIED_CREDENTIALS
+      .lock()
+      .unwrap()
+      .cache_get(&cache_key)
+      .is_some()
+    {
+      return Ok(());
+    }
+    let hash = hash.clone();
+    let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+      .await
+      .map_err(|_| AccessDenied::Unauthorized)?;
+    match verified {
+      Ok(true) => {
+        VERIFIED_CREDENTIALS
+          .lock()
+          .unwrap()
+          .cache_set(cache_key, ());
+        Ok(())
+      }
+      Ok(false) => Err(AccessDenied::Unauthorized),
+      Err(e) => {
+        error!("Failed to verify basic auth credentials: {}", e);
+        Err(AccessDenied::Unauthorized)
+      }
+    }
+  }
+}
+
+/// Accepts CIDRs and bare IPs, e.g. `10.0.0.0/8` or `203.0.113.7`.
+fn parse_networks(values: &[String]) -> Vec<IpNet> {
+  values
+    .iter()
+    .filter_map(|value| {
+      let network = value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from));
+      match network {
+        Ok(network) => Some(network),
+        Err(_) => {
+          warn!("Ignoring invalid CIDR: {}", value);
+          None
+        }
+      }
+    })
+    .collect()
+}
+
+fn parse_basic_auth(authorization: &str) -> Option<(String, String)> {
+  let (scheme, credentials) = authorization.split_once(' ')?;
+  if !scheme.eq_ignore_ascii_case("basic") {
+    return None;
+  }
+  let credentials = STANDARD.decode(credentials.trim()).ok()?;
+  let credentials = String::from_utf8(credentials).ok()?;
+  let (username, password) = credentials.split_once(':')?;
+  Some((username.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::http::proxy::forwarding::{self, ForwardingPolicy};
+  use axum::http::HeaderValue;
+
+  #[test]
+  fn deny_list_wins_over_allow_list() {
+    let policy = AccessPolicy {
+      allow: Some(parse_networks(&["10.0.0.0/8".to_string()])),
+      deny: parse_networks(&["10.0.0.7".to_string()]),
+      credentials: Vec::new(),
+    };
+    assert!(policy.is_ip_allowed("10.1.2.3".parse().unwrap()));
+    assert!(!policy.is_ip_allowed("10.0.0.7".parse().unwrap()));
+    assert!(!policy.is_ip_allowed("192.168.0.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn forged_forwarded_for_doesnt_pass_allow_list() {
+    let policy = AccessPolicy {
+      allow: Some(parse_networks(&["10.0.0.0/8".to_string()])),
+      deny: Vec::new(),
+      credentials: Vec::new(),
+    };
+    let forwarding_policy = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: true,
+    };
+    let mut headers = HeaderMap::new();
+    // The client claims an allowed address, the trusted hop appended the one it really saw.
+    headers.insert(
+      "x-forwarded-for",
+      HeaderValue::from_static("10.0.0.1, 203.0.113.7"),
+    );
+    let peer = "198.51.100.2".parse().unwrap();
+    let trusted_proxies = ["198.51.100.0/24".parse().unwrap()];
+    let client_ip = forwarding::client_ip(&headers, forwarding_policy, &trusted_proxies, peer);
+    assert!(!policy.is_ip_allowed(client_ip));
+  }
+
+  #[tokio::test]
+  async fn direct_client_forging_forwarded_for_is_denied() {
+    let policy = AccessPolicy {
+      allow: Some(parse_networks(&["10.0.0.0/8".to_string()])),
+      deny: Vec::new(),
+      credentials: Vec::new(),
+    };
+    let forwarding_policy = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: true,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", HeaderValue::from_static("10.0.0.1"));
+    let trusted_proxies = ["198.51.100.0/24".parse().unwrap()];
+
+    // Connecting directly, the header is ignored and the peer address is checked.
+    let peer = "203.0.113.7".parse().unwrap();
+    let client_ip = forwarding::client_ip(&headers, forwarding_policy, &trusted_proxies, peer);
+    assert!(matches!(
+      policy.check(&headers, client_ip).await,
+      Err(AccessDenied::Forbidden)
+    ));
+
+    // Through a trusted proxy, the address it appended is checked.
+    let peer = "198.51.100.2".parse().unwrap();
+    let client_ip = forwarding::client_ip(&headers, forwarding_policy, &trusted_proxies, peer);
+    assert!(policy.check(&headers, client_ip).await.is_ok());
+  }
+}
@@ -0,0 +1,393 @@
+use crate::ingress::Ingress;
+use axum::body::{Body, Bytes};
+use axum::http::header::{
+  ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+  ETAG, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::Response;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use http_body::{Frame, SizeHint};
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Used when the ingress doesn't list its own content types.
+const DEFAULT_CONTENT_TYPES: [&str; 8] = [
+  "text/*",
+  "application/json",
+  "application/javascript",
+  "application/xml",
+  "application/manifest+json",
+  "application/wasm",
+  "image/svg+xml",
+  "font/ttf",
+];
+
+/// Streams that must reach the client as they're produced, which buffering would stall.
+const STREAMING_CONTENT_TYPES: [&str; 1] = ["text/event-stream"];
+
+/// Input compressed since the last flush before the encoder output is pushed to the client.
+/// Flushing every upstream chunk would end a deflate block each time and ruin the ratio, so
+/// chunks are only flushed early when the upstream goes quiet, see [`CompressionBody`].
+const FLUSH_THRESHOLD: usize = 32 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+  Gzip,
+  Deflate,
+}
+
+impl Encoding {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Encoding::Gzip => "gzip",
+      Encoding::Deflate => "deflate",
+    }
+  }
+}
+
+/// Picks the supported encoding with the highest `Accept-Encoding` weight, preferring gzip.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+  let mut best: Option<(Encoding, f32)> = None;
+  for value in headers.get_all(ACCEPT_ENCODING) {
+    let Ok(value) = value.to_str() else {
+      continue;
+    };
+    for item in value.split(',') {
+      let mut parts = item.split(';');
+      let coding = parts.next().unwrap_or_default().trim();
+      let weight = parts
+        .filter_map(|param| param.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+      let encoding = match coding.to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" | "*" => Encoding::Gzip,
+        "deflate" => Encoding::Deflate,
+        _ => continue,
+      };
+      if weight <= 0.0 {
+        continue;
+      }
+      let is_better = match best {
+        Some((current, current_weight)) => {
+          weight > current_weight || (weight == current_weight && current == Encoding::Deflate)
+        }
+        None => true,
+      };
+      if is_better {
+        best = Some((encoding, weight));
+      }
+    }
+  }
+  best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses the upstream response when the ingress allows it and the response qualifies.
+pub fn compress(
+  response: Response,
+  encoding: Encoding,
+  method: &Method,
+  ingress: &Ingress,
+) -> Response {
+  if !ingress.compression || !is_compressible(&response, method, ingress) {
+    return response;
+  }
+  let (mut parts, body) = response.into_parts();
+  parts.headers.remove(CONTENT_LENGTH);
+  parts.headers.insert(
+    CONTENT_ENCODING,
+    HeaderValue::from_static(encoding.as_str()),
+  );
+  let varies = parts
+    .headers
+    .get_all(VARY)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .any(|value| value.to_ascii_lowercase().contains("accept-encoding"));
+  if !varies {
+    parts
+      .headers
+      .append(VARY, HeaderValue::from_static("accept-encoding"));
+  }
+  // The representation changed, so a strong validator no longer matches it.
+  if let Some(etag) = parts.headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+    if !etag.starts_with("W/") {
+      if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+        parts.headers.insert(ETAG, weak);
+      }
+    }
+  }
+  let body = Body::new(CompressionBody {
+    inner: body,
+    encoder: Some(Encoder::new(encoding)),
+  });
+  Response::from_parts(parts, body)
+}
+
+fn is_compressible(response: &Response, method: &Method, ingress: &Ingress) -> bool {
+  let headers = response.headers();
+  let status = response.status();
+  if method == Method::HEAD
+    || status.is_informational()
+    || status == StatusCode::NO_CONTENT
+    || status == StatusCode::NOT_MODIFIED
+    // Byte ranges refer to the identity representation, compressing them corrupts the result.
+    || status == StatusCode::PARTIAL_CONTENT
+    || headers.contains_key(CONTENT_RANGE)
+    || headers.contains_key(CONTENT_ENCODING)
+  {
+    return false;
+  }
+  let no_transform = headers
+    .get_all(CACHE_CONTROL)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+  if no_transform {
+    return false;
+  }
+  let content_length = headers
+    .get(CONTENT_LENGTH)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.parse::<u64>().ok());
+  if content_length.is_some_and(|length| length < ingress.compression_min_size as u64) {
+    return false;
+  }
+  let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+    return false;
+  };
+  let essence = content_type
+    .split(';')
+    .next()
+    .unwrap_or_default()
+    .trim()
+    .to_ascii_lowercase();
+  if STREAMING_CONTENT_TYPES.contains(&essence.as_str()) {
+    return false;
+  }
+  if ingress.compression_content_types.is_empty() {
+    DEFAULT_CONTENT_TYPES
+      .iter()
+      .any(|pattern| matches_content_type(pattern, &essence))
+  } else {
+    ingress
+      .compression_content_types
+      .iter()
+      .any(|pattern| matches_content_type(pattern, &essence))
+  }
+}
+
+fn matches_content_type(pattern: &str, essence: &str) -> bool {
+  match pattern.strip_suffix("/*") {
+    Some(kind) => essence
+      .split_once('/')
+      .is_some_and(|(essence_kind, _)| essence_kind.eq_ignore_ascii_case(kind)),
+    None => pattern.eq_ignore_ascii_case(essence),
+  }
+}
+
+enum Coder {
+  Gzip(GzEncoder<Vec<u8>>),
+  Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+struct Encoder {
+  coder: Coder,
+  /// Input bytes written since the last flush.
+  unflushed: usize,
+}
+
+impl Encoder {
+  fn new(encoding: Encoding) -> Self {
+    let coder = match encoding {
+      Encoding::Gzip => Coder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+      Encoding::Deflate => Coder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default())),
+    };
+    Self {
+      coder,
+      unflushed: 0,
+    }
+  }
+
+  /// Compresses a chunk and returns the output so far, flushed once [`FLUSH_THRESHOLD`] bytes
+  /// went in so large streamed responses keep flowing.
+  fn encode(&mut self, data: &[u8]) -> std::io::Result<Bytes> {
+    self.unflushed += data.len();
+    let output = match &mut self.coder {
+      Coder::Gzip(encoder) => {
+        encoder.write_all(data)?;
+        encoder.get_mut()
+      }
+      Coder::Deflate(encoder) => {
+        encoder.write_all(data)?;
+        encoder.get_mut()
+      }
+    };
+    if self.unflushed >= FLUSH_THRESHOLD {
+      return self.flush();
+    }
+    Ok(Bytes::from(std::mem::take(output)))
+  }
+
+  /// Ends the current block so everything written so far can be decoded by the client.
+  fn flush(&mut self) -> std::io::Result<Bytes> {
+    self.unflushed = 0;
+    let output = match &mut self.coder {
+      Coder::Gzip(encoder) => {
+        encoder.flush()?;
+        encoder.get_mut()
+      }
+      Coder::Deflate(encoder) => {
+        encoder.flush()?;
+        encoder.get_mut()
+      }
+    };
+    Ok(Bytes::from(std::mem::take(output)))
+  }
+
+  fn finish(self) -> std::io::Result<Bytes> {
+    let output = match self.coder {
+      Coder::Gzip(encoder) => encoder.finish()?,
+      Coder::Deflate(encoder) => encoder.finish()?,
+    };
+    Ok(Bytes::from(output))
+  }
+}
+
+/// Compresses the upstream body, flushing whatever is buffered whenever the upstream has
+/// nothing more to send yet, so NDJSON or long-poll responses aren't held back.
+struct CompressionBody {
+  inner: Body,
+  /// Taken once the inner body ends and the trailer has been written.
+  encoder: Option<Encoder>,
+}
+
+impl http_body::Body for CompressionBody {
+  type Data = Bytes;
+  type Error = axum::Error;
+
+  fn poll_frame(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+    let this = self.get_mut();
+    loop {
+      let Some(encoder) = this.encoder.as_mut() else {
+        return Poll::Ready(None);
+      };
+      match Pin::new(&mut this.inner).poll_frame(cx) {
+        Poll::Pending if encoder.unflushed == 0 => return Poll::Pending,
+        Poll::Pending => {
+          return match encoder.flush() {
+            Ok(output) if output.is_empty() => Poll::Pending,
+            Ok(output) => Poll::Ready(Some(Ok(Frame::data(output)))),
+            Err(e) => Poll::Ready(Some(Err(axum::Error::new(e)))),
+          };
+        }
+        Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+        Poll::Ready(Some(Ok(frame))) => {
+          // Trailers are dropped, they rarely survive a content coding change anyway.
+          let Ok(data) = frame.into_data() else {
+            continue;
+          };
+          match encoder.encode(&data) {
+            Ok(output) if output.is_empty() => continue,
+            Ok(output) => return Poll::Ready(Some(Ok(Frame::data(output)))),
+            Err(e) => return Poll::Ready(Some(Err(axum::Error::new(e)))),
+          }
+        }
+        Poll::Ready(None) => {
+          let encoder = this.encoder.take().unwrap();
+          return match encoder.finish() {
+            Ok(output) => Poll::Ready(Some(Ok(Frame::data(output)))),
+            Err(e) => Poll::Ready(Some(Err(axum::Error::new(e)))),
+          };
+        }
+      }
+    }
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.encoder.is_none()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    SizeHint::default()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn negotiate_respects_weights() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      ACCEPT_ENCODING,
+      HeaderValue::from_static("br, deflate;q=0.8, gzip;q=0.5"),
+    );
+    assert_eq!(negotiate(&headers), Some(Encoding::Deflate));
+
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("deflate, gzip"));
+    assert_eq!(negotiate(&headers), Some(Encoding::Gzip));
+
+    headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip;q=0, br"));
+    assert_eq!(negotiate(&headers), None);
+  }
+
+  #[test]
+  fn encoder_buffers_small_chunks_until_the_end() {
+    let mut encoder = Encoder::new(Encoding::Gzip);
+    let mut compressed = Vec::new();
+    for _ in 0..100 {
+      let output = encoder.encode(b"hello compression ").unwrap();
+      compressed.extend_from_slice(&output);
+    }
+    // Only the gzip header may have been written, no block was ended early.
+    assert!(compressed.len() <= 10);
+    compressed.extend_from_slice(&encoder.finish().unwrap());
+
+    let mut decoded = String::new();
+    std::io::Read::read_to_string(
+      &mut flate2::read::GzDecoder::new(compressed.as_slice()),
+      &mut decoded,
+    )
+    .unwrap();
+    assert_eq!(decoded, "hello compression ".repeat(100));
+  }
+
+  #[tokio::test]
+  async fn stalled_streams_are_flushed() {
+    use http_body_util::BodyExt;
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Bytes>(1);
+    let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+      let chunk = receiver.recv().await?;
+      Some((Ok::<_, std::io::Error>(chunk), receiver))
+    });
+    let mut body = CompressionBody {
+      inner: Body::from_stream(stream),
+      encoder: Some(Encoder::new(Encoding::Gzip)),
+    };
+
+    sender
+      .send(Bytes::from_static(b"{\"event\":\"ready\"}\n"))
+      .await
+      .unwrap();
+    let mut decoder = flate2::write::GzDecoder::new(Vec::new());
+    // The upstream keeps the response open, the line must still reach the client.
+    while decoder.get_ref().is_empty() {
+      let frame = tokio::time::timeout(std::time::Duration::from_secs(1), body.frame())
+        .await
+        .expect("compressed line held back")
+        .unwrap()
+        .unwrap();
+      decoder.write_all(&frame.into_data().unwrap()).unwrap();
+      decoder.flush().unwrap();
+    }
+    assert_eq!(decoder.get_ref().as_slice(), b"{\"event\":\"ready\"}\n");
+  }
+}
@@ -0,0 +1,167 @@
+use crate::service::error_page::ERROR_PAGE_NAMES;
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+/// Built-in page used when neither the service nor the cluster has one.
+const DEFAULT_PAGE: &str = include_str!("error_page.html");
+
+/// Why the proxy couldn't serve a request from the upstream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyError {
+  /// No ingress for the host.
+  NotFound,
+  /// The upstream refused the connection or sent an invalid response.
+  BadGateway,
+  /// The service has no running deployment to route to.
+  ServiceUnavailable,
+  /// The upstream didn't respond in time.
+  GatewayTimeout,
+  /// The service is in maintenance mode.
+  Maintenance,
+}
+
+impl ProxyError {
+  fn status(&self) -> StatusCode {
+    match self {
+      ProxyError::NotFound => StatusCode::NOT_FOUND,
+      ProxyError::BadGateway => StatusCode::BAD_GATEWAY,
+      ProxyError::ServiceUnavailable | ProxyError::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+      ProxyError::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+    }
+  }
+
+  /// The page name, also used as file name in the cluster error pages directory.
+  fn page_name(&self) -> &'static str {
+    match self {
+      ProxyError::NotFound => "404",
+      ProxyError::BadGateway => "502",
+      ProxyError::ServiceUnavailable => "503",
+      ProxyError::GatewayTimeout => "504",
+      ProxyError::Maintenance => "maintenance",
+    }
+  }
+
+  fn message(&self) -> &'static str {
+    match self {
+      ProxyError::NotFound => "There is nothing deployed at this address.",
+      ProxyError::BadGateway => "The app sent an invalid response or refused the connection.",
+      ProxyError::ServiceUnavailable => "The app isn't running right now.",
+      ProxyError::GatewayTimeout => "The app took too long to respond.",
+      ProxyError::Maintenance => "The app is down for maintenance, please check back soon.",
+    }
+  }
+
+  /// 504 when the upstream timed out, 502 for any other failure.
+  pub fn from_upstream(error: &hyper_util::client::legacy::Error) -> Self {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(error) = source {
+      if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+        if io_error.kind() == std::io::ErrorKind::TimedOut {
+          return ProxyError::GatewayTimeout;
+        }
+      }
+      source = error.source();
+    }
+    ProxyError::BadGateway
+  }
+
+  /// Renders the service page if it has one, then the cluster page, then the built-in one.
+  pub fn into_response(self, service_pages: Option<&HashMap<String, String>>) -> Response {
+    let html = service_pages
+      .and_then(|pages| pages.get(self.page_name()).cloned())
+      .or_else(|| ERROR_PAGES.get(self.page_name()))
+      .unwrap_or_else(|| self.default_page());
+    (
+      self.status(),
+      [
+        (CONTENT_TYPE, "text/html; charset=utf-8"),
+        (CACHE_CONTROL, "no-store"),
+      ],
+      html,
+    )
+      .into_response()
+  }
+
+  fn default_page(&self) -> String {
+    let status = self.status();
+    DEFAULT_PAGE
+      .replace("{code}", &status.as_u16().to_string())
+      .replace("{reason}", status.canonical_reason().unwrap_or_default())
+      .replace("{message}", self.message())
+  }
+}
+
+/// Cluster wide error pages, loaded from `ERROR_PAGES_DIR` when the proxy starts.
+pub struct ErrorPages {
+  pages: RwLock<HashMap<String, String>>,
+}
+
+pub static ERROR_PAGES: Lazy<ErrorPages> = Lazy::new(|| ErrorPages {
+  pages: RwLock::new(HashMap::new()),
+});
+
+impl ErrorPages {
+  fn get(&self, name: &str) -> Option<String> {
+    self.pages.read().unwrap().get(name).cloned()
+  }
+
+  pub async fn load(&self, dir: &Path) {
+    let mut pages = HashMap::new();
+    for name in ERROR_PAGE_NAMES {
+      let path = dir.join(format!("{}.html", name));
+      match tokio::fs::read_to_string(&path).await {
+        Ok(html) => {
+          pages.insert(name.to_string(), html);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to read error page {:?}: {}", path, e),
+      }
+    }
+    info!("Loaded {} cluster error pages", pages.len());
+    *self.pages.write().unwrap() = pages;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn html(response: Response) -> String {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+      .await
+      .unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn service_pages_override_the_built_in_page() {
+    let response = ProxyError::Maintenance.into_response(None);
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[CACHE_CONTROL], "no-store");
+    let page = html(response).await;
+    assert!(page.contains("503"));
+    assert!(page.contains(ProxyError::Maintenance.message()));
+    assert!(!page.contains("{message}"));
+
+    let service_pages = HashMap::from([(
+      "maintenance".to_string(),
+      "<h1>Back at noon</h1>".to_string(),
+    )]);
+    let response = ProxyError::Maintenance.into_response(Some(&service_pages));
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(html(response).await, "<h1>Back at noon</h1>");
+
+    // Pages only replace the error they're named after.
+    let response = ProxyError::GatewayTimeout.into_response(Some(&service_pages));
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert!(html(response)
+      .await
+      .contains(ProxyError::GatewayTimeout.message()));
+  }
+}
@@ -0,0 +1,297 @@
+use crate::ingress::Ingress;
+use axum::http::header::{
+  CONNECTION, FORWARDED, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING,
+  UPGRADE,
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+
+/// The proxy only accepts TLS connections.
+const PROTO: &str = "https";
+
+#[derive(Clone, Copy, Debug)]
+pub struct ForwardingPolicy {
+  /// Add forwarding headers to the upstream request.
+  pub enabled: bool,
+  /// Keep forwarding headers sent by the client and append to them.
+  pub trust_inbound: bool,
+}
+
+impl From<&Ingress> for ForwardingPolicy {
+  fn from(ingress: &Ingress) -> Self {
+    Self {
+      enabled: ingress.forwarded_headers,
+      trust_inbound: ingress.trust_forwarded_headers,
+    }
+  }
+}
+
+/// Removes hop-by-hop headers (RFC 9110 Section 7.6.1), including any listed in `Connection`.
+///
+/// When `keep_upgrade` is set, `Connection: upgrade` and `Upgrade` are preserved so the
+/// protocol switch can reach the upstream.
+pub fn strip_hop_by_hop_headers(headers: &mut HeaderMap, keep_upgrade: bool) {
+  let connection_headers: Vec<HeaderName> = headers
+    .get_all(CONNECTION)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .filter_map(|token| HeaderName::from_bytes(token.trim().as_bytes()).ok())
+    .collect();
+  for name in connection_headers {
+    if !(keep_upgrade && name == UPGRADE) {
+      headers.remove(name);
+    }
+  }
+  for name in [
+    CONNECTION,
+    KEEP_ALIVE,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+  ] {
+    headers.remove(name);
+  }
+  if keep_upgrade {
+    headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+  } else {
+    headers.remove(UPGRADE);
+  }
+}
+
+/// Sets `Forwarded` (RFC 7239), `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host`
+/// and `X-Real-IP` for the upstream request.
+///
+/// Inbound values are dropped unless the policy trusts them, so clients can't spoof their IP.
+pub fn apply_forwarded_headers(
+  headers: &mut HeaderMap,
+  policy: ForwardingPolicy,
+  client_ip: IpAddr,
+  host: &str,
+) {
+  if !policy.trust_inbound {
+    for name in [
+      FORWARDED,
+      X_FORWARDED_FOR,
+      X_FORWARDED_PROTO,
+      X_FORWARDED_HOST,
+      X_REAL_IP,
+    ] {
+      headers.remove(name);
+    }
+  }
+  if !policy.enabled {
+    return;
+  }
+
+  let client = client_ip.to_string();
+  append(headers, X_FORWARDED_FOR, &client);
+  let forwarded = format!(
+    "for={};proto={};host={}",
+    forwarded_node(client_ip),
+    PROTO,
+    quote_if_needed(host)
+  );
+  append(headers, FORWARDED, &forwarded);
+  insert_if_absent(headers, X_FORWARDED_PROTO, PROTO);
+  insert_if_absent(headers, X_FORWARDED_HOST, host);
+  insert_if_absent(headers, X_REAL_IP, &client);
+}
+
+/// The originating client IP, read from `X-Forwarded-For` only when the policy trusts it and
+/// the connection comes from one of `trusted_proxies`, otherwise anyone could set the header.
+///
+/// Entries are walked from the right, where the trusted hop appended the address it saw,
+/// skipping `trusted_proxies`. Anything further left was sent by the client and can be forged.
+pub fn client_ip(
+  headers: &HeaderMap,
+  policy: ForwardingPolicy,
+  trusted_proxies: &[IpNet],
+  peer_ip: IpAddr,
+) -> IpAddr {
+  if !policy.trust_inbound || !is_trusted(trusted_proxies, peer_ip) {
+    return peer_ip;
+  }
+  let entries: Vec<&str> = headers
+    .get_all(X_FORWARDED_FOR)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .collect();
+  let mut client_ip = peer_ip;
+  for entry in entries.into_iter().rev() {
+    let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+      break;
+    };
+    client_ip = ip;
+    if !is_trusted(trusted_proxies, ip) {
+      break;
+    }
+  }
+  client_ip
+}
+
+fn is_trusted(trusted_proxies: &[IpNet], ip: IpAddr) -> bool {
+  trusted_proxies.iter().any(|network| network.contains(&ip))
+}
+
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+  let value = match headers.get(&name).and_then(|v| v.to_str().ok()) {
+    Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, value),
+    _ => value.to_string(),
+  };
+  if let Ok(value) = HeaderValue::from_str(&value) {
+    headers.insert(name, value);
+  }
+}
+
+fn insert_if_absent(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+  if headers.contains_key(&name) {
+    return;
+  }
+  if let Ok(value) = HeaderValue::from_str(value) {
+    headers.insert(name, value);
+  }
+}
+
+/// IPv6 nodes must be bracketed and quoted, e.g. `for="[2001:db8::1]"`.
+fn forwarded_node(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+  }
+}
+
+fn quote_if_needed(value: &str) -> String {
+  let is_token = value
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+  if is_token {
+    value.to_string()
+  } else {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+  fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+      headers.append(*name, HeaderValue::from_static(value));
+    }
+    headers
+  }
+
+  #[test]
+  fn untrusted_inbound_headers_are_replaced() {
+    let mut headers = headers(&[
+      ("x-forwarded-for", "10.0.0.1"),
+      ("x-real-ip", "10.0.0.1"),
+      ("forwarded", "for=10.0.0.1"),
+    ]);
+    let policy = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: false,
+    };
+    apply_forwarded_headers(&mut headers, policy, CLIENT, "app.example.com");
+    assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+    assert_eq!(headers["x-real-ip"], "203.0.113.7");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+    assert_eq!(headers["x-forwarded-host"], "app.example.com");
+    assert_eq!(
+      headers["forwarded"],
+      "for=203.0.113.7;proto=https;host=app.example.com"
+    );
+  }
+
+  #[test]
+  fn trusted_inbound_headers_are_appended() {
+    let mut headers = headers(&[("x-forwarded-for", "10.0.0.1"), ("x-real-ip", "10.0.0.1")]);
+    let policy = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: true,
+    };
+    let client = "2001:db8::1".parse().unwrap();
+    apply_forwarded_headers(&mut headers, policy, client, "app.example.com:8443");
+    assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 2001:db8::1");
+    assert_eq!(headers["x-real-ip"], "10.0.0.1");
+    assert_eq!(
+      headers["forwarded"],
+      "for=\"[2001:db8::1]\";proto=https;host=\"app.example.com:8443\""
+    );
+  }
+
+  #[test]
+  fn client_ip_ignores_spoofed_leftmost_entries() {
+    let policy = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: true,
+    };
+    let peer = "198.51.100.2".parse().unwrap();
+    let trusted_proxies = ["198.51.100.0/24".parse().unwrap()];
+    let spoofed = headers(&[("x-forwarded-for", "10.0.0.1, 203.0.113.7")]);
+    assert_eq!(client_ip(&spoofed, policy, &trusted_proxies, peer), CLIENT);
+
+    let chained = headers(&[
+      ("x-forwarded-for", "10.0.0.1, 203.0.113.7"),
+      ("x-forwarded-for", "198.51.100.9"),
+    ]);
+    assert_eq!(client_ip(&chained, policy, &trusted_proxies, peer), CLIENT);
+
+    let untrusted = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: false,
+    };
+    assert_eq!(client_ip(&spoofed, untrusted, &trusted_proxies, peer), peer);
+  }
+
+  #[test]
+  fn client_ip_ignores_forwarded_for_from_untrusted_peers() {
+    let policy = ForwardingPolicy {
+      enabled: true,
+      trust_inbound: true,
+    };
+    let forged = headers(&[("x-forwarded-for", "10.0.0.1")]);
+    let trusted_proxies = ["198.51.100.0/24".parse().unwrap()];
+    // A client connecting directly claims an address, there is no proxy to vouch for it.
+    assert_eq!(client_ip(&forged, policy, &trusted_proxies, CLIENT), CLIENT);
+    assert_eq!(client_ip(&forged, policy, &[], CLIENT), CLIENT);
+  }
+
+  #[test]
+  fn hop_by_hop_headers_are_stripped() {
+    let mut headers = headers(&[
+      ("connection", "keep-alive, x-custom, Upgrade"),
+      ("keep-alive", "timeout=5"),
+      ("x-custom", "1"),
+      ("upgrade", "websocket"),
+      ("te", "trailers"),
+      ("accept", "*/*"),
+    ]);
+    strip_hop_by_hop_headers(&mut headers, true);
+    assert_eq!(headers["connection"], "upgrade");
+    assert_eq!(headers["upgrade"], "websocket");
+    assert!(!headers.contains_key("keep-alive"));
+    assert!(!headers.contains_key("x-custom"));
+    assert!(!headers.contains_key("te"));
+    assert!(headers.contains_key("accept"));
+
+    strip_hop_by_hop_headers(&mut headers, false);
+    assert!(!headers.contains_key("connection"));
+    assert!(!headers.contains_key("upgrade"));
+  }
+}
@@ -0,0 +1,314 @@
+mod acceptor;
+mod access;
+mod compression;
+mod error_page;
+mod forwarding;
+mod rate_limit;
+mod routing;
+mod upgrade;
+mod upstream;
+
+use crate::access_log::{AccessLog, AccessLogTarget};
+use crate::certificate::authority::is_internal_name;
+use crate::certificate::cache::{wildcard_name, Lookup, CERTIFICATE_CACHE};
+use crate::certificate::Certificate;
+use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::deployment::Deployment;
+use crate::http::proxy::acceptor::MetricsAcceptor;
+use crate::http::proxy::access::AccessDenied;
+use crate::http::proxy::error_page::{ProxyError, ERROR_PAGES};
+use crate::http::proxy::forwarding::ForwardingPolicy;
+use crate::http::proxy::rate_limit::RATE_LIMITER;
+use crate::http::proxy::routing::{RoutingTable, ROUTING_TABLE};
+use crate::http::proxy::upstream::{Upstream, UpstreamPolicy};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::{middleware, Extension, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use once_cell::sync::Lazy;
+use rustls::server::ClientHello;
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use sqlx::{Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error, info};
+
+pub struct Proxy;
+
+impl Proxy {
+  pub async fn start_server(
+    config: &'static Config,
+    shared_pool: &Arc<Pool<Postgres>>,
+  ) -> anyhow::Result<()> {
+    if let Err(e) = CERTIFICATE_CACHE.warm(shared_pool).await {
+      error!("Failed to warm certificate cache: {}", e);
+    }
+    CERTIFICATE_CACHE.start_sweeper();
+    ERROR_PAGES.load(&config.error_pages_dir).await;
+    RoutingTable::start_listener(shared_pool).await?;
+    Deployment::start_last_accessed_flusher(shared_pool).await?;
+
+    let cert_resolver = DatabaseCertResolver::new(Arc::clone(shared_pool));
+
+    let server_config = ServerConfig::builder()
+      .with_no_client_auth()
+      .with_cert_resolver(Arc::new(cert_resolver));
+
+    let tls_config = RustlsConfig::from_config(Arc::new(server_config));
+
+    let app = Router::new()
+      .route("/", any(Self::handler))
+      .route("/*path", any(Self::handler))
+      .layer(middleware::from_fn(AccessLog::middleware))
+      .layer(Extension(Arc::clone(shared_pool)))
+      .layer(Extension(config));
+
+    let listener = std::net::TcpListener::bind(config.proxy_address())?;
+    tokio::spawn(async move {
+      info!(
+        "DoseiD Proxy Server running on http://{}",
+        &config.proxy_address()
+      );
+      axum_server::from_tcp_rustls(listener, tls_config)
+        .map(MetricsAcceptor::new)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .expect("Failed start Doseid Proxy Server");
+    });
+    Ok(())
+  }
+
+  async fn handler(
+    Extension(config): Extension<&'static Config>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    mut req: Request,
+  ) -> Result<Response, StatusCode> {
+    let headers = req.headers();
+    let host = match headers.get("host") {
+      Some(host_header) => host_header.to_str().unwrap_or_default(),
+      None => return Err(StatusCode::NOT_FOUND),
+    };
+    debug!("Received request for host: {}", host);
+    let path = req.uri().path();
+    let path_query = req
+      .uri()
+      .path_and_query()
+      .map(|v| v.as_str())
+      .unwrap_or(path);
+
+    let Some(route) = ROUTING_TABLE.get(host) else {
+      return Ok(ProxyError::NotFound.into_response(None));
+    };
+    let error_pages = Some(&route.error_pages);
+    let forwarding_policy = ForwardingPolicy::from(&route.ingress);
+    let client_ip = forwarding::client_ip(
+      headers,
+      forwarding_policy,
+      &config.trusted_proxies,
+      client_addr.ip(),
+    );
+    let mut access_log_target = AccessLogTarget {
+      service_id: route.ingress.service_id,
+      deployment_id: None,
+      upstream_latency: None,
+      client_ip,
+    };
+    let upstreams: Vec<Upstream> = route
+      .deployments
+      .iter()
+      .filter_map(|deployment| {
+        deployment.upstream_authority().map(|authority| Upstream {
+          deployment_id: deployment.id,
+          authority,
+        })
+      })
+      .collect();
+    let unavailable = if route.maintenance {
+      Some(ProxyError::Maintenance)
+    } else if upstreams.is_empty() {
+      Some(ProxyError::ServiceUnavailable)
+    } else {
+      None
+    };
+    if let Some(proxy_error) = unavailable {
+      let mut response = proxy_error.into_response(error_pages);
+      response.extensions_mut().insert(access_log_target);
+      return Ok(response);
+    }
+
+    if let Some(retry_after) = RATE_LIMITER.check(&route.rate_limits, path, headers, client_ip) {
+      debug!("Rate limited {} for {}", client_ip, host);
+      let mut response = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::RETRY_AFTER, retry_after.as_secs_f64().ceil() as u64)
+        .body(Body::empty())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+      response.extensions_mut().insert(access_log_target);
+      return Ok(response);
+    }
+    if let Err(denied) = route.access_policy.check(headers, client_ip).await {
+      let mut response = match denied {
+        AccessDenied::Forbidden => StatusCode::FORBIDDEN.into_response(),
+        AccessDenied::Unauthorized => (
+          StatusCode::UNAUTHORIZED,
+          [(
+            header::WWW_AUTHENTICATE,
+            format!("Basic realm=\"{}\", charset=\"UTF-8\"", host),
+          )],
+        )
+          .into_response(),
+      };
+      response.extensions_mut().insert(access_log_target);
+      return Ok(response);
+    }
+
+    debug!("Forwarding: {}{}", host, path_query);
+    let host = host.to_string();
+    let is_upgrade = upgrade::is_upgrade_request(&req);
+    let encoding = compression::negotiate(req.headers());
+    let method = req.method().clone();
+    let headers = req.headers_mut();
+    forwarding::strip_hop_by_hop_headers(headers, is_upgrade);
+    if route.access_policy.requires_auth() {
+      // The credentials are for the proxy, not the app.
+      headers.remove(header::AUTHORIZATION);
+    }
+    forwarding::apply_forwarded_headers(headers, forwarding_policy, client_addr.ip(), &host);
+    let client_upgrade = is_upgrade.then(|| hyper::upgrade::on(&mut req));
+    let upstream_policy = UpstreamPolicy::from(&route.ingress);
+    let upstream_started_at = Instant::now();
+    let upstream_response = upstream::send(req, &upstreams, upstream_policy, is_upgrade).await;
+    access_log_target.upstream_latency = Some(upstream_started_at.elapsed());
+    let mut response = match upstream_response {
+      Ok((deployment_id, mut response)) => {
+        access_log_target.deployment_id = Some(deployment_id);
+        if let Some(deployment) = route.deployments.iter().find(|d| d.id == deployment_id) {
+          deployment.update_last_accessed();
+        }
+        if let Some(client_upgrade) = client_upgrade {
+          if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            upgrade::tunnel(host, client_upgrade, upstream_upgrade);
+          }
+        }
+        match encoding {
+          Some(encoding) => {
+            compression::compress(response.into_response(), encoding, &method, &route.ingress)
+          }
+          None => response.into_response(),
+        }
+      }
+      Err(proxy_error) => proxy_error.into_response(error_pages),
+    };
+    response.extensions_mut().insert(access_log_target);
+    Ok(response)
+  }
+}
+
+/// Serializes internal issuance, a burst of first handshakes would otherwise each store a leaf.
+static INTERNAL_ISSUE_LOCK: Lazy<tokio::sync::Mutex<()>> =
+  Lazy::new(|| tokio::sync::Mutex::new(()));
+
+#[derive(Debug)]
+struct DatabaseCertResolver {
+  pool: Arc<Pool<Postgres>>,
+}
+
+impl DatabaseCertResolver {
+  fn new(pool: Arc<Pool<Postgres>>) -> Self {
+    Self { pool }
+  }
+}
+
+impl DatabaseCertResolver {
+  fn load(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+    match CERTIFICATE_CACHE.get(domain) {
+      Lookup::Found(certified_key) => return Some(certified_key),
+      Lookup::NotFound => return None,
+      Lookup::Unknown => {}
+    }
+
+    info!("Loading certificate for: {}", domain);
+
+    let pool = self.pool.clone();
+    let domain_clone = domain.to_string();
+    let db_cert = tokio::task::block_in_place(move || {
+      let rt = tokio::runtime::Handle::current();
+      rt.block_on(async { Certificate::get_by_domain_name(domain_clone, &pool).await })
+    })
+    .ok()?;
+
+    let certified_key = match db_cert.map(|db_cert| db_cert.certified_key()) {
+      Some(Ok(certified_key)) => Some(Arc::new(certified_key)),
+      Some(Err(e)) => {
+        error!("Failed to load certificate for {}: {}", domain, e);
+        None
+      }
+      None => None,
+    };
+    if certified_key.is_some() {
+      info!("Successfully loaded certificate for {}", domain);
+    }
+    CERTIFICATE_CACHE.insert(domain, certified_key.clone());
+    certified_key
+  }
+
+  /// Issues a certificate of the internal CA for names routed to a service, e.g. `localhost`.
+  /// The route is read from memory, and concurrent handshakes for a name issue it once.
+  fn issue_internal(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+    if !is_internal_name(domain) {
+      return None;
+    }
+    let route = ROUTING_TABLE.get(domain)?;
+    let pool = self.pool.clone();
+    let domain_clone = domain.to_string();
+    let issued = tokio::task::block_in_place(move || {
+      let rt = tokio::runtime::Handle::current();
+      rt.block_on(async {
+        let _guard = INTERNAL_ISSUE_LOCK.lock().await;
+        if let Lookup::Found(certified_key) = CERTIFICATE_CACHE.get(&domain_clone) {
+          return anyhow::Ok((certified_key, false));
+        }
+        let certificate =
+          Certificate::issue_internal(&domain_clone, route.ingress.owner_id, &pool).await?;
+        let certified_key = Arc::new(certificate.certified_key()?);
+        CERTIFICATE_CACHE.insert(&domain_clone, Some(Arc::clone(&certified_key)));
+        anyhow::Ok((certified_key, true))
+      })
+    });
+    match issued {
+      Ok((certified_key, created)) => {
+        if created {
+          info!("Issued internal certificate for {}", domain);
+        }
+        Some(certified_key)
+      }
+      Err(e) => {
+        error!("Failed to issue internal certificate for {}: {}", domain, e);
+        None
+      }
+    }
+  }
+}
+
+impl rustls::server::ResolvesServerCert for DatabaseCertResolver {
+  fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+    // Clients don't send SNI for IP addresses, those get the certificate of the cluster name.
+    let domain = match client_hello.server_name() {
+      Some(server_name) => server_name.to_string(),
+      None => tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(async { Cluster::get().await.name })
+      }),
+    };
+    self
+      .load(&domain)
+      .or_else(|| wildcard_name(&domain).and_then(|wildcard| self.load(&wildcard)))
+      .or_else(|| self.issue_internal(&domain))
+  }
+}
@@ -0,0 +1,166 @@
+use crate::ingress::rate_limit::IngressRateLimit;
+use axum::http::HeaderMap;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How often buckets which refilled completely are dropped.
+const SWEEP_SPAN: Duration = Duration::from_secs(60);
+/// Buckets are keyed by client IPs or headers, the least recently used ones are dropped
+/// past this.
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+  /// When the bucket is full again, after which it's the same as a missing one.
+  full_at: Instant,
+}
+
+impl Bucket {
+  fn new(burst: f64, now: Instant) -> Self {
+    Self {
+      tokens: burst,
+      updated_at: now,
+      full_at: now,
+    }
+  }
+
+  /// Refills the bucket and takes a token, or returns how long until one is available.
+  fn take(&mut self, rate: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+    let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * rate).min(burst);
+    self.updated_at = now;
+    let result = if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      Ok(())
+    } else {
+      Err(seconds((1.0 - self.tokens) / rate))
+    };
+    // Out of range means it won't be full any time soon, the bucket is kept until it's idle.
+    if let Some(full_at) = now.checked_add(seconds((burst - self.tokens) / rate)) {
+      self.full_at = full_at;
+    }
+    result
+  }
+}
+
+/// Saturates instead of panicking on huge or non-finite waits.
+fn seconds(value: f64) -> Duration {
+  Duration::try_from_secs_f64(value).unwrap_or(Duration::MAX)
+}
+
+/// Drops full buckets and, if that isn't enough, the least recently used half.
+fn make_room(buckets: &mut HashMap<(Uuid, String), Bucket>, now: Instant, max: usize) {
+  buckets.retain(|_, bucket| bucket.full_at > now);
+  if buckets.len() < max {
+    return;
+  }
+  let mut updated_at: Vec<Instant> = buckets.values().map(|bucket| bucket.updated_at).collect();
+  let (_, median, _) = updated_at.select_nth_unstable(buckets.len() / 2);
+  let median = *median;
+  buckets.retain(|_, bucket| bucket.updated_at > median);
+}
+
+/// Token buckets keyed by rate limit and client.
+pub struct RateLimiter {
+  buckets: Mutex<HashMap<(Uuid, String), Bucket>>,
+  swept_at: Mutex<Instant>,
+}
+
+pub static RATE_LIMITER: Lazy<RateLimiter> = Lazy::new(|| RateLimiter {
+  buckets: Mutex::new(HashMap::new()),
+  swept_at: Mutex::new(Instant::now()),
+});
+
+impl RateLimiter {
+  /// Takes a token from every limit matching the path, returns the longest wait if any is empty.
+  pub fn check(
+    &self,
+    rate_limits: &[IngressRateLimit],
+    path: &str,
+    headers: &HeaderMap,
+    client_ip: IpAddr,
+  ) -> Option<Duration> {
+    let now = Instant::now();
+    self.sweep(now);
+    let mut buckets = self.buckets.lock().unwrap();
+    let mut retry_after = None;
+    for rate_limit in rate_limits.iter().filter(|limit| limit.matches(path)) {
+      let key = rate_limit
+        .key_header
+        .as_ref()
+        .and_then(|header| headers.get(header.as_str()))
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| client_ip.to_string());
+      let burst = rate_limit.burst as f64;
+      if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&(rate_limit.id, key.clone())) {
+        make_room(&mut buckets, now, MAX_BUCKETS);
+      }
+      let bucket = buckets
+        .entry((rate_limit.id, key))
+        .or_insert_with(|| Bucket::new(burst, now));
+      if let Err(wait) = bucket.take(rate_limit.requests_per_second, burst, now) {
+        retry_after = retry_after.max(Some(wait));
+      }
+    }
+    retry_after
+  }
+
+  fn sweep(&self, now: Instant) {
+    let mut swept_at = self.swept_at.lock().unwrap();
+    if now.saturating_duration_since(*swept_at) < SWEEP_SPAN {
+      return;
+    }
+    *swept_at = now;
+    self
+      .buckets
+      .lock()
+      .unwrap()
+      .retain(|_, bucket| bucket.full_at > now);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bucket_allows_burst_then_waits_for_refill() {
+    let now = Instant::now();
+    let mut bucket = Bucket::new(2.0, now);
+    assert!(bucket.take(0.5, 2.0, now).is_ok());
+    assert!(bucket.take(0.5, 2.0, now).is_ok());
+    assert_eq!(bucket.take(0.5, 2.0, now), Err(Duration::from_secs(2)));
+    assert!(bucket.take(0.5, 2.0, now + Duration::from_secs(2)).is_ok());
+  }
+
+  #[test]
+  fn tiny_rates_saturate_instead_of_panicking() {
+    let now = Instant::now();
+    let mut bucket = Bucket::new(1.0, now);
+    assert!(bucket.take(f64::MIN_POSITIVE, 1.0, now).is_ok());
+    assert_eq!(bucket.take(f64::MIN_POSITIVE, 1.0, now), Err(Duration::MAX));
+  }
+
+  #[test]
+  fn make_room_drops_least_recently_used_buckets() {
+    let now = Instant::now();
+    let mut buckets = HashMap::new();
+    for i in 0..4u64 {
+      let updated_at = now + Duration::from_secs(i);
+      let mut bucket = Bucket::new(1.0, updated_at);
+      bucket.take(0.001, 1.0, updated_at).unwrap();
+      buckets.insert((Uuid::nil(), i.to_string()), bucket);
+    }
+    make_room(&mut buckets, now, 4);
+    let mut kept: Vec<_> = buckets.keys().map(|(_, key)| key.as_str()).collect();
+    kept.sort();
+    assert_eq!(kept, ["3"]);
+  }
+}
@@ -0,0 +1,206 @@
+use crate::deployment::Deployment;
+use crate::http::proxy::access::AccessPolicy;
+use crate::http::proxy::upstream;
+use crate::ingress::rate_limit::IngressRateLimit;
+use crate::ingress::Ingress;
+use crate::service::error_page::ServiceErrorPage;
+use crate::service::Service;
+use once_cell::sync::Lazy;
+use sqlx::postgres::PgListener;
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::{interval, sleep};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Postgres channel notified by the triggers on the tables the routes are built from.
+const ROUTING_CHANNEL: &str = "routing_change";
+/// Full reload in case a notification was missed.
+const REFRESH_SPAN: u64 = 300; // 5 minutes
+const RECONNECT_SPAN: u64 = 5;
+
+pub struct Route {
+  pub ingress: Ingress,
+  /// The latest deployment of the ingress service first, then its other running deployments
+  /// that failed requests are retried on.
+  pub deployments: Vec<Deployment>,
+  pub rate_limits: Vec<IngressRateLimit>,
+  pub access_policy: AccessPolicy,
+  pub maintenance: bool,
+  /// Error pages of the ingress service, keyed by page name.
+  pub error_pages: HashMap<String, String>,
+}
+
+/// Host to deployment routes served from memory, so proxied requests don't query Postgres.
+pub struct RoutingTable {
+  routes: RwLock<HashMap<String, Arc<Route>>>,
+}
+
+pub static ROUTING_TABLE: Lazy<RoutingTable> = Lazy::new(|| RoutingTable {
+  routes: RwLock::new(HashMap::new()),
+});
+
+impl RoutingTable {
+  pub fn get(&self, host: &str) -> Option<Arc<Route>> {
+    self.routes.read().unwrap().get(host).cloned()
+  }
+
+  pub async fn refresh(&self, pg_pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let ingresses = Ingress::get_all(pg_pool).await?;
+    let deployments = service_deployments(
+      Deployment::get_latest_per_service(pg_pool).await?,
+      Deployment::get_running(pg_pool).await?,
+    );
+    let deployment_ids: HashSet<Uuid> = deployments
+      .values()
+      .flatten()
+      .map(|deployment| deployment.id)
+      .collect();
+    let mut rate_limits: HashMap<_, Vec<_>> = HashMap::new();
+    for rate_limit in IngressRateLimit::get_all(pg_pool).await? {
+      rate_limits
+        .entry(rate_limit.ingress_id)
+        .or_default()
+        .push(rate_limit);
+    }
+
+    let maintenance: HashMap<_, _> = Service::get_all(pg_pool)
+      .await?
+      .into_iter()
+      .map(|service| (service.id, service.maintenance))
+      .collect();
+    let mut error_pages: HashMap<_, HashMap<_, _>> = HashMap::new();
+    for error_page in ServiceErrorPage::get_all(pg_pool).await? {
+      error_pages
+        .entry(error_page.service_id)
+        .or_default()
+        .insert(error_page.name, error_page.html);
+    }
+
+    let routes: HashMap<String, Arc<Route>> = ingresses
+      .into_iter()
+      .map(|ingress| {
+        let deployments = deployments
+          .get(&ingress.service_id)
+          .cloned()
+          .unwrap_or_default();
+        let rate_limits = rate_limits.remove(&ingress.id).unwrap_or_default();
+        let access_policy = AccessPolicy::from(&ingress);
+        let maintenance = maintenance
+          .get(&ingress.service_id)
+          .copied()
+          .unwrap_or_default();
+        let error_pages = error_pages
+          .get(&ingress.service_id)
+          .cloned()
+          .unwrap_or_default();
+        (
+          ingress.host.clone(),
+          Arc::new(Route {
+            ingress,
+            deployments,
+            rate_limits,
+            access_policy,
+            maintenance,
+            error_pages,
+          }),
+        )
+      })
+      .collect();
+
+    debug!("Routing table refreshed with {} hosts", routes.len());
+    *self.routes.write().unwrap() = routes;
+    upstream::retain_circuits(&deployment_ids);
+    Ok(())
+  }
+
+  /// Loads the routing table and keeps it up to date from Postgres notifications.
+  pub async fn start_listener(pg_pool: &Arc<Pool<Postgres>>) -> anyhow::Result<()> {
+    ROUTING_TABLE.refresh(pg_pool).await?;
+    let mut listener = PgListener::connect_with(pg_pool).await?;
+    listener.listen(ROUTING_CHANNEL).await?;
+
+    let pool = Arc::clone(pg_pool);
+    tokio::spawn(async move {
+      info!("DoseiD Routing Table Listener Running");
+      let mut interval = interval(Duration::from_secs(REFRESH_SPAN));
+      loop {
+        tokio::select! {
+          notification = listener.try_recv() => match notification {
+            Ok(Some(notification)) => {
+              debug!("Routing change on table: {}", notification.payload());
+            }
+            Ok(None) => warn!("Routing listener connection lost, reconnecting"),
+            Err(e) => {
+              error!("Routing listener failed: {}", e);
+              sleep(Duration::from_secs(RECONNECT_SPAN)).await;
+            }
+          },
+          _ = interval.tick() => {}
+        }
+        if let Err(e) = ROUTING_TABLE.refresh(&pool).await {
+          error!("Failed to refresh routing table: {}", e);
+        }
+      }
+    });
+    Ok(())
+  }
+}
+
+/// Deployments per service in [`Route::deployments`] order, the latest one first.
+fn service_deployments(
+  latest: Vec<Deployment>,
+  running: Vec<Deployment>,
+) -> HashMap<Uuid, Vec<Deployment>> {
+  let mut deployments: HashMap<_, Vec<_>> = HashMap::new();
+  for deployment in latest {
+    deployments.insert(deployment.service_id, vec![deployment]);
+  }
+  for deployment in running {
+    let service_deployments = deployments.entry(deployment.service_id).or_default();
+    if !service_deployments.iter().any(|d| d.id == deployment.id) {
+      service_deployments.push(deployment);
+    }
+  }
+  deployments
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+
+  fn deployment(service_id: Uuid) -> Deployment {
+    Deployment {
+      id: Uuid::new_v4(),
+      service_id,
+      owner_id: Uuid::nil(),
+      host_port: None,
+      container_port: Some(8080),
+      container_ip: Some("10.213.0.2".to_string()),
+      status: "running".to_string(),
+      last_accessed_at: None,
+      updated_at: Utc::now(),
+      created_at: Utc::now(),
+    }
+  }
+
+  #[test]
+  fn latest_deployment_routes_first_then_other_running_ones() {
+    let service_id = Uuid::new_v4();
+    let latest = deployment(service_id);
+    let previous = deployment(service_id);
+    let other_service = deployment(Uuid::new_v4());
+    let running = vec![previous.clone(), latest.clone(), other_service.clone()];
+
+    let deployments = service_deployments(vec![latest.clone()], running);
+    let ids: Vec<Uuid> = deployments[&service_id].iter().map(|d| d.id).collect();
+    assert_eq!(ids, vec![latest.id, previous.id]);
+    assert_eq!(
+      deployments[&other_service.service_id][0].id,
+      other_service.id
+    );
+  }
+}
@@ -0,0 +1,44 @@
+use axum::extract::Request;
+use axum::http::{header, HeaderMap};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use tokio::io::copy_bidirectional;
+use tracing::{debug, error};
+
+/// Returns true when the request asks to switch protocols,
+/// e.g. `Connection: Upgrade` + `Upgrade: websocket`.
+pub fn is_upgrade_request(req: &Request) -> bool {
+  has_connection_upgrade(req.headers()) && req.headers().contains_key(header::UPGRADE)
+}
+
+fn has_connection_upgrade(headers: &HeaderMap) -> bool {
+  headers
+    .get_all(header::CONNECTION)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Waits for both sides of an upgraded connection and copies bytes between them
+/// until either side closes.
+pub fn tunnel(host: String, client_upgrade: OnUpgrade, upstream_upgrade: OnUpgrade) {
+  tokio::spawn(async move {
+    let (client, upstream) = match tokio::try_join!(client_upgrade, upstream_upgrade) {
+      Ok(upgraded) => upgraded,
+      Err(e) => {
+        error!("Upgrade failed for {}: {}", host, e);
+        return;
+      }
+    };
+    let mut client = TokioIo::new(client);
+    let mut upstream = TokioIo::new(upstream);
+    match copy_bidirectional(&mut client, &mut upstream).await {
+      Ok((from_client, from_upstream)) => debug!(
+        "Upgraded connection closed for {}: {} bytes sent, {} bytes received",
+        host, from_client, from_upstream
+      ),
+      Err(e) => debug!("Upgraded connection for {} ended with error: {}", host, e),
+    }
+  });
+}
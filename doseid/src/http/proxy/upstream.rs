@@ -0,0 +1,511 @@
+use crate::http::proxy::error_page::ProxyError;
+use crate::ingress::Ingress;
+use crate::metrics::METRICS;
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::{HeaderMap, Method, StatusCode, Uri, Version};
+use http_body::{Frame, SizeHint};
+use hyper::body::Incoming;
+use hyper::Response;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout, Sleep};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+/// Consecutive failures that open the circuit of a deployment.
+const CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+/// How long an open circuit fast-fails before letting a trial request through.
+const CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+/// Multiplied by the attempt number between retries.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Distinct connect timeouts that keep a client, each ingress can set its own.
+const MAX_CLIENTS: usize = 64;
+
+type Client = hyper_util::client::legacy::Client<HttpConnector, Body>;
+
+/// Clients keyed by connect timeout, which hyper only takes per connector.
+static CLIENTS: Lazy<Mutex<HashMap<Duration, Client>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn client(connect_timeout: Duration) -> Client {
+  let mut clients = CLIENTS.lock().unwrap();
+  if clients.len() >= MAX_CLIENTS && !clients.contains_key(&connect_timeout) {
+    // In-flight requests hold their own clone, dropping one only loses its idle connections.
+    if let Some(evicted) = clients.keys().next().copied() {
+      clients.remove(&evicted);
+    }
+  }
+  clients
+    .entry(connect_timeout)
+    .or_insert_with(|| {
+      let mut connector = HttpConnector::new();
+      connector.set_connect_timeout(Some(connect_timeout));
+      hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(connector)
+    })
+    .clone()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UpstreamPolicy {
+  pub connect_timeout: Duration,
+  /// Time until the upstream response headers, and between frames of the response body.
+  pub read_timeout: Duration,
+  pub retries: u32,
+}
+
+impl From<&Ingress> for UpstreamPolicy {
+  fn from(ingress: &Ingress) -> Self {
+    Self {
+      connect_timeout: Duration::from_millis(ingress.connect_timeout_ms.max(1) as u64),
+      read_timeout: Duration::from_millis(ingress.read_timeout_ms.max(1) as u64),
+      retries: ingress.retries.max(0) as u32,
+    }
+  }
+}
+
+/// What's needed to send a bodyless request again.
+struct RetryTemplate {
+  method: Method,
+  uri: Uri,
+  version: Version,
+  headers: HeaderMap,
+}
+
+impl RetryTemplate {
+  /// Only idempotent methods without a body are retried, the body can't be replayed.
+  fn from_request(request: &Request, is_upgrade: bool) -> Option<Self> {
+    let idempotent = matches!(
+      *request.method(),
+      Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    );
+    let bodyless = http_body::Body::size_hint(request.body()).exact() == Some(0);
+    (idempotent && bodyless && !is_upgrade).then(|| Self {
+      method: request.method().clone(),
+      uri: request.uri().clone(),
+      version: request.version(),
+      headers: request.headers().clone(),
+    })
+  }
+
+  fn build(&self) -> Request {
+    let mut request = Request::new(Body::empty());
+    *request.method_mut() = self.method.clone();
+    *request.uri_mut() = self.uri.clone();
+    *request.version_mut() = self.version;
+    *request.headers_mut() = self.headers.clone();
+    request
+  }
+}
+
+/// A deployment the proxy can forward to.
+pub struct Upstream {
+  pub deployment_id: Uuid,
+  /// `host:port`, see [`crate::deployment::Deployment::upstream_authority`].
+  pub authority: String,
+}
+
+/// Sends the request with the ingress timeouts, retrying failed idempotent requests on the
+/// next upstream whose circuit is closed. Returns the deployment that answered.
+pub async fn send(
+  request: Request,
+  upstreams: &[Upstream],
+  policy: UpstreamPolicy,
+  is_upgrade: bool,
+) -> Result<(Uuid, Response<Body>), ProxyError> {
+  let client = client(policy.connect_timeout);
+  let retry_template = RetryTemplate::from_request(&request, is_upgrade);
+  let mut request = Some(request);
+  let mut last_error = ProxyError::ServiceUnavailable;
+  let mut next = 0;
+  let mut attempt = 0;
+  loop {
+    let Some((index, permit)) = (0..upstreams.len()).find_map(|offset| {
+      let index = (next + offset) % upstreams.len();
+      CIRCUIT_BREAKERS
+        .allow(upstreams[index].deployment_id)
+        .map(|permit| (index, permit))
+    }) else {
+      return Err(last_error);
+    };
+    next = index + 1;
+    let upstream = &upstreams[index];
+    let mut attempt_request = match (request.take(), &retry_template) {
+      (Some(request), _) => request,
+      (None, Some(template)) => template.build(),
+      (None, None) => return Err(last_error),
+    };
+    let uri = upstream_uri(attempt_request.uri(), &upstream.authority)?;
+    debug!("Forwarding to {}", uri);
+    *attempt_request.uri_mut() = uri;
+
+    let result = match timeout(policy.read_timeout, client.request(attempt_request)).await {
+      Ok(Ok(response)) => Ok(response),
+      Ok(Err(e)) => {
+        error!(
+          "Upstream request to {} failed: {}",
+          upstream.deployment_id, e
+        );
+        Err(ProxyError::from_upstream(&e))
+      }
+      Err(_) => Err(ProxyError::GatewayTimeout),
+    };
+    if matches!(result, Err(ProxyError::GatewayTimeout)) {
+      METRICS.upstream_timeout();
+    }
+    permit.record(matches!(&result, Ok(response) if !is_unavailable(response.status())));
+    match (result, &retry_template) {
+      (Err(proxy_error), Some(_)) if attempt < policy.retries => {
+        attempt += 1;
+        last_error = proxy_error;
+        warn!(
+          "Retrying upstream request after {} failed, attempt {}",
+          upstream.deployment_id, attempt
+        );
+        METRICS.upstream_retry();
+        sleep(RETRY_BACKOFF * attempt).await;
+      }
+      // The tunnel takes over after the protocol switch, the body isn't read.
+      (Ok(response), _) if is_upgrade => {
+        return Ok((upstream.deployment_id, response.map(Body::new)));
+      }
+      (Ok(response), _) => {
+        let response = response.map(|body| {
+          Body::new(TimeoutBody {
+            inner: body,
+            read_timeout: policy.read_timeout,
+            deadline: Box::pin(sleep(policy.read_timeout)),
+          })
+        });
+        return Ok((upstream.deployment_id, response));
+      }
+      (Err(proxy_error), _) => return Err(proxy_error),
+    }
+  }
+}
+
+/// Gateway errors from the replica itself, e.g. a sidecar or app server that lost its backend.
+fn is_unavailable(status: StatusCode) -> bool {
+  matches!(
+    status,
+    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+  )
+}
+
+fn upstream_uri(uri: &Uri, authority: &str) -> Result<Uri, ProxyError> {
+  let path_query = uri.path_and_query().map(|v| v.as_str()).unwrap_or("/");
+  Uri::try_from(format!("http://{}{}", authority, path_query))
+    .map_err(|_| ProxyError::ServiceUnavailable)
+}
+
+/// Fails the response body when the upstream stays silent for longer than the read timeout,
+/// which otherwise only covers the response headers.
+struct TimeoutBody {
+  inner: Incoming,
+  read_timeout: Duration,
+  /// Pushed back every time a frame arrives.
+  deadline: Pin<Box<Sleep>>,
+}
+
+impl http_body::Body for TimeoutBody {
+  type Data = Bytes;
+  type Error = axum::Error;
+
+  fn poll_frame(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+    let this = self.get_mut();
+    match Pin::new(&mut this.inner).poll_frame(cx) {
+      Poll::Ready(frame) => {
+        let deadline = tokio::time::Instant::now() + this.read_timeout;
+        this.deadline.as_mut().reset(deadline);
+        Poll::Ready(frame.map(|frame| frame.map_err(axum::Error::new)))
+      }
+      Poll::Pending => {
+        if this.deadline.as_mut().poll(cx).is_pending() {
+          return Poll::Pending;
+        }
+        METRICS.upstream_timeout();
+        Poll::Ready(Some(Err(axum::Error::new(
+          "upstream response body timed out",
+        ))))
+      }
+    }
+  }
+
+  fn is_end_stream(&self) -> bool {
+    self.inner.is_end_stream()
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    self.inner.size_hint()
+  }
+}
+
+/// Removes the circuits of deployments that are no longer routed to.
+pub fn retain_circuits(deployment_ids: &HashSet<Uuid>) {
+  CIRCUIT_BREAKERS
+    .circuits
+    .lock()
+    .unwrap()
+    .retain(|deployment_id, _| deployment_ids.contains(deployment_id));
+  METRICS.retain_circuit_states(deployment_ids);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CircuitState {
+  Closed,
+  HalfOpen,
+  Open,
+}
+
+impl CircuitState {
+  fn as_gauge(&self) -> u8 {
+    match self {
+      CircuitState::Closed => 0,
+      CircuitState::HalfOpen => 1,
+      CircuitState::Open => 2,
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Circuit {
+  state: CircuitState,
+  consecutive_failures: u32,
+  opened_at: Instant,
+  /// Set while the single half-open trial request is in flight.
+  trial_in_flight: bool,
+}
+
+impl Circuit {
+  fn new() -> Self {
+    Self {
+      state: CircuitState::Closed,
+      consecutive_failures: 0,
+      opened_at: Instant::now(),
+      trial_in_flight: false,
+    }
+  }
+
+  fn allow(&mut self, now: Instant) -> bool {
+    match self.state {
+      CircuitState::Closed => true,
+      CircuitState::Open if now.saturating_duration_since(self.opened_at) >= CIRCUIT_COOLDOWN => {
+        self.state = CircuitState::HalfOpen;
+        self.trial_in_flight = true;
+        true
+      }
+      CircuitState::Open => false,
+      CircuitState::HalfOpen if !self.trial_in_flight => {
+        self.trial_in_flight = true;
+        true
+      }
+      CircuitState::HalfOpen => false,
+    }
+  }
+
+  /// Frees the half-open trial of a request that ended without an outcome.
+  fn abandon(&mut self) {
+    self.trial_in_flight = false;
+  }
+
+  /// `trial` is set for the outcome of the half-open trial, outcomes of requests let through
+  /// while the circuit was still closed only count while it still is.
+  fn record(&mut self, success: bool, trial: bool, now: Instant) {
+    if trial {
+      self.trial_in_flight = false;
+    } else if self.state != CircuitState::Closed {
+      // Let through before the circuit opened, the trial decides from here.
+      return;
+    }
+    if success {
+      self.state = CircuitState::Closed;
+      self.consecutive_failures = 0;
+      return;
+    }
+    self.consecutive_failures += 1;
+    if self.state == CircuitState::HalfOpen
+      || self.consecutive_failures >= CIRCUIT_FAILURE_THRESHOLD
+    {
+      self.state = CircuitState::Open;
+      self.opened_at = now;
+    }
+  }
+}
+
+/// Per deployment circuit breakers, an open circuit fast-fails with a 503.
+struct CircuitBreakers {
+  circuits: Mutex<HashMap<Uuid, Circuit>>,
+}
+
+static CIRCUIT_BREAKERS: Lazy<CircuitBreakers> = Lazy::new(|| CircuitBreakers {
+  circuits: Mutex::new(HashMap::new()),
+});
+
+/// Lets one request through a circuit, its outcome is reported with [`CircuitPermit::record`].
+///
+/// Dropping it unrecorded, e.g. when the client disconnects mid-request, frees the half-open
+/// trial so the circuit doesn't stay stuck.
+struct CircuitPermit {
+  deployment_id: Uuid,
+  /// Whether this permit is the half-open trial, only that one may free it.
+  trial: bool,
+  recorded: bool,
+}
+
+impl CircuitPermit {
+  fn record(mut self, success: bool) {
+    self.recorded = true;
+    CIRCUIT_BREAKERS.record(self.deployment_id, success, self.trial);
+  }
+}
+
+impl Drop for CircuitPermit {
+  fn drop(&mut self) {
+    if self.trial && !self.recorded {
+      if let Some(circuit) = CIRCUIT_BREAKERS
+        .circuits
+        .lock()
+        .unwrap()
+        .get_mut(&self.deployment_id)
+      {
+        circuit.abandon();
+      }
+    }
+  }
+}
+
+impl CircuitBreakers {
+  fn allow(&self, deployment_id: Uuid) -> Option<CircuitPermit> {
+    let mut circuits = self.circuits.lock().unwrap();
+    let circuit = circuits.entry(deployment_id).or_insert_with(Circuit::new);
+    let previous = circuit.state;
+    let allowed = circuit.allow(Instant::now());
+    if circuit.state != previous {
+      METRICS.circuit_state(deployment_id, circuit.state.as_gauge());
+    }
+    // Built lazily, dropping a denied permit would lock the circuits again.
+    allowed.then(|| CircuitPermit {
+      deployment_id,
+      trial: circuit.state == CircuitState::HalfOpen,
+      recorded: false,
+    })
+  }
+
+  fn record(&self, deployment_id: Uuid, success: bool, trial: bool) {
+    let mut circuits = self.circuits.lock().unwrap();
+    let circuit = circuits.entry(deployment_id).or_insert_with(Circuit::new);
+    let previous = circuit.state;
+    circuit.record(success, trial, Instant::now());
+    if circuit.state != previous {
+      if circuit.state == CircuitState::Open {
+        warn!("Circuit opened for deployment {}", deployment_id);
+      }
+      METRICS.circuit_state(deployment_id, circuit.state.as_gauge());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn circuit_opens_after_failures_and_recovers_after_cooldown() {
+    let now = Instant::now();
+    let mut circuit = Circuit::new();
+    for _ in 0..CIRCUIT_FAILURE_THRESHOLD {
+      assert!(circuit.allow(now));
+      circuit.record(false, false, now);
+    }
+    assert_eq!(circuit.state, CircuitState::Open);
+    assert!(!circuit.allow(now));
+
+    let later = now + CIRCUIT_COOLDOWN;
+    assert!(circuit.allow(later));
+    assert_eq!(circuit.state, CircuitState::HalfOpen);
+    assert!(!circuit.allow(later));
+    circuit.record(true, true, later);
+    assert_eq!(circuit.state, CircuitState::Closed);
+  }
+
+  #[test]
+  fn abandoned_trial_frees_the_half_open_circuit() {
+    let now = Instant::now();
+    let mut circuit = Circuit::new();
+    for _ in 0..CIRCUIT_FAILURE_THRESHOLD {
+      circuit.record(false, false, now);
+    }
+    let later = now + CIRCUIT_COOLDOWN;
+    assert!(circuit.allow(later));
+    assert!(!circuit.allow(later));
+    circuit.abandon();
+    assert_eq!(circuit.state, CircuitState::HalfOpen);
+    assert!(circuit.allow(later));
+  }
+
+  #[test]
+  fn only_the_trial_permit_frees_the_half_open_circuit() {
+    let deployment_id = Uuid::new_v4();
+    let closed_permit = CIRCUIT_BREAKERS.allow(deployment_id).unwrap();
+    assert!(!closed_permit.trial);
+    for _ in 0..CIRCUIT_FAILURE_THRESHOLD {
+      CIRCUIT_BREAKERS.record(deployment_id, false, false);
+    }
+    CIRCUIT_BREAKERS
+      .circuits
+      .lock()
+      .unwrap()
+      .get_mut(&deployment_id)
+      .unwrap()
+      .opened_at -= CIRCUIT_COOLDOWN;
+    let trial_permit = CIRCUIT_BREAKERS.allow(deployment_id).unwrap();
+    assert!(trial_permit.trial);
+
+    // A slow request from before the circuit opened ends after the trial started.
+    drop(closed_permit);
+    assert!(CIRCUIT_BREAKERS.allow(deployment_id).is_none());
+    drop(trial_permit);
+    assert!(CIRCUIT_BREAKERS.allow(deployment_id).is_some());
+  }
+
+  #[test]
+  fn late_outcomes_of_closed_permits_keep_the_trial_in_flight() {
+    let now = Instant::now();
+    let mut circuit = Circuit::new();
+    for _ in 0..CIRCUIT_FAILURE_THRESHOLD {
+      circuit.record(false, false, now);
+    }
+    let later = now + CIRCUIT_COOLDOWN;
+    assert!(circuit.allow(later));
+    circuit.record(false, false, later);
+    assert_eq!(circuit.state, CircuitState::HalfOpen);
+    assert!(circuit.trial_in_flight);
+    assert!(!circuit.allow(later));
+  }
+
+  #[test]
+  fn gateway_errors_count_against_the_circuit() {
+    assert!(is_unavailable(StatusCode::BAD_GATEWAY));
+    assert!(is_unavailable(StatusCode::SERVICE_UNAVAILABLE));
+    assert!(is_unavailable(StatusCode::GATEWAY_TIMEOUT));
+    assert!(!is_unavailable(StatusCode::INTERNAL_SERVER_ERROR));
+    assert!(!is_unavailable(StatusCode::NOT_FOUND));
+  }
+
+  #[test]
+  fn clients_are_capped() {
+    for millis in 0..(MAX_CLIENTS as u64 * 2) {
+      client(Duration::from_millis(1_000_000 + millis));
+    }
+    assert!(CLIENTS.lock().unwrap().len() <= MAX_CLIENTS);
+  }
+}
@@ -1,19 +1,41 @@
+use crate::ingress::rate_limit::IngressRateLimit;
 use chrono::{DateTime, Utc};
+use dosei_schema::app::AppIngress;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use tracing::info;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
+pub mod rate_limit;
 pub mod route;
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+const MAX_RETRIES: u32 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct Ingress {
   pub id: Uuid,
   pub service_id: Uuid,
   pub owner_id: Uuid,
   pub host: String,
   pub path: Option<String>,
+  pub forwarded_headers: bool,
+  pub trust_forwarded_headers: bool,
+  /// `username:bcrypt-hash` entries, never returned by the API.
+  #[serde(skip_serializing)]
+  pub basic_auth: Vec<String>,
+  pub allow_cidrs: Vec<String>,
+  pub deny_cidrs: Vec<String>,
+  pub compression: bool,
+  pub compression_min_size: i32,
+  /// Empty for the default content types.
+  pub compression_content_types: Vec<String>,
+  pub connect_timeout_ms: i32,
+  pub read_timeout_ms: i32,
+  pub retries: i32,
+  /// Key algorithm of ACME certificates for the host, the cluster `CERTIFICATE_KEY_TYPE` when
+  /// unset.
+  pub certificate_key_type: Option<String>,
   pub updated_at: DateTime<Utc>,
   pub created_at: DateTime<Utc>,
 }
@@ -55,6 +77,38 @@ impl Ingress {
     Ok(ingress)
   }
 
+  pub async fn get_all(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(Self, "SELECT * FROM ingress")
+        .fetch_all(pg_pool)
+        .await?,
+    )
+  }
+
+  pub async fn get_by_host(host: &str, pg_pool: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+    Ok(
+      sqlx::query_as!(Self, "SELECT * FROM ingress WHERE host = $1", host)
+        .fetch_optional(pg_pool)
+        .await?,
+    )
+  }
+
+  /// Ingresses one label below `parent`, the hosts a `*.<parent>` certificate covers.
+  pub async fn get_by_parent_domain(
+    parent: &str,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT * FROM ingress WHERE substring(host from position('.' in host) + 1) = $1",
+        parent
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+
   pub async fn get_by_service_id(
     service_id: Uuid,
     pg_pool: &Pool<Postgres>,
@@ -69,6 +123,7 @@ impl Ingress {
       .await?,
     )
   }
+
   pub async fn update_host(&self, host: String, pg_pool: &Pool<Postgres>) -> anyhow::Result<Self> {
     Ok(
       sqlx::query_as!(
@@ -82,4 +137,73 @@ impl Ingress {
       .await?,
     )
   }
+
+  /// Applies the app.json ingress settings, unset values fall back to the defaults.
+  pub async fn update_settings(
+    &self,
+    settings: &AppIngress,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Self> {
+    let compression = settings.compression.as_ref();
+    let ingress = sqlx::query_as!(
+      Self,
+      "
+      UPDATE ingress
+      SET
+        forwarded_headers = $1,
+        trust_forwarded_headers = $2,
+        basic_auth = $3,
+        allow_cidrs = $4,
+        deny_cidrs = $5,
+        compression = $6,
+        compression_min_size = $7,
+        compression_content_types = $8,
+        connect_timeout_ms = $9,
+        read_timeout_ms = $10,
+        retries = $11,
+        certificate_key_type = $12,
+        updated_at = $13
+      WHERE id = $14
+      RETURNING *
+      ",
+      settings.forwarded_headers.unwrap_or(true),
+      settings.trust_forwarded_headers.unwrap_or(false),
+      settings.basic_auth.as_deref().unwrap_or_default(),
+      settings.allow_cidrs.as_deref().unwrap_or_default(),
+      settings.deny_cidrs.as_deref().unwrap_or_default(),
+      compression
+        .and_then(|compression| compression.enabled)
+        .unwrap_or(true),
+      compression
+        .and_then(|compression| compression.min_size)
+        .unwrap_or(1024)
+        .min(i32::MAX as u32) as i32,
+      compression
+        .and_then(|compression| compression.content_types.as_deref())
+        .unwrap_or_default(),
+      settings
+        .connect_timeout_ms
+        .unwrap_or(5000)
+        .clamp(1, 600_000) as i32,
+      settings
+        .read_timeout_ms
+        .unwrap_or(60_000)
+        .clamp(1, 3_600_000) as i32,
+      settings.retries.unwrap_or(2).min(MAX_RETRIES) as i32,
+      settings
+        .certificate_key_type
+        .map(|key_type| key_type.as_str()),
+      Utc::now(),
+      self.id
+    )
+    .fetch_one(pg_pool)
+    .await?;
+    IngressRateLimit::replace(
+      self.id,
+      settings.rate_limits.as_deref().unwrap_or_default(),
+      pg_pool,
+    )
+    .await?;
+    Ok(ingress)
+  }
 }
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use dosei_schema::app::AppRateLimit;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Slower limits make refill times overflow, this is one request every ~17 minutes.
+const MIN_REQUESTS_PER_SECOND: f64 = 0.001;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IngressRateLimit {
+  pub id: Uuid,
+  pub ingress_id: Uuid,
+  pub path: Option<String>,
+  pub requests_per_second: f64,
+  pub burst: i32,
+  pub key_header: Option<String>,
+  pub created_at: DateTime<Utc>,
+}
+
+impl IngressRateLimit {
+  pub async fn get_all(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(Self, "SELECT * FROM ingress_rate_limit")
+        .fetch_all(pg_pool)
+        .await?,
+    )
+  }
+
+  /// Replaces the rate limits of an ingress with the ones declared in app.json.
+  pub async fn replace(
+    ingress_id: Uuid,
+    rate_limits: &[AppRateLimit],
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    let mut transaction = pg_pool.begin().await?;
+    sqlx::query!(
+      "DELETE FROM ingress_rate_limit WHERE ingress_id = $1",
+      ingress_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    for rate_limit in rate_limits {
+      if !rate_limit.requests_per_second.is_finite()
+        || rate_limit.requests_per_second < MIN_REQUESTS_PER_SECOND
+      {
+        warn!(
+          "Ignoring rate limit with {} requests per second",
+          rate_limit.requests_per_second
+        );
+        continue;
+      }
+      let burst = rate_limit
+        .burst
+        .unwrap_or(rate_limit.requests_per_second.ceil() as u32)
+        .clamp(1, i32::MAX as u32);
+      sqlx::query!(
+        "
+        INSERT INTO ingress_rate_limit (id, ingress_id, path, requests_per_second, burst, key_header)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ",
+        Uuid::new_v4(),
+        ingress_id,
+        rate_limit.path,
+        rate_limit.requests_per_second,
+        burst as i32,
+        rate_limit.key_header.as_ref().map(|header| header.to_lowercase()),
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+  }
+
+  pub fn matches(&self, path: &str) -> bool {
+    self
+      .path
+      .as_deref()
+      .is_none_or(|prefix| path.starts_with(prefix))
+  }
+}
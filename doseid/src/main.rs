@@ -1,3 +1,4 @@
+mod access_log;
 mod account;
 mod auth;
 mod certificate;
@@ -8,11 +9,18 @@ mod deployment;
 mod http;
 mod ingress;
 mod job;
+mod metrics;
 mod service;
 mod session;
 
+use crate::access_log::AccessLog;
+use crate::certificate::acme::Acme;
+use crate::certificate::authority::CertificateAuthority;
+use crate::certificate::encryption::ClusterKey;
+use crate::certificate::Certificate;
 use crate::cluster::DaemonClusterInit;
 use crate::config::Config;
+use crate::container::network::Network;
 use crate::container::Container;
 use crate::http::Http;
 use crate::job::Job;
@@ -27,6 +35,7 @@ async fn main() -> anyhow::Result<()> {
   let config: &'static Config = Box::leak(Box::new(Config::new()?));
 
   Container::check_docker_daemon_status().await;
+  Network::init(config).await?;
 
   let pg_pool = Pool::<Postgres>::connect(&config.database_url)
     .await
@@ -34,9 +43,14 @@ async fn main() -> anyhow::Result<()> {
   sqlx::migrate!().run(&pg_pool).await?;
   let shared_pool = Arc::new(pg_pool);
 
+  Acme::init(config);
+  ClusterKey::init(config, &shared_pool).await?;
+  Certificate::encrypt_stored_keys(&shared_pool).await?;
   let cluster = DaemonClusterInit::new()
     .await
     .context("Cluster creation failed")?;
+  CertificateAuthority::init(&cluster.name, &shared_pool).await?;
+  certificate::dns::init(config)?;
   cluster
     .init(&shared_pool)
     .await
@@ -48,8 +62,9 @@ async fn main() -> anyhow::Result<()> {
   certificate::start_certificate_server(&shared_pool).await?;
 
   Job::start_server().await?;
-  Container::start_event_listener().await?;
-  Container::start_monitoring_server().await?;
+  AccessLog::start_server(config, &shared_pool).await?;
+  Container::start_event_listener(&shared_pool).await?;
+  Container::start_monitoring_server(&shared_pool).await?;
   Http::start_server(config, &shared_pool).await?;
   Ok(())
 }
@@ -0,0 +1,342 @@
+use crate::certificate::Certificate;
+use crate::deployment::Deployment;
+use once_cell::sync::Lazy;
+use sqlx::{Pool, Postgres};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::error;
+use uuid::Uuid;
+
+pub mod route;
+
+/// Latency buckets in seconds, matching the Prometheus client defaults.
+const LATENCY_BUCKETS: [f64; 11] = [
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Build duration buckets in seconds.
+const BUILD_BUCKETS: [f64; 8] = [10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0];
+
+#[derive(Debug)]
+struct Histogram {
+  buckets: &'static [f64],
+  counts: Vec<u64>,
+  sum: f64,
+  count: u64,
+}
+
+impl Histogram {
+  fn new(buckets: &'static [f64]) -> Self {
+    Self {
+      buckets,
+      counts: vec![0; buckets.len()],
+      sum: 0.0,
+      count: 0,
+    }
+  }
+
+  fn observe(&mut self, value: f64) {
+    for (bucket, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+      if value <= *bucket {
+        *count += 1;
+      }
+    }
+    self.sum += value;
+    self.count += 1;
+  }
+
+  fn render(&self, out: &mut String, name: &str, labels: &str) {
+    let separator = if labels.is_empty() { "" } else { "," };
+    for (bucket, count) in self.buckets.iter().zip(self.counts.iter()) {
+      let _ = writeln!(
+        out,
+        "{}_bucket{{{}{}le=\"{}\"}} {}",
+        name, labels, separator, bucket, count
+      );
+    }
+    let _ = writeln!(
+      out,
+      "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+      name, labels, separator, self.count
+    );
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+  }
+}
+
+/// Process wide metrics exposed in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+  /// Keyed by service id (empty for unrouted requests) and status code.
+  proxy_requests: Mutex<BTreeMap<(String, u16), Histogram>>,
+  /// Keyed by build result, `success` or `failure`.
+  builds: Mutex<BTreeMap<&'static str, Histogram>>,
+  active_connections: AtomicI64,
+  tls_handshakes_succeeded: AtomicU64,
+  tls_handshakes_failed: AtomicU64,
+  upstream_retries: AtomicU64,
+  upstream_timeouts: AtomicU64,
+  /// Circuit breaker state by deployment id, 0 closed, 1 half-open and 2 open.
+  circuit_states: Mutex<BTreeMap<Uuid, u8>>,
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics {
+  proxy_requests: Mutex::new(BTreeMap::new()),
+  builds: Mutex::new(BTreeMap::new()),
+  active_connections: AtomicI64::new(0),
+  tls_handshakes_succeeded: AtomicU64::new(0),
+  tls_handshakes_failed: AtomicU64::new(0),
+  upstream_retries: AtomicU64::new(0),
+  upstream_timeouts: AtomicU64::new(0),
+  circuit_states: Mutex::new(BTreeMap::new()),
+});
+
+impl Metrics {
+  pub fn observe_proxy_request(&self, service_id: Option<Uuid>, status: u16, latency: Duration) {
+    let service_id = service_id.map(|id| id.to_string()).unwrap_or_default();
+    self
+      .proxy_requests
+      .lock()
+      .unwrap()
+      .entry((service_id, status))
+      .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+      .observe(latency.as_secs_f64());
+  }
+
+  pub fn observe_build(&self, success: bool, duration: Duration) {
+    let result = if success { "success" } else { "failure" };
+    self
+      .builds
+      .lock()
+      .unwrap()
+      .entry(result)
+      .or_insert_with(|| Histogram::new(&BUILD_BUCKETS))
+      .observe(duration.as_secs_f64());
+  }
+
+  pub fn connection_opened(&self) {
+    self.active_connections.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn connection_closed(&self) {
+    self.active_connections.fetch_sub(1, Ordering::Relaxed);
+  }
+
+  pub fn tls_handshake(&self, success: bool) {
+    let counter = if success {
+      &self.tls_handshakes_succeeded
+    } else {
+      &self.tls_handshakes_failed
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn upstream_retry(&self) {
+    self.upstream_retries.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn upstream_timeout(&self) {
+    self.upstream_timeouts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn circuit_state(&self, deployment_id: Uuid, state: u8) {
+    self
+      .circuit_states
+      .lock()
+      .unwrap()
+      .insert(deployment_id, state);
+  }
+
+  pub fn retain_circuit_states(&self, deployment_ids: &HashSet<Uuid>) {
+    self
+      .circuit_states
+      .lock()
+      .unwrap()
+      .retain(|deployment_id, _| deployment_ids.contains(deployment_id));
+  }
+
+  /// Renders the in-memory metrics along with gauges read at scrape time.
+  ///
+  /// A failing Docker or Postgres read only drops its own section and is reported through
+  /// `doseid_scrape_error`, so the rest of the scrape still gets through.
+  pub async fn render(&self, pg_pool: &Pool<Postgres>) -> String {
+    let mut out = String::new();
+    self.render_proxy(&mut out);
+
+    out.push_str("# HELP doseid_proxy_active_connections Open client connections to the proxy.\n");
+    out.push_str("# TYPE doseid_proxy_active_connections gauge\n");
+    let _ = writeln!(
+      out,
+      "doseid_proxy_active_connections {}",
+      self.active_connections.load(Ordering::Relaxed)
+    );
+
+    out.push_str("# HELP doseid_tls_handshakes_total TLS handshakes completed by the proxy.\n");
+    out.push_str("# TYPE doseid_tls_handshakes_total counter\n");
+    let _ = writeln!(
+      out,
+      "doseid_tls_handshakes_total{{result=\"success\"}} {}",
+      self.tls_handshakes_succeeded.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+      out,
+      "doseid_tls_handshakes_total{{result=\"failure\"}} {}",
+      self.tls_handshakes_failed.load(Ordering::Relaxed)
+    );
+
+    out.push_str("# HELP doseid_proxy_upstream_retries_total Upstream requests retried.\n");
+    out.push_str("# TYPE doseid_proxy_upstream_retries_total counter\n");
+    let _ = writeln!(
+      out,
+      "doseid_proxy_upstream_retries_total {}",
+      self.upstream_retries.load(Ordering::Relaxed)
+    );
+
+    out.push_str("# HELP doseid_proxy_upstream_timeouts_total Upstream requests that timed out.\n");
+    out.push_str("# TYPE doseid_proxy_upstream_timeouts_total counter\n");
+    let _ = writeln!(
+      out,
+      "doseid_proxy_upstream_timeouts_total {}",
+      self.upstream_timeouts.load(Ordering::Relaxed)
+    );
+
+    out.push_str(
+      "# HELP doseid_proxy_circuit_breaker_state Upstream circuit state, 0 closed, 1 half-open, 2 open.\n",
+    );
+    out.push_str("# TYPE doseid_proxy_circuit_breaker_state gauge\n");
+    for (deployment_id, state) in self.circuit_states.lock().unwrap().iter() {
+      let _ = writeln!(
+        out,
+        "doseid_proxy_circuit_breaker_state{{deployment_id=\"{}\"}} {}",
+        deployment_id, state
+      );
+    }
+
+    out.push_str("# HELP doseid_build_duration_seconds Deployment image build durations.\n");
+    out.push_str("# TYPE doseid_build_duration_seconds histogram\n");
+    for (result, histogram) in self.builds.lock().unwrap().iter() {
+      let labels = format!("result=\"{}\"", result);
+      histogram.render(&mut out, "doseid_build_duration_seconds", &labels);
+    }
+
+    out.push_str(
+      "# HELP doseid_certificate_expiry_timestamp_seconds Certificate expiry as a unix timestamp.\n",
+    );
+    out.push_str("# TYPE doseid_certificate_expiry_timestamp_seconds gauge\n");
+    let certificates = Certificate::get_all(pg_pool).await;
+    if let Ok(certificates) = &certificates {
+      for certificate in certificates {
+        let _ = writeln!(
+          out,
+          "doseid_certificate_expiry_timestamp_seconds{{domain_name=\"{}\"}} {}",
+          escape_label(&certificate.domain_name),
+          certificate.expires_at.timestamp()
+        );
+      }
+    }
+
+    out.push_str("# HELP doseid_deployments Deployments by status.\n");
+    out.push_str("# TYPE doseid_deployments gauge\n");
+    let deployments = Deployment::count_by_status(pg_pool).await;
+    if let Ok(deployments) = &deployments {
+      for (status, count) in deployments {
+        let _ = writeln!(
+          out,
+          "doseid_deployments{{status=\"{}\"}} {}",
+          escape_label(status),
+          count
+        );
+      }
+    }
+
+    out.push_str("# HELP doseid_db_pool_connections Postgres pool connections.\n");
+    out.push_str("# TYPE doseid_db_pool_connections gauge\n");
+    let idle = pg_pool.num_idle();
+    let size = pg_pool.size() as usize;
+    let _ = writeln!(out, "doseid_db_pool_connections{{state=\"idle\"}} {}", idle);
+    let _ = writeln!(
+      out,
+      "doseid_db_pool_connections{{state=\"active\"}} {}",
+      size.saturating_sub(idle)
+    );
+    let _ = writeln!(
+      out,
+      "doseid_db_pool_connections{{state=\"max\"}} {}",
+      pg_pool.options().get_max_connections()
+    );
+
+    out.push_str("# HELP doseid_scrape_error Whether a metrics section failed to be read.\n");
+    out.push_str("# TYPE doseid_scrape_error gauge\n");
+    let sections = [
+      ("certificates", certificates.err()),
+      ("deployments", deployments.err()),
+    ];
+    for (section, error) in sections {
+      if let Some(e) = &error {
+        error!("Failed to read {} metrics: {}", section, e);
+      }
+      let _ = writeln!(
+        out,
+        "doseid_scrape_error{{section=\"{}\"}} {}",
+        section,
+        error.is_some() as u8
+      );
+    }
+    out
+  }
+
+  fn render_proxy(&self, out: &mut String) {
+    let proxy_requests = self.proxy_requests.lock().unwrap();
+
+    out.push_str("# HELP doseid_proxy_requests_total Requests handled by the proxy.\n");
+    out.push_str("# TYPE doseid_proxy_requests_total counter\n");
+    for ((service_id, status), histogram) in proxy_requests.iter() {
+      let _ = writeln!(
+        out,
+        "doseid_proxy_requests_total{{service_id=\"{}\",status=\"{}\"}} {}",
+        service_id, status, histogram.count
+      );
+    }
+
+    out.push_str(
+      "# HELP doseid_proxy_request_duration_seconds Time until the proxy response headers, including auth and compression.\n",
+    );
+    out.push_str("# TYPE doseid_proxy_request_duration_seconds histogram\n");
+    for ((service_id, status), histogram) in proxy_requests.iter() {
+      let labels = format!("service_id=\"{}\",status=\"{}\"", service_id, status);
+      histogram.render(out, "doseid_proxy_request_duration_seconds", &labels);
+    }
+  }
+}
+
+fn escape_label(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn histogram_renders_cumulative_buckets() {
+    let mut histogram = Histogram::new(&[0.1, 1.0]);
+    histogram.observe(0.05);
+    histogram.observe(0.5);
+    histogram.observe(2.0);
+
+    let mut out = String::new();
+    histogram.render(&mut out, "latency_seconds", "service_id=\"a\"");
+    assert_eq!(
+      out,
+      "latency_seconds_bucket{service_id=\"a\",le=\"0.1\"} 1\n\
+       latency_seconds_bucket{service_id=\"a\",le=\"1\"} 2\n\
+       latency_seconds_bucket{service_id=\"a\",le=\"+Inf\"} 3\n\
+       latency_seconds_sum{service_id=\"a\"} 2.55\n\
+       latency_seconds_count{service_id=\"a\"} 3\n"
+    );
+  }
+}
@@ -0,0 +1,74 @@
+use crate::config::Config;
+use crate::metrics::METRICS;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::Extension;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+const TAG: &str = "metrics";
+const BEARER: &str = "Bearer ";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[utoipa::path(
+  get,
+  path = "/metrics",
+  responses(
+        (status = StatusCode::OK, body = String, description = "Metrics in the Prometheus text exposition format"),
+        (status = StatusCode::UNAUTHORIZED, description = "Missing or invalid `METRICS_TOKEN` bearer token"),
+        (status = StatusCode::NOT_FOUND, description = "Metrics are disabled, `METRICS_TOKEN` isn't set"),
+  ),
+  tag = TAG
+)]
+pub async fn api_metrics(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(config): Extension<&'static Config>,
+  headers: HeaderMap,
+) -> Result<([(header::HeaderName, &'static str); 1], String), StatusCode> {
+  // Metrics list every account's domains and services, so they're never served anonymously.
+  let metrics_token = config.metrics_token.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+  if !is_authorized(&headers, metrics_token) {
+    return Err(StatusCode::UNAUTHORIZED);
+  }
+  let metrics = METRICS.render(&pg_pool).await;
+  Ok(([(header::CONTENT_TYPE, CONTENT_TYPE)], metrics))
+}
+
+/// Compares in constant time, so response timing doesn't reveal how much of the token matched.
+fn is_authorized(headers: &HeaderMap, metrics_token: &str) -> bool {
+  headers
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix(BEARER))
+    .is_some_and(|bearer_token| bool::from(bearer_token.as_bytes().ct_eq(metrics_token.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use axum::http::HeaderValue;
+
+  #[test]
+  fn only_the_metrics_token_is_authorized() {
+    let mut headers = HeaderMap::new();
+    assert!(!is_authorized(&headers, "s3cret"));
+    for (authorization, authorized) in [
+      ("Bearer s3cret", true),
+      ("Bearer s3cre", false),
+      ("Bearer s3cret2", false),
+      ("Basic s3cret", false),
+      ("s3cret", false),
+    ] {
+      headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_static(authorization),
+      );
+      assert_eq!(
+        is_authorized(&headers, "s3cret"),
+        authorized,
+        "{}",
+        authorization
+      );
+    }
+  }
+}
@@ -0,0 +1,109 @@
+use chrono::Utc;
+use flate2::read::GzDecoder;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Error page names an app can override.
+pub const ERROR_PAGE_NAMES: [&str; 5] = ["404", "502", "503", "504", "maintenance"];
+const MAX_ERROR_PAGE_SIZE: u64 = 256 * 1024; // 256 KiB
+
+pub struct ServiceErrorPage {
+  pub service_id: Uuid,
+  pub name: String,
+  pub html: String,
+}
+
+impl ServiceErrorPage {
+  pub async fn get_all(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(
+        Self,
+        "SELECT service_id, name, html FROM service_error_page"
+      )
+      .fetch_all(pg_pool)
+      .await?,
+    )
+  }
+
+  /// Replaces the error pages of a service with the ones declared in app.json.
+  pub async fn replace(
+    service_id: Uuid,
+    pages: &HashMap<String, String>,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<()> {
+    let mut transaction = pg_pool.begin().await?;
+    sqlx::query!(
+      "DELETE FROM service_error_page WHERE service_id = $1",
+      service_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    for (name, html) in pages {
+      sqlx::query!(
+        "
+        INSERT INTO service_error_page (service_id, name, html, updated_at, created_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ",
+        service_id,
+        name,
+        html,
+        Utc::now(),
+        Utc::now(),
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+  }
+
+  /// Reads the app.json error pages from the uploaded `.tar.gz`, keyed by page name.
+  pub fn read_from_archive(
+    archive: &[u8],
+    error_pages: &HashMap<String, String>,
+  ) -> anyhow::Result<HashMap<String, String>> {
+    let mut wanted: HashMap<PathBuf, &str> = HashMap::new();
+    for (name, path) in error_pages {
+      if !ERROR_PAGE_NAMES.contains(&name.as_str()) {
+        warn!("Ignoring unknown error page: {}", name);
+        continue;
+      }
+      wanted.insert(normalize(Path::new(path)), name);
+    }
+
+    let mut pages = HashMap::new();
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    for entry in archive.entries()? {
+      let mut entry = entry?;
+      let path = normalize(&entry.path()?);
+      let Some(name) = wanted.get(&path) else {
+        continue;
+      };
+      if entry.size() > MAX_ERROR_PAGE_SIZE {
+        warn!("Ignoring error page {:?}, it exceeds 256 KiB", path);
+        continue;
+      }
+      let mut html = String::new();
+      entry.read_to_string(&mut html)?;
+      pages.insert(name.to_string(), html);
+    }
+    for (path, name) in wanted {
+      if !pages.contains_key(name) {
+        warn!("Error page {:?} for {} not found in the app", path, name);
+      }
+    }
+    Ok(pages)
+  }
+}
+
+/// Drops `.` components so `./public/503.html` matches `public/503.html`.
+fn normalize(path: &Path) -> PathBuf {
+  path
+    .components()
+    .filter(|component| matches!(component, Component::Normal(_)))
+    .collect()
+}
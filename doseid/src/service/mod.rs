@@ -5,6 +5,7 @@ use tracing::info;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
+pub mod error_page;
 pub mod route;
 
 #[derive(Serialize, Deserialize, Debug, ToSchema)]
@@ -12,6 +13,8 @@ pub struct Service {
   pub id: Uuid,
   pub name: String,
   pub owner_id: Uuid,
+  /// The proxy serves the maintenance page while the containers keep running.
+  pub maintenance: bool,
   pub updated_at: DateTime<Utc>,
   pub created_at: DateTime<Utc>,
 }
@@ -48,11 +51,21 @@ impl Service {
     )
   }
 
-  pub async fn get_by_name(name: String, pg_pool: &Pool<Postgres>) -> anyhow::Result<Option<Self>> {
+  /// Names are only unique per account, so the owner is always part of the lookup.
+  pub async fn get_by_name(
+    name: &str,
+    owner_id: Uuid,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Option<Self>> {
     Ok(
-      sqlx::query_as!(Service, "SELECT * FROM service WHERE name = $1", name)
-        .fetch_optional(pg_pool)
-        .await?,
+      sqlx::query_as!(
+        Service,
+        "SELECT * FROM service WHERE name = $1 AND owner_id = $2",
+        name,
+        owner_id
+      )
+      .fetch_optional(pg_pool)
+      .await?,
     )
   }
 
@@ -70,4 +83,30 @@ impl Service {
       .await?,
     )
   }
+
+  pub async fn get_all(pg_pool: &Pool<Postgres>) -> anyhow::Result<Vec<Self>> {
+    Ok(
+      sqlx::query_as!(Service, "SELECT * FROM service")
+        .fetch_all(pg_pool)
+        .await?,
+    )
+  }
+
+  pub async fn update_maintenance(
+    &self,
+    maintenance: bool,
+    pg_pool: &Pool<Postgres>,
+  ) -> anyhow::Result<Self> {
+    Ok(
+      sqlx::query_as!(
+        Service,
+        "UPDATE service SET maintenance = $1, updated_at = $2 WHERE id = $3 RETURNING *",
+        maintenance,
+        Utc::now(),
+        self.id
+      )
+      .fetch_one(pg_pool)
+      .await?,
+    )
+  }
 }
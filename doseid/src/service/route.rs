@@ -1,11 +1,39 @@
+use crate::container::event::ContainerEvent;
+use crate::container::stats::ContainerStats;
 use crate::service::Service;
 use crate::session::AuthSession;
+use axum::extract::{Path, Query};
 use axum::http::StatusCode;
 use axum::{Extension, Json};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 
 const TAG: &str = "service";
+const DEFAULT_METRICS_MINUTES: i64 = 60;
+const MAX_METRICS_MINUTES: i64 = 1440;
+const DEFAULT_EVENTS_LIMIT: i64 = 100;
+const MAX_EVENTS_LIMIT: i64 = 1000;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ServiceMaintenance {
+  pub enabled: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ServiceMetricsQuery {
+  /// Window of samples to return in minutes, defaults to 60 and caps at 1440.
+  minutes: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ServiceEventsQuery {
+  /// Number of most recent events to return, defaults to 100 and caps at 1000.
+  limit: Option<i64>,
+}
 
 #[utoipa::path(
   get,
@@ -27,3 +55,115 @@ pub async fn api_list_services(
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
   Ok((StatusCode::OK, Json(services)))
 }
+
+#[utoipa::path(
+  get,
+  path = "/service/{service_id}/metrics",
+  params(
+    ("service_id" = String, Path, description = "Service ID"),
+    ServiceMetricsQuery,
+  ),
+  responses(
+        (status = StatusCode::OK, body = Vec<ContainerStats>),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_get_service_metrics(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+  Path(service_id): Path<Uuid>,
+  Query(query): Query<ServiceMetricsQuery>,
+) -> Result<(StatusCode, Json<Vec<ContainerStats>>), StatusCode> {
+  let service = Service::get_by_id(service_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  if service.owner_id != session.account_id {
+    return Err(StatusCode::NOT_FOUND);
+  }
+  let minutes = query
+    .minutes
+    .unwrap_or(DEFAULT_METRICS_MINUTES)
+    .clamp(1, MAX_METRICS_MINUTES);
+  let since = Utc::now() - chrono::Duration::minutes(minutes);
+  let stats = ContainerStats::get_by_service_id(service_id, since, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::OK, Json(stats)))
+}
+
+#[utoipa::path(
+  get,
+  path = "/service/{service_id}/events",
+  params(
+    ("service_id" = String, Path, description = "Service ID"),
+    ServiceEventsQuery,
+  ),
+  responses(
+        (status = StatusCode::OK, body = Vec<ContainerEvent>),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_list_service_events(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+  Path(service_id): Path<Uuid>,
+  Query(query): Query<ServiceEventsQuery>,
+) -> Result<(StatusCode, Json<Vec<ContainerEvent>>), StatusCode> {
+  let service = Service::get_by_id(service_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  if service.owner_id != session.account_id {
+    return Err(StatusCode::NOT_FOUND);
+  }
+  let limit = query
+    .limit
+    .unwrap_or(DEFAULT_EVENTS_LIMIT)
+    .clamp(1, MAX_EVENTS_LIMIT);
+  let events = ContainerEvent::get_by_service_id(service_id, limit, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::OK, Json(events)))
+}
+
+#[utoipa::path(
+  put,
+  path = "/service/{service_id}/maintenance",
+  params(
+    ("service_id" = String, Path, description = "Service ID"),
+  ),
+  request_body = ServiceMaintenance,
+  responses(
+        (status = StatusCode::OK, body = Service),
+  ),
+  security(
+      ("Authentication" = [])
+  ),
+  tag = TAG
+)]
+pub async fn api_update_service_maintenance(
+  pg_pool: Extension<Arc<Pool<Postgres>>>,
+  Extension(AuthSession(session)): Extension<AuthSession>,
+  Path(service_id): Path<Uuid>,
+  Json(body): Json<ServiceMaintenance>,
+) -> Result<(StatusCode, Json<Service>), StatusCode> {
+  let service = Service::get_by_id(service_id, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+  if service.owner_id != session.account_id {
+    return Err(StatusCode::NOT_FOUND);
+  }
+  let service = service
+    .update_maintenance(body.enabled, &pg_pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+  Ok((StatusCode::OK, Json(service)))
+}
@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AppCronJob {
@@ -12,11 +13,108 @@ pub struct AppCronJob {
   pub is_async: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppRateLimit {
+  /// Only limit requests whose path starts with this prefix, defaults to every path.
+  pub path: Option<String>,
+  /// Sustained request rate allowed per client.
+  pub requests_per_second: f64,
+  /// Requests allowed in a burst, defaults to `requests_per_second` rounded up.
+  pub burst: Option<u32>,
+  /// Tell clients apart by this request header instead of their IP.
+  pub key_header: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AppCompression {
+  /// Compress responses the app sent uncompressed, defaults to `true`.
+  pub enabled: Option<bool>,
+  /// Skip responses smaller than this many bytes, defaults to `1024`.
+  pub min_size: Option<u32>,
+  /// Content types to compress, `text/*` style wildcards are allowed.
+  /// Defaults to text, JSON, JavaScript, XML, SVG and WebAssembly.
+  pub content_types: Option<Vec<String>>,
+}
+
+/// Key algorithm of ACME certificates.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum CertificateKeyType {
+  #[default]
+  #[serde(rename = "ecdsa-p256")]
+  EcdsaP256,
+  #[serde(rename = "ecdsa-p384")]
+  EcdsaP384,
+  #[serde(rename = "rsa-2048")]
+  Rsa2048,
+  #[serde(rename = "rsa-4096")]
+  Rsa4096,
+}
+
+impl CertificateKeyType {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::EcdsaP256 => "ecdsa-p256",
+      Self::EcdsaP384 => "ecdsa-p384",
+      Self::Rsa2048 => "rsa-2048",
+      Self::Rsa4096 => "rsa-4096",
+    }
+  }
+}
+
+impl FromStr for CertificateKeyType {
+  type Err = anyhow::Error;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value.to_lowercase().as_str() {
+      "ecdsa-p256" => Ok(Self::EcdsaP256),
+      "ecdsa-p384" => Ok(Self::EcdsaP384),
+      "rsa-2048" => Ok(Self::Rsa2048),
+      "rsa-4096" => Ok(Self::Rsa4096),
+      _ => Err(anyhow::Error::msg(format!(
+        "Invalid certificate key type `{}`, expected ecdsa-p256, ecdsa-p384, rsa-2048 or rsa-4096",
+        value
+      ))),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct AppIngress {
+  /// Add `Forwarded`, `X-Forwarded-*` and `X-Real-IP` headers to proxied requests, defaults to `true`.
+  pub forwarded_headers: Option<bool>,
+  /// Keep forwarding headers sent by the client instead of replacing them, defaults to `false`.
+  /// Only enable this when the cluster sits behind another trusted proxy.
+  pub trust_forwarded_headers: Option<bool>,
+  /// Token bucket limits, a request over any matching limit gets a `429 Too Many Requests`.
+  pub rate_limits: Option<Vec<AppRateLimit>>,
+  /// Require HTTP basic auth, entries are `username:bcrypt-hash` as written by `htpasswd -B`.
+  pub basic_auth: Option<Vec<String>>,
+  /// Only accept clients within these CIDRs or IPs, defaults to everyone.
+  pub allow_cidrs: Option<Vec<String>>,
+  /// Reject clients within these CIDRs or IPs, checked before `allow_cidrs`.
+  pub deny_cidrs: Option<Vec<String>>,
+  /// gzip or deflate responses, negotiated from the request `Accept-Encoding`.
+  pub compression: Option<AppCompression>,
+  /// Time allowed to connect to the app, defaults to `5000`.
+  pub connect_timeout_ms: Option<u32>,
+  /// Time allowed for the app to send its response headers, defaults to `60000`.
+  pub read_timeout_ms: Option<u32>,
+  /// Retries of failed idempotent requests without a body, defaults to `2`.
+  pub retries: Option<u32>,
+  /// Key algorithm of the certificates of `domains`, defaults to the cluster
+  /// `CERTIFICATE_KEY_TYPE`. Applied to new certificates and on renewal.
+  pub certificate_key_type: Option<CertificateKeyType>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct App {
   pub name: String,
   pub port: Option<i16>,
   pub domains: Option<Vec<String>>,
+  pub ingress: Option<AppIngress>,
+  /// HTML pages served by the proxy instead of the cluster ones, keyed by `404`, `502`, `503`,
+  /// `504` or `maintenance`, with a file path within the app as value.
+  pub error_pages: Option<HashMap<String, String>>,
   pub env: Option<HashMap<String, String>>,
   pub cron_jobs: Option<Vec<AppCronJob>>,
 }
@@ -19,6 +19,10 @@ pub struct ClusterInit {
   pub name: String,
   pub dosei_public_key: String,
   pub accounts: Option<Vec<ClusterAccount>>,
+  /// Base domain every service is published under as `<service>.<wildcard_domain>`,
+  /// served with a single `*.<wildcard_domain>` certificate.
+  #[serde(default)]
+  pub wildcard_domain: Option<String>,
 }
 
 impl DoseiObject for ClusterInit {